@@ -0,0 +1,64 @@
+//! Detection of tampering with the usb while it was out of our hands.
+//!
+//! The kernel, initramfs and bootloader on the usb are not encrypted, so anyone with access to
+//! the usb can replace them. At every update, the updater records a manifest of the whole usb
+//! filesystem on the encrypted root, which cannot be tampered with. The `audit` subcommand,
+//! run from the real system, compares the usb against that record.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+
+use crate::{cli, manifest::Manifest, utils};
+
+/// Default location of the manifest recorded at the last trusted update.
+pub const DEFAULT_TRUSTED_MANIFEST: &str = "/var/lib/usb-boot/trusted.manifest";
+
+/// Entry point of the `audit` subcommand.
+///
+/// Usage: `audit [--record <file>] (--device <block device> | <directory>)`
+///
+/// Compares every file on the usb with the manifest recorded at the last trusted update.
+/// If `--device` is given, the device is mounted read-only and its whole filesystem is audited.
+/// Otherwise, `directory` must be the root of the already mounted usb filesystem.
+pub fn audit_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        options: &["--record", "--device"],
+        positionals: (0, 1),
+        ..Default::default()
+    })?;
+    let record = Path::new(args.value("--record").unwrap_or(DEFAULT_TRUSTED_MANIFEST));
+
+    let mount = match (args.value("--device"), args.positionals()) {
+        (Some(device), []) => Some(utils::TemporaryMount::read_only(Path::new(device))?),
+        (None, [_]) => None,
+        _ => bail!("exactly one of --device or a directory must be given"),
+    };
+    let root = match &mount {
+        Some(mount) => mount.path().to_path_buf(),
+        None => PathBuf::from(&args.positionals()[0]),
+    };
+
+    let trusted = match std::fs::read_to_string(record) {
+        Ok(x) => Manifest::parse(&x)?,
+        Err(e) => bail!("failed to read the trusted record \"{}\": {}", record.display(), e),
+    };
+    let current = Manifest::from_directory(&root)?;
+
+    let differences = trusted.differences(&current);
+    if differences.is_empty() {
+        println!("the usb matches the last trusted update ({} files)", trusted.entries.len());
+        return Ok(());
+    }
+
+    eprintln!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+    eprintln!("WARNING: the usb boot files changed since the last trusted update.");
+    eprintln!("The kernel, initramfs or bootloader on the usb may have been tampered with.");
+    eprintln!("Do not enter any passphrase into a system booted from this usb until");
+    eprintln!("the usb has been rewritten with update_usb_boot.");
+    eprintln!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+    for difference in &differences {
+        eprintln!("{}", difference);
+    }
+    bail!("{} files on the usb differ from the last trusted update", differences.len());
+}
@@ -0,0 +1,662 @@
+//! Verifying the Authenticode signatures of EFI kernels and UKIs before kexec.
+//!
+//! Firmware with Secure Boot only checks the images it starts itself, which is the usb kernel.
+//! The runner checks the kernel or UKI of the real system the same way before loading it, so a
+//! tampered kernel is not booted even when Secure Boot is off.
+//!
+//! An image is trusted if its SHA-256 Authenticode digest is in the trust store, or if one of its
+//! PKCS#7 signatures is made by a certificate in the trust store, or by a certificate that is
+//! issued by one, through the certificates bundled in the signature. Like the firmware, the
+//! validity periods of the certificates are ignored, since the clock cannot be trusted at boot.
+//! Only SHA-256 with RSA is supported, which is what sbsign and the Microsoft CAs use.
+//!
+//! The trust store is the certificates in [`CERTS_DIR`], which the mkinitcpio hook bundles into
+//! the first stage. If there are none, `usbkexec.verify=on` uses the db of the firmware instead.
+//! Verification is on when there are bundled certificates, and can be turned off with
+//! `usbkexec.verify=off`. Separate initrds are not signed, so only a UKI covers its initrd.
+//!
+//! Whichever certificates are trusted, the dbx of the firmware is honoured like the firmware does:
+//! an image is rejected if its digest, or a certificate of the chain of its signature, is in it.
+
+use std::{fs, io, path::Path};
+
+use anyhow::{Context, Result, bail};
+use cms::{
+    cert::CertificateChoices,
+    content_info::ContentInfo,
+    signed_data::{SignedData, SignerIdentifier},
+};
+use der::{Decode, Encode, Sequence, asn1::{Any, ObjectIdentifier, OctetString}, oid::db::rfc5912};
+use rsa::{RsaPublicKey, pkcs1::DecodeRsaPublicKey, pkcs1v15::{Signature, VerifyingKey}, signature::Verifier};
+use sha2::{Digest, Sha256};
+use x509_cert::{
+    Certificate,
+    ext::pkix::{BasicConstraints, KeyUsage},
+    spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned},
+};
+
+use crate::{cmdline::KernelCommandLine, pe::{self, PeError, PeImage}};
+
+/// The kernel parameter that turns verification on or off.
+pub const VERIFY_KEY: &str = "usbkexec.verify";
+/// The trusted certificates bundled into the first stage, in PEM or DER files.
+pub const CERTS_DIR: &str = "/etc/usb-boot/certs";
+/// The db variable of the firmware, with the certificates and hashes allowed by Secure Boot.
+const DB_EFIVAR: &str = "/sys/firmware/efi/efivars/db-d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+/// The dbx variable of the firmware, with the certificates and hashes revoked by Secure Boot.
+const DBX_EFIVAR: &str = "/sys/firmware/efi/efivars/dbx-d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+/// `EFI_CERT_X509_GUID`, in the byte order of the firmware.
+const EFI_CERT_X509: [u8; 16] = [0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72];
+/// `EFI_CERT_SHA256_GUID`, in the byte order of the firmware.
+const EFI_CERT_SHA256: [u8; 16] = [0x26, 0x16, 0xc4, 0xc1, 0x4c, 0x50, 0x92, 0x40, 0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93, 0x43, 0x28];
+/// The size of the header of an `EFI_SIGNATURE_LIST`, and of the owner of each signature in it.
+const SIGNATURE_LIST_HEADER_SIZE: usize = 28;
+const SIGNATURE_OWNER_SIZE: usize = 16;
+/// `WIN_CERT_TYPE_PKCS_SIGNED_DATA`, the type of the entries of the certificate table with
+/// Authenticode signatures.
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 2;
+/// `SPC_INDIRECT_DATA_OBJID`, the content type of Authenticode signatures.
+const SPC_INDIRECT_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.4");
+const SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+const MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+/// How many certificates there may be between the signer and a trusted certificate.
+const MAX_CHAIN_LENGTH: usize = 8;
+
+/// The digest of the image, in `SpcIndirectDataContent`.
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+struct DigestInfo {
+    digest_algorithm: AlgorithmIdentifierOwned,
+    digest: OctetString,
+}
+
+/// The content of Authenticode signatures. `data` says what kind of file is signed, and is not
+/// needed to verify it.
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+struct SpcIndirectDataContent {
+    data: Any,
+    message_digest: DigestInfo,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum AuthenticodeError {
+    #[error(transparent)]
+    Pe(#[from] PeError),
+    #[error("the image is not signed, and its digest is not trusted")]
+    Unsigned,
+    #[error("the certificate table of the image is invalid")]
+    InvalidCertificateTable,
+    #[error("the signature is malformed: {0}")]
+    Malformed(#[from] der::Error),
+    #[error("the signature is not an Authenticode signature: {reason}")]
+    NotAuthenticode {
+        reason: &'static str,
+    },
+    #[error("the algorithm {oid} is not supported, only SHA-256 with RSA is")]
+    UnsupportedAlgorithm {
+        oid: ObjectIdentifier,
+    },
+    #[error("the image does not match the digest in the signature")]
+    DigestMismatch,
+    #[error("the certificate of the signer is not in the signature")]
+    MissingSignerCertificate,
+    #[error("the signature does not match the certificate of the signer")]
+    BadSignature,
+    #[error("the signer \"{subject}\" is not trusted")]
+    Untrusted {
+        subject: String,
+    },
+    #[error("the digest of the image is revoked in the dbx")]
+    RevokedDigest,
+    #[error("the certificate \"{subject}\" is revoked in the dbx")]
+    RevokedCertificate {
+        subject: String,
+    },
+    #[error("the signature list is invalid")]
+    InvalidSignatureList,
+}
+
+/// Computes the Authenticode digest of a PE image. The checksum, the entry of the certificate
+/// table and the table itself are left out, since signing changes them.
+pub fn image_digest(data: &[u8], image: &PeImage) -> Result<[u8; 32], AuthenticodeError> {
+    let checksum = image.checksum_offset();
+    let entry = image.certificate_table_entry_offset(data)?;
+    let headers = image.size_of_headers(data)?;
+    if headers > data.len() || entry + 8 > headers {
+        return Err(PeError::InvalidOptionalHeader.into());
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(&data[..checksum]);
+    hasher.update(&data[checksum + 4..entry]);
+    hasher.update(&data[entry + 8..headers]);
+
+    let mut sections: Vec<_> = image.sections.iter().filter(|x| x.raw_size > 0).collect();
+    sections.sort_by_key(|x| x.raw_offset);
+    let mut hashed = headers;
+    for section in sections {
+        let start = section.raw_offset as usize;
+        let contents = data.get(start..start + section.raw_size as usize)
+            .ok_or_else(|| PeError::SectionOutOfBounds { name: section.name.clone() })?;
+        hasher.update(contents);
+        hashed += contents.len();
+    }
+    // Whatever follows the sections, up to the certificate table at the end of the file.
+    let table_size = image.certificate_table(data)?.map_or(0, |(_, size)| size);
+    let end = data.len().checked_sub(table_size).ok_or(AuthenticodeError::InvalidCertificateTable)?;
+    if let Some(rest) = data.get(hashed..end) {
+        hasher.update(rest);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Returns the PKCS#7 signatures in the certificate table of the image.
+pub fn signatures<'a>(data: &'a [u8], image: &PeImage) -> Result<Vec<&'a [u8]>, AuthenticodeError> {
+    let (offset, size) = match image.certificate_table(data)? {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let table = data.get(offset..offset + size).ok_or(AuthenticodeError::InvalidCertificateTable)?;
+    let mut signatures = Vec::new();
+    let mut position = 0;
+    // Each entry is a WIN_CERTIFICATE: its length, revision and type, then the signature. The
+    // entries are aligned to 8 bytes.
+    while position + 8 <= table.len() {
+        let length = pe::read_u32(table, position).unwrap() as usize;
+        if length < 8 || position + length > table.len() {
+            return Err(AuthenticodeError::InvalidCertificateTable);
+        }
+        if pe::read_u16(table, position + 6) == Some(WIN_CERT_TYPE_PKCS_SIGNED_DATA) {
+            signatures.push(&table[position + 8..position + length]);
+        }
+        position += length.next_multiple_of(8);
+    }
+    Ok(signatures)
+}
+
+/// Verifies an RSA PKCS#1 v1.5 signature with SHA-256.
+fn verify_rsa(key: &SubjectPublicKeyInfoOwned, message: &[u8], signature: &[u8]) -> Result<(), AuthenticodeError> {
+    if key.algorithm.oid != rfc5912::RSA_ENCRYPTION {
+        return Err(AuthenticodeError::UnsupportedAlgorithm { oid: key.algorithm.oid });
+    }
+    let key = RsaPublicKey::from_pkcs1_der(key.subject_public_key.raw_bytes())
+        .map_err(|_| AuthenticodeError::BadSignature)?;
+    let signature = Signature::try_from(signature).map_err(|_| AuthenticodeError::BadSignature)?;
+    VerifyingKey::<Sha256>::new(key)
+        .verify(message, &signature)
+        .map_err(|_| AuthenticodeError::BadSignature)
+}
+
+/// Whether `certificate` is a CA whose key may sign certificates: its basic constraints have cA
+/// set, and its key usage includes keyCertSign.
+fn is_certificate_authority(certificate: &Certificate) -> bool {
+    let tbs = &certificate.tbs_certificate;
+    matches!(tbs.get::<BasicConstraints>(), Ok(Some((_, x))) if x.ca)
+        && matches!(tbs.get::<KeyUsage>(), Ok(Some((_, x))) if x.key_cert_sign())
+}
+
+/// Whether `certificate` is signed by the key of `issuer`, and `issuer` may sign certificates.
+fn is_issued_by(certificate: &Certificate, issuer: &Certificate) -> bool {
+    certificate.tbs_certificate.issuer == issuer.tbs_certificate.subject
+        && is_certificate_authority(issuer)
+        && certificate.signature_algorithm.oid == rfc5912::SHA_256_WITH_RSA_ENCRYPTION
+        && certificate.tbs_certificate.to_der().is_ok_and(|tbs| {
+            verify_rsa(&issuer.tbs_certificate.subject_public_key_info, &tbs, certificate.signature.raw_bytes()).is_ok()
+        })
+}
+
+/// The certificates and image digests that are trusted, and those that are revoked.
+#[derive(Debug, Default)]
+pub struct TrustStore {
+    pub certificates: Vec<Certificate>,
+    pub digests: Vec<[u8; 32]>,
+    pub revoked_certificates: Vec<Certificate>,
+    pub revoked_digests: Vec<[u8; 32]>,
+}
+
+/// Reads an efivar with a list of `EFI_SIGNATURE_LIST`s, like db. Returns None if it does not
+/// exist.
+fn read_signature_lists_efivar(path: &str) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        // The variable starts with its attributes.
+        Ok(data) => Ok(Some(data.get(4..).unwrap_or_default().to_vec())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path)),
+    }
+}
+
+/// Parses a list of `EFI_SIGNATURE_LIST`s into its X.509 certificates and SHA-256 digests. Other
+/// kinds of signatures are skipped.
+fn parse_signature_lists(mut data: &[u8]) -> Result<(Vec<Certificate>, Vec<[u8; 32]>), AuthenticodeError> {
+    let mut certificates = Vec::new();
+    let mut digests = Vec::new();
+    while !data.is_empty() {
+        let (list_size, header_size, signature_size) = match (pe::read_u32(data, 16), pe::read_u32(data, 20), pe::read_u32(data, 24)) {
+            (Some(list), Some(header), Some(signature)) => (list as usize, header as usize, signature as usize),
+            _ => return Err(AuthenticodeError::InvalidSignatureList),
+        };
+        let start = SIGNATURE_LIST_HEADER_SIZE + header_size;
+        if list_size < start || list_size > data.len() || signature_size <= SIGNATURE_OWNER_SIZE {
+            return Err(AuthenticodeError::InvalidSignatureList);
+        }
+        let kind = &data[..16];
+        for signature in data[start..list_size].chunks_exact(signature_size) {
+            let contents = &signature[SIGNATURE_OWNER_SIZE..];
+            if kind == EFI_CERT_X509 {
+                certificates.push(Certificate::from_der(contents)?);
+            } else if kind == EFI_CERT_SHA256 {
+                digests.push(contents.try_into().map_err(|_| AuthenticodeError::InvalidSignatureList)?);
+            }
+        }
+        data = &data[list_size..];
+    }
+    Ok((certificates, digests))
+}
+
+impl TrustStore {
+    /// Returns the trust store if verification is on, see the module documentation.
+    pub fn from_command_line(command_line: &KernelCommandLine) -> Result<Option<TrustStore>> {
+        let required = match command_line.get(VERIFY_KEY) {
+            None => false,
+            Some("on") => true,
+            Some("off") => return Ok(None),
+            Some(value) => bail!("unknown {}=\"{}\", expected on or off", VERIFY_KEY, value),
+        };
+        let mut store = TrustStore::default();
+        store.add_directory(Path::new(CERTS_DIR))?;
+        if store.is_empty() {
+            if !required {
+                return Ok(None);
+            }
+            if let Some(db) = read_signature_lists_efivar(DB_EFIVAR)? {
+                store.add_signature_lists(&db).context("failed to read the db efivar")?;
+            }
+            if store.is_empty() {
+                bail!("{}=on, but there are no certificates in {} and no db efivar", VERIFY_KEY, CERTS_DIR);
+            }
+        }
+        if let Some(dbx) = read_signature_lists_efivar(DBX_EFIVAR)? {
+            store.add_revocation_lists(&dbx).context("failed to read the dbx efivar")?;
+        }
+        Ok(Some(store))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.certificates.is_empty() && self.digests.is_empty()
+    }
+
+    /// Adds the certificates in the files of `directory`, if it exists.
+    pub fn add_directory(&mut self, directory: &Path) -> Result<()> {
+        let entries = match fs::read_dir(directory) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", directory.display())),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let data = fs::read(&path)?;
+            let certificates = if data.starts_with(b"-----BEGIN") {
+                Certificate::load_pem_chain(&data)
+            } else {
+                Certificate::from_der(&data).map(|x| vec![x])
+            };
+            self.certificates.extend(certificates.with_context(|| format!("failed to read the certificate {}", path.display()))?);
+        }
+        Ok(())
+    }
+
+    /// Trusts the X.509 certificates and SHA-256 digests of a list of `EFI_SIGNATURE_LIST`s, like
+    /// the db variable. Other kinds of signatures are skipped.
+    pub fn add_signature_lists(&mut self, data: &[u8]) -> Result<(), AuthenticodeError> {
+        let (certificates, digests) = parse_signature_lists(data)?;
+        self.certificates.extend(certificates);
+        self.digests.extend(digests);
+        Ok(())
+    }
+
+    /// Revokes the X.509 certificates and SHA-256 digests of a list of `EFI_SIGNATURE_LIST`s, like
+    /// the dbx variable. Other kinds of signatures are skipped.
+    pub fn add_revocation_lists(&mut self, data: &[u8]) -> Result<(), AuthenticodeError> {
+        let (certificates, digests) = parse_signature_lists(data)?;
+        self.revoked_certificates.extend(certificates);
+        self.revoked_digests.extend(digests);
+        Ok(())
+    }
+
+    fn check_revoked(&self, certificate: &Certificate) -> Result<(), AuthenticodeError> {
+        if self.revoked_certificates.contains(certificate) {
+            return Err(AuthenticodeError::RevokedCertificate { subject: certificate.tbs_certificate.subject.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Verifies that the PE image is trusted. If no signature is, returns the error of the first.
+    pub fn verify(&self, data: &[u8]) -> Result<(), AuthenticodeError> {
+        let image = PeImage::parse(data)?;
+        let digest = image_digest(data, &image)?;
+        if self.revoked_digests.contains(&digest) {
+            return Err(AuthenticodeError::RevokedDigest);
+        }
+        if self.digests.contains(&digest) {
+            return Ok(());
+        }
+        let mut first_error = None;
+        for signature in signatures(data, &image)? {
+            match self.verify_signature(signature, &digest) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                },
+            }
+        }
+        Err(first_error.unwrap_or(AuthenticodeError::Unsigned))
+    }
+
+    /// Verifies one PKCS#7 signature of an image with the given digest.
+    fn verify_signature(&self, signature: &[u8], digest: &[u8; 32]) -> Result<(), AuthenticodeError> {
+        let content_info = ContentInfo::from_der(signature)?;
+        if content_info.content_type != SIGNED_DATA {
+            return Err(AuthenticodeError::NotAuthenticode { reason: "it is not signed data" });
+        }
+        let signed_data: SignedData = content_info.content.decode_as()?;
+        let content = match &signed_data.encap_content_info {
+            x if x.econtent_type != SPC_INDIRECT_DATA => None,
+            x => x.econtent.as_ref(),
+        }.ok_or(AuthenticodeError::NotAuthenticode { reason: "the content is not SpcIndirectDataContent" })?;
+        let indirect: SpcIndirectDataContent = content.decode_as()?;
+        if indirect.message_digest.digest_algorithm.oid != rfc5912::ID_SHA_256 {
+            return Err(AuthenticodeError::UnsupportedAlgorithm { oid: indirect.message_digest.digest_algorithm.oid });
+        }
+        if indirect.message_digest.digest.as_bytes() != digest {
+            return Err(AuthenticodeError::DigestMismatch);
+        }
+
+        let certificates: Vec<&Certificate> = signed_data.certificates.iter()
+            .flat_map(|x| x.0.iter())
+            .filter_map(|x| match x {
+                CertificateChoices::Certificate(x) => Some(x),
+                _ => None,
+            })
+            .collect();
+        // Authenticode signatures have exactly one signer.
+        let signer = signed_data.signer_infos.0.iter().next()
+            .ok_or(AuthenticodeError::NotAuthenticode { reason: "there is no signer" })?;
+        let certificate = match &signer.sid {
+            SignerIdentifier::IssuerAndSerialNumber(sid) => certificates.iter().copied().find(|x| {
+                x.tbs_certificate.issuer == sid.issuer && x.tbs_certificate.serial_number == sid.serial_number
+            }),
+            SignerIdentifier::SubjectKeyIdentifier(_) => None,
+        }.ok_or(AuthenticodeError::MissingSignerCertificate)?;
+        for oid in [signer.digest_alg.oid, signer.signature_algorithm.oid] {
+            if ![rfc5912::ID_SHA_256, rfc5912::RSA_ENCRYPTION, rfc5912::SHA_256_WITH_RSA_ENCRYPTION].contains(&oid) {
+                return Err(AuthenticodeError::UnsupportedAlgorithm { oid });
+            }
+        }
+
+        // The signer signs the attributes, which include the digest of the content.
+        let attributes = signer.signed_attrs.as_ref()
+            .ok_or(AuthenticodeError::NotAuthenticode { reason: "there are no signed attributes" })?;
+        let message_digest: OctetString = attributes.iter()
+            .find(|x| x.oid == MESSAGE_DIGEST)
+            .and_then(|x| x.values.iter().next())
+            .ok_or(AuthenticodeError::NotAuthenticode { reason: "there is no message digest" })?
+            .decode_as()?;
+        if message_digest.as_bytes() != Sha256::digest(content.value()).as_slice() {
+            return Err(AuthenticodeError::DigestMismatch);
+        }
+        verify_rsa(&certificate.tbs_certificate.subject_public_key_info, &attributes.to_der()?, signer.signature.as_bytes())?;
+
+        self.check_chain(certificate, &certificates)
+    }
+
+    /// Checks that the certificate is trusted, or is issued by a trusted certificate through the
+    /// intermediate certificates, and that no certificate of that chain is revoked.
+    fn check_chain(&self, certificate: &Certificate, intermediates: &[&Certificate]) -> Result<(), AuthenticodeError> {
+        let mut current = certificate;
+        for _ in 0..MAX_CHAIN_LENGTH {
+            self.check_revoked(current)?;
+            if let Some(anchor) = self.certificates.iter().find(|x| *x == current || is_issued_by(current, x)) {
+                return self.check_revoked(anchor);
+            }
+            match intermediates.iter().find(|x| **x != current && is_issued_by(current, x)) {
+                Some(x) => current = x,
+                None => break,
+            }
+        }
+        Err(AuthenticodeError::Untrusted { subject: certificate.tbs_certificate.subject.to_string() })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use cms::{
+        cert::{CertificateChoices, IssuerAndSerialNumber},
+        content_info::CmsVersion,
+        signed_data::{CertificateSet, EncapsulatedContentInfo, SignerInfo, SignerInfos},
+    };
+    use der::{Tag, asn1::SetOfVec};
+    use rsa::{RsaPrivateKey, pkcs1v15::SigningKey, rand_core::OsRng, signature::{SignatureEncoding, Signer}};
+    use x509_cert::{
+        attr::Attribute,
+        builder::{Builder, CertificateBuilder, Profile},
+        name::Name,
+        serial_number::SerialNumber,
+        spki::EncodePublicKey,
+        time::Validity,
+    };
+
+    use super::*;
+    use crate::{pe::tests::build_image, utils};
+
+    pub(crate) struct TestKey {
+        pub key: RsaPrivateKey,
+        pub certificate: Certificate,
+    }
+
+    /// Generates a key with a CA certificate that is issued by `issuer`, or self-signed. The keys
+    /// are small, to keep the tests fast.
+    pub(crate) fn test_key(subject: &str, issuer: Option<&TestKey>) -> TestKey {
+        let profile = match issuer {
+            Some(issuer) => Profile::SubCA { issuer: issuer.certificate.tbs_certificate.subject.clone(), path_len_constraint: None },
+            None => Profile::Root,
+        };
+        generate_key(subject, profile, issuer)
+    }
+
+    /// Generates a key with an end entity certificate, which may not sign certificates.
+    fn test_leaf_key(subject: &str, issuer: &TestKey) -> TestKey {
+        let profile = Profile::Leaf {
+            issuer: issuer.certificate.tbs_certificate.subject.clone(),
+            enable_key_agreement: false,
+            enable_key_encipherment: false,
+        };
+        generate_key(subject, profile, Some(issuer))
+    }
+
+    fn generate_key(subject: &str, profile: Profile, issuer: Option<&TestKey>) -> TestKey {
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let signer = SigningKey::<Sha256>::new(issuer.map_or(&key, |x| &x.key).clone());
+        let public_key = SubjectPublicKeyInfoOwned::from_der(key.to_public_key().to_public_key_der().unwrap().as_bytes()).unwrap();
+        let certificate = CertificateBuilder::new(
+            profile,
+            SerialNumber::from(1u32),
+            Validity::from_now(Duration::from_secs(3600)).unwrap(),
+            Name::from_str(subject).unwrap(),
+            public_key,
+            &signer,
+        ).unwrap().build::<Signature>().unwrap();
+        TestKey { key, certificate }
+    }
+
+    fn sha256() -> AlgorithmIdentifierOwned {
+        AlgorithmIdentifierOwned { oid: rfc5912::ID_SHA_256, parameters: None }
+    }
+
+    /// Signs the image like sbsign does, with the certificates of `chain` in the signature.
+    pub(crate) fn sign(data: &[u8], signer: &TestKey, chain: &[&Certificate]) -> Vec<u8> {
+        let image = PeImage::parse(data).unwrap();
+        let indirect = SpcIndirectDataContent {
+            data: Any::new(Tag::Sequence, SPC_INDIRECT_DATA.to_der().unwrap()).unwrap(),
+            message_digest: DigestInfo {
+                digest_algorithm: sha256(),
+                digest: OctetString::new(image_digest(data, &image).unwrap()).unwrap(),
+            },
+        };
+        let content = Any::from_der(&indirect.to_der().unwrap()).unwrap();
+        let attributes = SetOfVec::try_from(vec![
+            Attribute {
+                oid: ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.3"),
+                values: SetOfVec::try_from(vec![Any::encode_from(&SPC_INDIRECT_DATA).unwrap()]).unwrap(),
+            },
+            Attribute {
+                oid: MESSAGE_DIGEST,
+                values: SetOfVec::try_from(vec![Any::new(Tag::OctetString, Sha256::digest(content.value()).to_vec()).unwrap()]).unwrap(),
+            },
+        ]).unwrap();
+        let signature = SigningKey::<Sha256>::new(signer.key.clone()).sign(&attributes.to_der().unwrap());
+        let signer_info = SignerInfo {
+            version: CmsVersion::V1,
+            sid: SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
+                issuer: signer.certificate.tbs_certificate.issuer.clone(),
+                serial_number: signer.certificate.tbs_certificate.serial_number.clone(),
+            }),
+            digest_alg: sha256(),
+            signed_attrs: Some(attributes),
+            signature_algorithm: AlgorithmIdentifierOwned { oid: rfc5912::RSA_ENCRYPTION, parameters: Some(Any::null()) },
+            signature: OctetString::new(signature.to_vec()).unwrap(),
+            unsigned_attrs: None,
+        };
+        let certificates = [&signer.certificate].into_iter().chain(chain.iter().copied())
+            .map(|x| CertificateChoices::Certificate(x.clone()))
+            .collect::<Vec<_>>();
+        let signed_data = SignedData {
+            version: CmsVersion::V1,
+            digest_algorithms: SetOfVec::try_from(vec![sha256()]).unwrap(),
+            encap_content_info: EncapsulatedContentInfo { econtent_type: SPC_INDIRECT_DATA, econtent: Some(content) },
+            certificates: Some(CertificateSet(SetOfVec::try_from(certificates).unwrap())),
+            crls: None,
+            signer_infos: SignerInfos(SetOfVec::try_from(vec![signer_info]).unwrap()),
+        };
+        let blob = ContentInfo { content_type: SIGNED_DATA, content: Any::encode_from(&signed_data).unwrap() }.to_der().unwrap();
+
+        let mut signed = data.to_vec();
+        signed.resize(signed.len().next_multiple_of(8), 0);
+        let table_offset = signed.len();
+        signed.extend_from_slice(&(8 + blob.len() as u32).to_le_bytes());
+        signed.extend_from_slice(&0x0200u16.to_le_bytes());
+        signed.extend_from_slice(&WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+        signed.extend_from_slice(&blob);
+        signed.resize(signed.len().next_multiple_of(8), 0);
+        let entry = image.certificate_table_entry_offset(data).unwrap();
+        pe::write_u32(&mut signed, entry, table_offset as u32);
+        let table_size = signed.len() - table_offset;
+        pe::write_u32(&mut signed, entry + 4, table_size as u32);
+        signed
+    }
+
+    /// Builds an `EFI_SIGNATURE_LIST` with one signature.
+    fn signature_list(kind: [u8; 16], contents: &[u8]) -> Vec<u8> {
+        let mut list = kind.to_vec();
+        let signature_size = SIGNATURE_OWNER_SIZE + contents.len();
+        for value in [SIGNATURE_LIST_HEADER_SIZE + signature_size, 0, signature_size] {
+            list.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        list.extend_from_slice(&[0; SIGNATURE_OWNER_SIZE]);
+        list.extend_from_slice(contents);
+        list
+    }
+
+    #[test]
+    fn test_verify() {
+        let root = test_key("CN=root", None);
+        let intermediate = test_key("CN=intermediate", Some(&root));
+        let signer = test_key("CN=signer", Some(&intermediate));
+        let other = test_key("CN=other", None);
+        let trusting = |certificates: &[&Certificate]| TrustStore {
+            certificates: certificates.iter().map(|x| (*x).clone()).collect(),
+            ..Default::default()
+        };
+        let revoking = |certificate: &Certificate| TrustStore {
+            certificates: vec![root.certificate.clone()],
+            revoked_certificates: vec![certificate.clone()],
+            ..Default::default()
+        };
+
+        let image = build_image(&[(".text", b"code"), (".linux", &[1; 0x300])]);
+        let signed = sign(&image, &signer, &[&intermediate.certificate]);
+        let digest = image_digest(&image, &PeImage::parse(&image).unwrap()).unwrap();
+        assert_eq!(image_digest(&signed, &PeImage::parse(&signed).unwrap()), Ok(digest));
+        let mut tampered = signed.clone();
+        tampered[0x400] ^= 1;
+        let unchained = sign(&image, &signer, &[]);
+        // End entity certificates may sign images, but not other certificates.
+        let leaf = test_leaf_key("CN=leaf", &intermediate);
+        let leaf_signed = sign(&image, &leaf, &[&intermediate.certificate]);
+        let leaf_of_leaf = test_leaf_key("CN=leaf of leaf", &leaf);
+        let leaf_of_leaf_signed = sign(&image, &leaf_of_leaf, &[&leaf.certificate, &intermediate.certificate]);
+
+        let test_cases = [
+            (trusting(&[&root.certificate]), &signed, Ok(())),
+            (trusting(&[&intermediate.certificate]), &signed, Ok(())),
+            (trusting(&[&signer.certificate]), &signed, Ok(())),
+            (trusting(&[&other.certificate, &root.certificate]), &signed, Ok(())),
+            (trusting(&[&other.certificate]), &signed, Err(AuthenticodeError::Untrusted { subject: "CN=signer".to_string() })),
+            (trusting(&[&root.certificate]), &unchained, Err(AuthenticodeError::Untrusted { subject: "CN=signer".to_string() })),
+            (trusting(&[&root.certificate]), &tampered, Err(AuthenticodeError::DigestMismatch)),
+            (trusting(&[&root.certificate]), &leaf_signed, Ok(())),
+            (trusting(&[&root.certificate]), &leaf_of_leaf_signed, Err(AuthenticodeError::Untrusted { subject: "CN=leaf of leaf".to_string() })),
+            (trusting(&[&leaf.certificate]), &leaf_of_leaf_signed, Err(AuthenticodeError::Untrusted { subject: "CN=leaf of leaf".to_string() })),
+            (trusting(&[&leaf_of_leaf.certificate]), &leaf_of_leaf_signed, Ok(())),
+            (trusting(&[&root.certificate]), &image, Err(AuthenticodeError::Unsigned)),
+            (TrustStore { digests: vec![digest], ..Default::default() }, &image, Ok(())),
+            (TrustStore { digests: vec![digest], ..Default::default() }, &tampered, Err(AuthenticodeError::DigestMismatch)),
+            // Revoked signers, intermediates, anchors and digests are rejected.
+            (revoking(&signer.certificate), &signed, Err(AuthenticodeError::RevokedCertificate { subject: "CN=signer".to_string() })),
+            (revoking(&intermediate.certificate), &signed, Err(AuthenticodeError::RevokedCertificate { subject: "CN=intermediate".to_string() })),
+            (revoking(&root.certificate), &signed, Err(AuthenticodeError::RevokedCertificate { subject: "CN=root".to_string() })),
+            (revoking(&other.certificate), &signed, Ok(())),
+            (TrustStore { digests: vec![digest], revoked_digests: vec![digest], ..Default::default() }, &image, Err(AuthenticodeError::RevokedDigest)),
+            (TrustStore { revoked_digests: vec![digest], ..revoking(&other.certificate) }, &signed, Err(AuthenticodeError::RevokedDigest)),
+        ];
+        for (store, data, expected) in test_cases {
+            assert_eq!(store.verify(data), expected);
+        }
+    }
+
+    /// A small EFI application, signed outside of this program by a key certified by `ca.der`,
+    /// with trailing data before the certificate table. The digest was computed with goblin.
+    const SIGNED_FIXTURE: &[u8] = include_bytes!("testdata/authenticode/signed.efi");
+    const FIXTURE_CA: &[u8] = include_bytes!("testdata/authenticode/ca.der");
+    const FIXTURE_DIGEST: &str = "f10585dc33a00b618f6b036e9954d824850b306fa1a1f59b232e2b48ef2b487b";
+
+    #[test]
+    fn test_known_answer() {
+        let image = PeImage::parse(SIGNED_FIXTURE).unwrap();
+        assert_eq!(utils::to_hex(&image_digest(SIGNED_FIXTURE, &image).unwrap()), FIXTURE_DIGEST);
+
+        let store = TrustStore { certificates: vec![Certificate::from_der(FIXTURE_CA).unwrap()], ..Default::default() };
+        assert_eq!(store.verify(SIGNED_FIXTURE), Ok(()));
+        let mut tampered = SIGNED_FIXTURE.to_vec();
+        tampered[image.sections[0].raw_offset as usize] ^= 1;
+        assert_eq!(store.verify(&tampered), Err(AuthenticodeError::DigestMismatch));
+    }
+
+    #[test]
+    fn test_add_signature_lists() {
+        let root = test_key("CN=root", None);
+        let mut db = signature_list(EFI_CERT_X509, &root.certificate.to_der().unwrap());
+        db.extend(signature_list(EFI_CERT_SHA256, &[7; 32]));
+        db.extend(signature_list([0; 16], &[8; 20]));
+        let mut store = TrustStore::default();
+        store.add_signature_lists(&db).unwrap();
+        assert_eq!(store.certificates, [root.certificate]);
+        assert_eq!(store.digests, [[7; 32]]);
+
+        assert_eq!(store.add_signature_lists(&db[..db.len() - 1]), Err(AuthenticodeError::InvalidSignatureList));
+
+        let dbx = signature_list(EFI_CERT_X509, &store.certificates[0].to_der().unwrap());
+        store.add_revocation_lists(&dbx).unwrap();
+        assert_eq!(store.revoked_certificates, store.certificates);
+        assert!(store.revoked_digests.is_empty());
+    }
+}
@@ -0,0 +1,336 @@
+//! Boot Loader Specification (Type #1) entries on the root of the real system.
+//!
+//! With `usbkexec.entry=` on the kernel command line, the runner takes the kernel, initrds and
+//! command line from an entry in `loader/entries/*.conf` on the real system, instead of from the
+//! kernel command line of the usb. Updating kernels on the real system then needs no change to
+//! the usb. `usbkexec.entry=default` selects the entry that `default` in `loader/loader.conf`
+//! selects, or the first entry if it is not set. Any other value is a glob matched against the
+//! entry IDs, e.g. `usbkexec.entry=arch-*`, and selects the first matching entry.
+//!
+//! Entries are ordered like systemd-boot does: entries with a `sort-key` first, by `sort-key`,
+//! `machine-id` and newest `version`, then the others by newest file name.
+
+use std::{cmp::Ordering, io::Read, path::{Path, PathBuf}};
+
+use anyhow::{Context, Result, bail};
+
+use crate::target_root::TargetRoot;
+
+/// The kernel parameter that selects a boot loader entry.
+pub const ENTRY_KEY: &str = "usbkexec.entry";
+/// Where the partition with the entries (`$BOOT`) may be mounted on the real system.
+pub const BOOT_CANDIDATES: &[&str] = &["/boot", "/efi", "/boot/efi"];
+
+/// A boot loader entry.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct BlsEntry {
+    /// The file name without `.conf`.
+    pub id: String,
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub machine_id: Option<String>,
+    pub sort_key: Option<String>,
+    /// Path of the kernel, relative to `$BOOT`.
+    pub linux: String,
+    /// Paths of the initrds, relative to `$BOOT`, in the order they are loaded.
+    pub initrd: Vec<String>,
+    /// Every `options` line, in order.
+    pub options: Vec<String>,
+    pub architecture: Option<String>,
+    /// Path of the device tree, relative to `$BOOT`.
+    pub devicetree: Option<String>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ParseBlsError {
+    #[error("{id}: no linux line, the entry is not a Type #1 entry for Linux")]
+    MissingLinux {
+        id: String,
+    },
+}
+
+impl BlsEntry {
+    pub fn parse(id: &str, contents: &str) -> Result<BlsEntry, ParseBlsError> {
+        let mut entry = BlsEntry { id: id.to_string(), ..Default::default() };
+        let mut linux = None;
+        for line in contents.lines().map(|x| x.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once(|c: char| c.is_ascii_whitespace()) {
+                Some((key, value)) => (key, value.trim().to_string()),
+                None => (line, String::new()),
+            };
+            match key {
+                "title" => entry.title = Some(value),
+                "version" => entry.version = Some(value),
+                "machine-id" => entry.machine_id = Some(value),
+                "sort-key" => entry.sort_key = Some(value),
+                "linux" => linux = Some(value),
+                "initrd" => entry.initrd.push(value),
+                "options" => entry.options.push(value),
+                "architecture" => entry.architecture = Some(value.to_ascii_lowercase()),
+                "devicetree" => entry.devicetree = Some(value),
+                // Unknown keys, like efi, are for other kinds of entries.
+                _ => {},
+            }
+        }
+        entry.linux = linux.ok_or(ParseBlsError::MissingLinux { id: id.to_string() })?;
+        Ok(entry)
+    }
+
+    /// The kernel command line of the entry.
+    pub fn command_line(&self) -> String {
+        self.options.join(" ")
+    }
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let start = digits.iter().position(|x| *x != b'0').unwrap_or(digits.len());
+    &digits[start..]
+}
+
+/// Compares versions like the UAPI version format: runs of digits compare numerically, other
+/// characters compare one by one, and `~` sorts before everything, even the end.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (Some(b'~'), Some(b'~')) => {},
+            (Some(b'~'), _) => return Ordering::Less,
+            (_, Some(b'~')) => return Ordering::Greater,
+            (None, None) => return Ordering::Equal,
+            (None, _) => return Ordering::Less,
+            (_, None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let digits = |s: &[u8]| s.iter().take_while(|x| x.is_ascii_digit()).count();
+                let (length_a, length_b) = (digits(a), digits(b));
+                let (number_a, number_b) = (trim_zeros(&a[..length_a]), trim_zeros(&b[..length_b]));
+                let ordering = number_a.len().cmp(&number_b.len()).then(number_a.cmp(number_b));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a = &a[length_a..];
+                b = &b[length_b..];
+                continue;
+            },
+            (Some(x), Some(y)) if x != y => return x.cmp(y),
+            _ => {},
+        }
+        a = &a[1..];
+        b = &b[1..];
+    }
+}
+
+/// Sorts entries in the order of the boot menu.
+pub fn sort_entries(entries: &mut [BlsEntry]) {
+    entries.sort_by(|a, b| match (&a.sort_key, &b.sort_key) {
+        (Some(key_a), Some(key_b)) => key_a.cmp(key_b)
+            .then_with(|| a.machine_id.cmp(&b.machine_id))
+            .then_with(|| compare_versions(
+                b.version.as_deref().unwrap_or(""),
+                a.version.as_deref().unwrap_or(""),
+            ))
+            .then_with(|| compare_versions(&b.id, &a.id)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => compare_versions(&b.id, &a.id),
+    });
+}
+
+/// Matches a glob with `*` and `?` against a string.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    match pattern.chars().next() {
+        None => text.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            text.char_indices().map(|(i, _)| i).chain([text.len()]).any(|i| glob_matches(rest, &text[i..]))
+        },
+        Some(c) => match text.chars().next() {
+            Some(t) if c == '?' || c == t => glob_matches(&pattern[c.len_utf8()..], &text[t.len_utf8()..]),
+            _ => false,
+        },
+    }
+}
+
+/// Returns the `default` of `loader.conf`, if set.
+pub fn parse_loader_conf(contents: &str) -> Option<String> {
+    // The last default wins.
+    contents.lines()
+        .rev()
+        .map(|x| x.trim())
+        .filter_map(|x| x.split_once(|c: char| c.is_ascii_whitespace()))
+        .find(|(key, _)| *key == "default")
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Selects the entry for `usbkexec.entry=<selector>` from sorted entries.
+pub fn select<'a>(entries: &'a [BlsEntry], selector: &str, loader_default: Option<&str>) -> Option<&'a BlsEntry> {
+    let pattern = match (selector, loader_default) {
+        ("default", None) => return entries.first(),
+        ("default", Some(default)) => default,
+        (pattern, _) => pattern,
+    };
+    entries.iter().find(|x| glob_matches(pattern, &x.id) || glob_matches(pattern, &format!("{}.conf", x.id)))
+}
+
+/// The architecture of entries this machine can boot, as named in entries.
+pub(crate) fn native_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "x64",
+        "x86" => "ia32",
+        "aarch64" => "aa64",
+        "arm" => "arm",
+        "riscv64" => "riscv64",
+        other => other,
+    }
+}
+
+fn read_in_root(target_root: &TargetRoot, path: &str) -> std::io::Result<String> {
+    let mut contents = String::new();
+    target_root.open(path)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Reads the entries in `<boot>/loader/entries` of the real system, sorted. Entries that are
+/// malformed or for another architecture are skipped.
+pub fn read_entries(target_root: &TargetRoot, boot: &str) -> std::io::Result<Vec<BlsEntry>> {
+    let directory = format!("{}/loader/entries", boot);
+    let mut entries = Vec::new();
+    for name in target_root.read_dir(&directory)? {
+        let name = name.to_string_lossy();
+        let id = match name.strip_suffix(".conf") {
+            Some(x) => x,
+            None => continue,
+        };
+        match BlsEntry::parse(id, &read_in_root(target_root, &format!("{}/{}", directory, name))?) {
+            Ok(entry) if entry.architecture.as_deref().is_none_or(|x| x == native_architecture()) => entries.push(entry),
+            Ok(_) => {},
+            Err(e) => eprintln!("skipping boot loader entry: {}", e),
+        }
+    }
+    sort_entries(&mut entries);
+    Ok(entries)
+}
+
+/// Finds the entry selected by `selector` on the real system.
+/// Returns the path `$BOOT` is mounted on in the real system, and the entry.
+pub fn find_entry(target_root: &TargetRoot, selector: &str) -> Result<(PathBuf, BlsEntry)> {
+    for boot in BOOT_CANDIDATES {
+        let entries = match read_entries(target_root, boot) {
+            Ok(x) if !x.is_empty() => x,
+            Ok(_) => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to read the boot loader entries in {}/loader/entries", boot)),
+        };
+        let loader_default = read_in_root(target_root, &format!("{}/loader/loader.conf", boot))
+            .ok()
+            .and_then(|x| parse_loader_conf(&x));
+        return match select(&entries, selector, loader_default.as_deref()) {
+            Some(entry) => Ok((Path::new(boot).to_path_buf(), entry.clone())),
+            None => bail!("no boot loader entry in {}/loader/entries matches \"{}\"", boot, selector),
+        };
+    }
+    bail!("no boot loader entries found in {}", BOOT_CANDIDATES.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let contents = "\
+# Generated by kernel-install
+title      Arch Linux
+version    6.1.1-arch1-1
+sort-key   arch
+linux      /vmlinuz-linux
+initrd     /intel-ucode.img
+initrd     /initramfs-linux.img
+options    root=/dev/mapper/root rw
+options    quiet
+devicetree /dtb
+";
+        assert_eq!(BlsEntry::parse("arch", contents), Ok(BlsEntry {
+            id: "arch".to_string(),
+            title: Some("Arch Linux".to_string()),
+            version: Some("6.1.1-arch1-1".to_string()),
+            sort_key: Some("arch".to_string()),
+            linux: "/vmlinuz-linux".to_string(),
+            initrd: vec!["/intel-ucode.img".to_string(), "/initramfs-linux.img".to_string()],
+            options: vec!["root=/dev/mapper/root rw".to_string(), "quiet".to_string()],
+            devicetree: Some("/dtb".to_string()),
+            ..Default::default()
+        }));
+        assert_eq!(BlsEntry::parse("arch", contents).unwrap().command_line(), "root=/dev/mapper/root rw quiet");
+        assert_eq!(BlsEntry::parse("efi", "efi /EFI/shell.efi"), Err(ParseBlsError::MissingLinux { id: "efi".to_string() }));
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        let test_cases = [
+            ("6.1.10", "6.1.9", Ordering::Greater),
+            ("6.1.01", "6.1.1", Ordering::Equal),
+            ("6.1", "6.1.1", Ordering::Less),
+            ("6.1~rc1", "6.1", Ordering::Less),
+            ("6.1-arch1", "6.1-arch2", Ordering::Less),
+        ];
+        for (a, b, expected) in test_cases {
+            assert_eq!(compare_versions(a, b), expected, "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_sort_and_select() {
+        let entry = |id: &str, sort_key: Option<&str>, version: Option<&str>| BlsEntry {
+            id: id.to_string(),
+            sort_key: sort_key.map(|x| x.to_string()),
+            version: version.map(|x| x.to_string()),
+            ..Default::default()
+        };
+        let mut entries = vec![
+            entry("linux-6.1.9", None, None),
+            entry("linux-6.1.10", None, None),
+            entry("arch-lts", Some("arch"), Some("5.15.80")),
+            entry("arch", Some("arch"), Some("6.1.10")),
+            entry("debian", Some("debian"), Some("6.1.10")),
+        ];
+        sort_entries(&mut entries);
+        let ids: Vec<&str> = entries.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(ids, ["arch", "arch-lts", "debian", "linux-6.1.10", "linux-6.1.9"]);
+
+        let test_cases = [
+            ("default", None, Some("arch")),
+            ("default", Some("linux-*"), Some("linux-6.1.10")),
+            ("default", Some("debian.conf"), Some("debian")),
+            ("arch-?ts", Some("debian.conf"), Some("arch-lts")),
+            ("fedora*", None, None),
+        ];
+        for (selector, loader_default, expected) in test_cases {
+            assert_eq!(select(&entries, selector, loader_default).map(|x| x.id.as_str()), expected, "{}", selector);
+        }
+        assert_eq!(parse_loader_conf("timeout 3\ndefault  arch-*\n"), Some("arch-*".to_string()));
+    }
+
+    #[test]
+    fn test_find_entry() {
+        let test_directory = crate::utils::TestDirectory::new("bls");
+        let directory = test_directory.path();
+        std::fs::create_dir_all(directory.join("efi/loader/entries")).unwrap();
+        let command_line = crate::cmdline::KernelCommandLine::parse(&format!("usbkexec.root={}", directory.display()));
+        let target_root = TargetRoot::locate(&command_line).unwrap();
+
+        let error = find_entry(&target_root, "default").unwrap_err();
+        assert_eq!(error.to_string(), "no boot loader entries found in /boot, /efi, /boot/efi");
+        std::fs::write(directory.join("efi/loader/entries/arch.conf"), "title Arch\nlinux /vmlinuz-linux\n").unwrap();
+        let (boot, entry) = find_entry(&target_root, "default").unwrap();
+        assert_eq!((boot.as_path(), entry.id.as_str()), (Path::new("/efi"), "arch"));
+
+        // Other errors than a missing directory are not skipped.
+        std::fs::create_dir_all(directory.join("boot/loader")).unwrap();
+        std::fs::write(directory.join("boot/loader/entries"), "").unwrap();
+        let error = find_entry(&target_root, "default").unwrap_err();
+        assert_eq!(error.to_string(), "failed to read the boot loader entries in /boot/loader/entries");
+    }
+}
@@ -0,0 +1,394 @@
+//! Generating the boot loader config of the usb, with the command line of the first stage.
+//!
+//! The first stage command line has to carry the kernel, initrd and command line of the second
+//! stage in the `usbkexec.*` parameters, with the command line quoted so the runner takes it as
+//! one parameter. Each boot loader passes the command line to the kernel in its own way, so the
+//! quoting is easy to get wrong by hand. The `gen-bootloader` subcommand writes the entry, and
+//! checks that the runner would turn what the kernel receives back into the desired second stage.
+//!
+//! systemd-boot and syslinux pass the command line as it is written, so the additional arguments
+//! are put into one `usbkexec.args=` in double quotes, or single quotes if they contain double
+//! quotes, or encoded with `base64:` if they contain both. GRUB escapes quotes and backslashes
+//! with backslashes, which the runner does not understand, so each parameter of the second stage
+//! gets its own `usbkexec.args=`, encoded if it has spaces, quotes or backslashes. The parameters
+//! of the first stage itself cannot have them with GRUB.
+//!
+//! syslinux and GRUB add `BOOT_IMAGE=` in front of the command line, and syslinux adds `initrd=`
+//! at the end. The runner passes them on like the parameters of the first stage, if the
+//! inheritance given with `--inherit` allows it, like the `--inherit` of the runner.
+
+use std::fs;
+
+use anyhow::{Result, bail};
+
+use crate::{
+    cli,
+    initramfs_kexec_runner::{self, BASE64_PREFIX, HEX_PREFIX, Inheritance, TransformParameters, encode_additional_args},
+    uki,
+    utils,
+};
+
+/// The boot loaders that configs can be generated for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Loader {
+    /// A Boot Loader Specification entry, for systemd-boot.
+    SystemdBoot,
+    /// A `syslinux.cfg`, also used by extlinux and isolinux.
+    Syslinux,
+    /// A `grub.cfg` menu entry.
+    Grub,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum BootloaderError {
+    #[error("unknown boot loader \"{value}\", expected systemd-boot, syslinux or grub")]
+    UnknownLoader {
+        value: String,
+    },
+    #[error("{loader} cannot pass \"{value}\" to the kernel unchanged")]
+    CannotPass {
+        loader: &'static str,
+        value: String,
+    },
+    #[error("the runner would boot {got} instead of {expected}")]
+    RoundTrip {
+        expected: String,
+        got: String,
+    },
+}
+
+impl Loader {
+    pub fn parse(value: &str) -> Result<Loader, BootloaderError> {
+        match value {
+            "systemd-boot" => Ok(Loader::SystemdBoot),
+            "syslinux" => Ok(Loader::Syslinux),
+            "grub" => Ok(Loader::Grub),
+            _ => Err(BootloaderError::UnknownLoader { value: value.to_string() }),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Loader::SystemdBoot => "systemd-boot",
+            Loader::Syslinux => "syslinux",
+            Loader::Grub => "GRUB",
+        }
+    }
+}
+
+/// The boot entry of the usb: the first stage on the usb, and the second stage it kexecs into.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BootEntry<'a> {
+    pub title: &'a str,
+    /// The kernel and initramfs of the first stage, as the boot loader finds them.
+    pub kernel: &'a str,
+    pub initrd: &'a str,
+    /// Parameters of the first stage, which the runner passes on to the second stage.
+    pub cmdline: &'a str,
+    /// The kernel, initrd and command line of the second stage.
+    pub target_kernel: &'a str,
+    pub target_initrd: &'a str,
+    pub target_cmdline: &'a str,
+}
+
+/// Splits a command line into its parameters, like the runner does.
+fn parameters(command_line: &str) -> impl Iterator<Item=&str> {
+    utils::split_at_unquoted_spaces(command_line)
+}
+
+/// Returns the parameters of the first stage, as they are given to the boot loader.
+fn first_stage_parameters(loader: Loader, entry: &BootEntry) -> Result<Vec<String>> {
+    let keys = TransformParameters::default();
+    if loader != Loader::Grub {
+        let mut transform = vec![(keys.kernel.as_str(), entry.target_kernel), (keys.initrd.as_str(), entry.target_initrd)];
+        if !entry.target_cmdline.trim().is_empty() {
+            transform.push((keys.additional_args.as_str(), entry.target_cmdline.trim()));
+        }
+        let command_line = uki::first_stage_command_line(entry.cmdline, &transform)?;
+        return Ok(parameters(&command_line).map(str::to_string).collect());
+    }
+
+    let mut result: Vec<String> = parameters(entry.cmdline).map(str::to_string).collect();
+    result.push(format!("{}={}", keys.kernel, entry.target_kernel));
+    result.push(format!("{}={}", keys.initrd, entry.target_initrd));
+    let grub_escapes = |x: &str| x.contains([' ', '"', '\'', '\\']);
+    if let Some(parameter) = result.iter().find(|x| grub_escapes(x)) {
+        return Err(BootloaderError::CannotPass { loader: loader.name(), value: parameter.clone() }.into());
+    }
+    for parameter in parameters(entry.target_cmdline) {
+        let looks_encoded = [BASE64_PREFIX, HEX_PREFIX].iter().any(|x| parameter.starts_with(x));
+        let value = match grub_escapes(parameter) || looks_encoded {
+            true => encode_additional_args(parameter),
+            false => parameter.to_string(),
+        };
+        result.push(format!("{}={}", keys.additional_args, value));
+    }
+    Ok(result)
+}
+
+/// Returns the command line the kernel receives from the boot loader, given the parameters.
+fn kernel_command_line(loader: Loader, entry: &BootEntry, parameters: &[String]) -> String {
+    let parameters = parameters.join(" ");
+    match loader {
+        Loader::SystemdBoot => parameters,
+        Loader::Syslinux => format!("BOOT_IMAGE={} {} initrd={}", entry.kernel, parameters, entry.initrd),
+        Loader::Grub => format!("BOOT_IMAGE={} {}", entry.kernel, parameters),
+    }
+}
+
+/// Checks that the runner, given `command_line` and `inheritance`, would boot the second stage
+/// of the entry with the same parameters as the desired command line, after the ones passed on
+/// from the first stage.
+fn check_round_trip(loader: Loader, entry: &BootEntry, command_line: &str, inheritance: &Inheritance) -> Result<()> {
+    let boot_image = format!("BOOT_IMAGE={}", entry.kernel);
+    let initrd = format!("initrd={}", entry.initrd);
+    let (leading, trailing) = match loader {
+        Loader::SystemdBoot => (None, None),
+        Loader::Syslinux => (Some(boot_image.as_str()), Some(initrd.as_str())),
+        Loader::Grub => (Some(boot_image.as_str()), None),
+    };
+    let inherited = |parameter: &&str| inheritance.inherits(parameter.split_once('=').map_or(*parameter, |(key, _)| key));
+    let mut expected: Vec<&str> = leading.into_iter().chain(parameters(entry.cmdline)).filter(inherited).collect();
+    expected.extend(parameters(entry.target_cmdline));
+    expected.extend(trailing.into_iter().filter(inherited));
+
+    let kexec_args = match initramfs_kexec_runner::transform_command_line(command_line, TransformParameters::default().try_into().unwrap(), inheritance) {
+        Ok(x) => x,
+        Err(e) => bail!("the runner would reject the command line \"{}\": {}", command_line, e.to_string().trim_end()),
+    };
+    let got: Vec<&str> = parameters(&kexec_args.command_line).collect();
+    let describe = |kernel: &str, initrd: &str, parameters: &[&str]| {
+        format!("\"{}\" with the initrd \"{}\" and the command line \"{}\"", kernel, initrd, parameters.join(" "))
+    };
+    if got != expected || kexec_args.kernel != entry.target_kernel || kexec_args.initrds != [entry.target_initrd] {
+        return Err(BootloaderError::RoundTrip {
+            expected: describe(entry.target_kernel, entry.target_initrd, &expected),
+            got: describe(&kexec_args.kernel, &kexec_args.initrds.join(" "), &got),
+        }.into());
+    }
+    Ok(())
+}
+
+/// Quotes a word for GRUB's script syntax.
+fn grub_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Generates the boot loader config for the entry, and checks it by round trip through a runner
+/// with `inheritance`. Returns the config and the command line the kernel receives.
+pub fn generate(loader: Loader, entry: &BootEntry, inheritance: &Inheritance) -> Result<(String, String)> {
+    for value in [entry.title, entry.kernel, entry.initrd, entry.cmdline, entry.target_kernel, entry.target_initrd, entry.target_cmdline] {
+        if value.contains(char::is_control) {
+            return Err(BootloaderError::CannotPass { loader: loader.name(), value: value.to_string() }.into());
+        }
+    }
+    for path in [entry.kernel, entry.initrd] {
+        if path.is_empty() || path.contains([' ', '"', '\'']) {
+            return Err(BootloaderError::CannotPass { loader: loader.name(), value: path.to_string() }.into());
+        }
+    }
+    let parameters = first_stage_parameters(loader, entry)?;
+    let command_line = kernel_command_line(loader, entry, &parameters);
+    check_round_trip(loader, entry, &command_line, inheritance)?;
+
+    let config = match loader {
+        Loader::SystemdBoot => format!(
+            "title   {}\nlinux   {}\ninitrd  {}\noptions {}\n",
+            entry.title, entry.kernel, entry.initrd, parameters.join(" "),
+        ),
+        Loader::Syslinux => format!(
+            "DEFAULT usb-boot\nLABEL usb-boot\n    MENU LABEL {}\n    LINUX {}\n    INITRD {}\n    APPEND {}\n",
+            entry.title, entry.kernel, entry.initrd, parameters.join(" "),
+        ),
+        Loader::Grub => format!(
+            "menuentry {} {{\n    linux {} {}\n    initrd {}\n}}\n",
+            grub_quote(entry.title),
+            grub_quote(entry.kernel),
+            parameters.iter().map(|x| grub_quote(x)).collect::<Vec<_>>().join(" "),
+            grub_quote(entry.initrd),
+        ),
+    };
+    Ok((config, command_line))
+}
+
+/// Entry point of the `gen-bootloader` subcommand.
+///
+/// Usage: `gen-bootloader --loader <systemd-boot|syslinux|grub> --kernel <path> --initrd <path>
+/// --target-kernel <path> --target-initrd <path> [--target-cmdline <parameters>]
+/// [--cmdline <parameters>] [--title <title>] [--inherit <inheritance>] [--output <file>]`
+///
+/// `--kernel` and `--initrd` are the first stage, as the boot loader finds them on the usb. The
+/// `--target-*` options are the second stage, in the real system. `--cmdline` adds parameters to
+/// the first stage, like `usbkexec.luks=`. `--inherit` is the `--inherit` the runner is given,
+/// by default everything but `BOOT_IMAGE=` and `initrd=`, like in the units generated by
+/// `gen-units`. The config is written to `--output`, or printed.
+pub fn gen_bootloader_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        options: &[
+            "--loader", "--kernel", "--initrd", "--target-kernel", "--target-initrd", "--target-cmdline",
+            "--cmdline", "--title", "--inherit", "--output",
+        ],
+        required: &["--loader", "--kernel", "--initrd", "--target-kernel", "--target-initrd"],
+        ..Default::default()
+    })?;
+    let loader = Loader::parse(args.value("--loader").unwrap())?;
+    let entry = BootEntry {
+        title: args.value("--title").unwrap_or("usb-boot"),
+        kernel: args.value("--kernel").unwrap(),
+        initrd: args.value("--initrd").unwrap(),
+        cmdline: args.value("--cmdline").unwrap_or(""),
+        target_kernel: args.value("--target-kernel").unwrap(),
+        target_initrd: args.value("--target-initrd").unwrap(),
+        target_cmdline: args.value("--target-cmdline").unwrap_or(""),
+    };
+    let inheritance = match args.value("--inherit") {
+        Some(value) => match Inheritance::parse(value) {
+            Some(x) => x,
+            None => bail!("invalid inheritance \"{}\", expected all, none, allow:PATTERN,... or deny:PATTERN,...", value),
+        },
+        None => Inheritance::default(),
+    };
+    let (config, command_line) = generate(loader, &entry, &inheritance)?;
+    match args.value("--output") {
+        Some(output) => {
+            fs::write(output, config)?;
+            println!("wrote {}, the kernel will get the command line: {}", output, command_line);
+        },
+        None => {
+            print!("{}", config);
+            eprintln!("the kernel will get the command line: {}", command_line);
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let entry = BootEntry {
+            title: "Arch Linux",
+            kernel: "/usb-boot/kernel",
+            initrd: "/usb-boot/initramfs.img",
+            cmdline: "usbkexec.luks=UUID=1234",
+            target_kernel: "/boot/vmlinuz-linux",
+            target_initrd: "/boot/initramfs-linux.img",
+            target_cmdline: "root=/dev/mapper/root rw quiet",
+        };
+        let test_cases = [
+            (Loader::SystemdBoot, entry.clone(), Ok(concat!(
+                "title   Arch Linux\n",
+                "linux   /usb-boot/kernel\n",
+                "initrd  /usb-boot/initramfs.img\n",
+                "options usbkexec.luks=UUID=1234 usbkexec.kernel=/boot/vmlinuz-linux usbkexec.initrd=/boot/initramfs-linux.img usbkexec.args=\"root=/dev/mapper/root rw quiet\"\n",
+            ).to_string())),
+            (Loader::Syslinux, BootEntry { target_cmdline: r#"root=/dev/sda2 x="a  b" y"#, ..entry.clone() }, Ok(concat!(
+                "DEFAULT usb-boot\n",
+                "LABEL usb-boot\n",
+                "    MENU LABEL Arch Linux\n",
+                "    LINUX /usb-boot/kernel\n",
+                "    INITRD /usb-boot/initramfs.img\n",
+                "    APPEND usbkexec.luks=UUID=1234 usbkexec.kernel=/boot/vmlinuz-linux usbkexec.initrd=/boot/initramfs-linux.img usbkexec.args='root=/dev/sda2 x=\"a  b\" y'\n",
+            ).to_string())),
+            (Loader::Grub, BootEntry { title: "Arch's", ..entry.clone() }, Ok(concat!(
+                "menuentry 'Arch'\\''s' {\n",
+                "    linux '/usb-boot/kernel' 'usbkexec.luks=UUID=1234' 'usbkexec.kernel=/boot/vmlinuz-linux' 'usbkexec.initrd=/boot/initramfs-linux.img' 'usbkexec.args=root=/dev/mapper/root' 'usbkexec.args=rw' 'usbkexec.args=quiet'\n",
+                "    initrd '/usb-boot/initramfs.img'\n",
+                "}\n",
+            ).to_string())),
+            (Loader::Grub, BootEntry { title: "usb", target_cmdline: r#"x="a b" quiet"#, ..entry.clone() }, Ok(concat!(
+                "menuentry 'usb' {\n",
+                "    linux '/usb-boot/kernel' 'usbkexec.luks=UUID=1234' 'usbkexec.kernel=/boot/vmlinuz-linux' 'usbkexec.initrd=/boot/initramfs-linux.img' 'usbkexec.args=base64:eD0iYSBiIg==' 'usbkexec.args=quiet'\n",
+                "    initrd '/usb-boot/initramfs.img'\n",
+                "}\n",
+            ).to_string())),
+            (Loader::Grub, BootEntry { cmdline: "usbkexec.luks='a b'", ..entry.clone() }, Err(BootloaderError::CannotPass {
+                loader: "GRUB",
+                value: "usbkexec.luks='a b'".to_string(),
+            }.to_string())),
+            (Loader::SystemdBoot, BootEntry { target_cmdline: "quiet\nrw", ..entry.clone() }, Err(BootloaderError::CannotPass {
+                loader: "systemd-boot",
+                value: "quiet\nrw".to_string(),
+            }.to_string())),
+            (Loader::SystemdBoot, BootEntry { cmdline: "usbkexec.kernel=/other", ..entry.clone() }, Err(
+                "the runner would reject the command line \"usbkexec.kernel=/other usbkexec.kernel=/boot/vmlinuz-linux usbkexec.initrd=/boot/initramfs-linux.img usbkexec.args=\"root=/dev/mapper/root rw quiet\"\": required parameter set multiple times: usbkexec.kernel".to_string(),
+            )),
+        ];
+        for (loader, entry, expected) in test_cases {
+            assert_eq!(generate(loader, &entry, &Inheritance::default()).map(|(config, _)| config).map_err(|e| e.to_string()), expected);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Command lines with nested quotes and encoded values survive every boot loader.
+        let target_cmdlines = [
+            "",
+            "quiet",
+            r#"x="a b" e"#,
+            "'c d' e",
+            "tee=4 sasd=1 83      dfds 983=5=das",
+            "'hello goodbye c32=gfda'",
+            r#"acpi_osi="!Windows 2020" x='a "b"'"#,
+            r"path=C:\\boot",
+            "hex:00",
+        ];
+        for target_cmdline in target_cmdlines {
+            for loader in [Loader::SystemdBoot, Loader::Syslinux, Loader::Grub] {
+                let entry = BootEntry {
+                    kernel: "/kernel",
+                    initrd: "/initrd",
+                    target_kernel: "/vmlinuz",
+                    target_initrd: "/initramfs",
+                    target_cmdline,
+                    ..Default::default()
+                };
+                assert!(generate(loader, &entry, &Inheritance::default()).is_ok(), "{:?} {:?}", loader, target_cmdline);
+            }
+        }
+
+        // The parameters of the first stage are passed on as the runner is told to.
+        let entry = BootEntry {
+            kernel: "/kernel",
+            initrd: "/initrd",
+            cmdline: "usbkexec.luks=UUID=1234 console=ttyS0",
+            target_kernel: "/vmlinuz",
+            target_initrd: "/initramfs",
+            target_cmdline: "quiet",
+            ..Default::default()
+        };
+        let inheritances = ["all", "none", "allow:usbkexec.*", "deny:console"].map(|x| Inheritance::parse(x).unwrap());
+        for inheritance in inheritances {
+            for loader in [Loader::SystemdBoot, Loader::Syslinux, Loader::Grub] {
+                assert!(generate(loader, &entry, &inheritance).is_ok(), "{:?} {:?}", loader, inheritance);
+            }
+        }
+
+        // An unpaired quote would swallow the parameters the runner appends.
+        let entry = BootEntry {
+            kernel: "/kernel",
+            initrd: "/initrd",
+            target_kernel: "/vmlinuz",
+            target_initrd: "/initramfs",
+            target_cmdline: r#"unpaired="quote"#,
+            ..Default::default()
+        };
+        let error = generate(Loader::SystemdBoot, &entry, &Inheritance::default()).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&BootloaderError::RoundTrip {
+            expected: r#""/vmlinuz" with the initrd "/initramfs" and the command line "unpaired="quote""#.to_string(),
+            got: r#""/vmlinuz" with the initrd "/initramfs" and the command line "unpaired="quote ""#.to_string(),
+        }));
+
+        // Quotes nested in the same kind of quotes end the quoted value early.
+        let entry = BootEntry { target_kernel: "/vmlinuz", target_initrd: "/initramfs", target_cmdline: r#"x="a b""#, ..Default::default() };
+        let command_line = r#"usbkexec.kernel=/vmlinuz usbkexec.initrd=/initramfs usbkexec.args="x="a b"""#;
+        let error = check_round_trip(Loader::SystemdBoot, &entry, command_line, &Inheritance::default()).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&BootloaderError::RoundTrip {
+            expected: r#""/vmlinuz" with the initrd "/initramfs" and the command line "x="a b"""#.to_string(),
+            got: r#""/vmlinuz" with the initrd "/initramfs" and the command line ""x="a b""""#.to_string(),
+        }));
+        assert_eq!(Loader::parse("lilo"), Err(BootloaderError::UnknownLoader { value: "lilo".to_string() }));
+    }
+}
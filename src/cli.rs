@@ -0,0 +1,206 @@
+use common::AggregateError;
+
+/// Represents an error that occurred while executing the [`parse`] function.
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum CliError {
+    /// An option that takes a value was given as the last argument, without a value after it.
+    #[error("the option \"{key}\" was given but no value was provided for it")]
+    KeyWithoutValue {
+        key: String,
+    },
+    /// A flag (an option that does not take a value) was given a value in the form `flag=value`.
+    #[error("the flag \"{flag}\" does not take a value")]
+    FlagWithValue {
+        flag: String,
+    },
+    /// An argument starting with `-` did not match any of the known flags or options.
+    #[error("unknown argument: {argument}")]
+    UnknownArgument {
+        argument: String,
+    },
+    /// An option that may only be given once was given multiple times.
+    #[error("the option \"{option}\" was set multiple times")]
+    OptionSetMultipleTimes {
+        option: String,
+    },
+    /// A required option was not given.
+    #[error("the required option \"{option}\" was not provided")]
+    MissingRequiredOption {
+        option: String,
+    },
+    /// The number of positional arguments was not in the accepted range.
+    #[error("expected {expected} positional arguments, but got {got}")]
+    WrongNumberOfPositionals {
+        expected: String,
+        got: usize,
+    },
+}
+
+/// Describes the arguments a subcommand accepts.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ArgSpec<'a> {
+    /// Options that do not take a value, e.g. `--force`.
+    pub flags: &'a [&'a str],
+    /// Options that take exactly one value and may be given at most once.
+    /// Each option can be given in the form "key=value" (1 argument) or "key value" (2 arguments).
+    pub options: &'a [&'a str],
+    /// Options that take a value and may be given any number of times.
+    pub repeated_options: &'a [&'a str],
+    /// Options from [`options`](ArgSpec::options) that must be given.
+    pub required: &'a [&'a str],
+    /// The minimum and maximum number of positional arguments accepted (inclusive).
+    pub positionals: (usize, usize),
+}
+
+/// The result of parsing a subcommand's arguments with [`parse`].
+#[derive(Debug, PartialEq, Default)]
+pub struct ParsedArgs {
+    flags: Vec<String>,
+    options: Vec<(String, String)>,
+    positionals: Vec<String>,
+}
+impl ParsedArgs {
+    /// Returns true if the flag was given.
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|x| x == name)
+    }
+    /// Returns the value of a single option, or None if it was not given.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+    /// Returns the values of an option in the order they were given.
+    pub fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item=&'a str> + 'a {
+        self.options.iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+    /// Returns the positional arguments in the order they were given.
+    pub fn positionals(&self) -> &[String] {
+        &self.positionals
+    }
+}
+
+/// Parses the arguments of a subcommand according to `spec`.
+/// Every argument starting with `-` must be one of the flags or options in `spec`.
+/// The argument `--` ends option parsing; every argument after it is positional.
+///
+/// # Errors:
+///   All problems with the arguments are collected, and returned together in an [`AggregateError`].
+pub fn parse(args: impl IntoIterator<Item=String>, spec: ArgSpec) -> Result<ParsedArgs, AggregateError<CliError>> {
+    let mut parsed = ParsedArgs::default();
+    let mut errors = Vec::new();
+
+    let mut only_positionals = false;
+    let mut args = args.into_iter();
+    'args_loop: while let Some(arg) = args.next() {
+        if only_positionals || !arg.starts_with('-') || arg == "-" {
+            parsed.positionals.push(arg);
+            continue;
+        }
+        if arg == "--" {
+            only_positionals = true;
+            continue;
+        }
+
+        let (key, inline_value) = match arg.split_once('=') {
+            Some((key, value)) => (key, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+
+        if spec.flags.contains(&key) {
+            if inline_value.is_some() {
+                errors.push(CliError::FlagWithValue { flag: key.to_string() });
+            }
+            parsed.flags.push(key.to_string());
+            continue;
+        }
+
+        let is_single = spec.options.contains(&key);
+        if !is_single && !spec.repeated_options.contains(&key) {
+            errors.push(CliError::UnknownArgument { argument: arg.clone() });
+            continue;
+        }
+        let value = match inline_value {
+            Some(x) => x,
+            None => match args.next() {
+                Some(x) => x,
+                None => {
+                    errors.push(CliError::KeyWithoutValue { key: key.to_string() });
+                    break 'args_loop;
+                },
+            },
+        };
+        if is_single && parsed.value(key).is_some() {
+            errors.push(CliError::OptionSetMultipleTimes { option: key.to_string() });
+        }
+        parsed.options.push((key.to_string(), value));
+    }
+
+    for option in spec.required {
+        if parsed.value(option).is_none() {
+            errors.push(CliError::MissingRequiredOption { option: option.to_string() });
+        }
+    }
+    let (min, max) = spec.positionals;
+    if parsed.positionals.len() < min || parsed.positionals.len() > max {
+        errors.push(CliError::WrongNumberOfPositionals {
+            expected: if min == max { min.to_string() } else { format!("{}-{}", min, max) },
+            got: parsed.positionals.len(),
+        });
+    }
+
+    if let Ok(aggregate) = AggregateError::try_from(errors) {
+        return Err(aggregate);
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::size_based_container::SizeBasedContainer;
+
+    const SPEC: ArgSpec = ArgSpec {
+        flags: &["--force"],
+        options: &["--destdir", "--prefix"],
+        repeated_options: &["--overlay"],
+        required: &["--prefix"],
+        positionals: (0, 1),
+    };
+
+    fn parse_str(command_line: &str) -> Result<ParsedArgs, AggregateError<CliError>> {
+        parse(command_line.split_whitespace().map(|x| x.to_string()), SPEC)
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = parse_str("--prefix /usr --force --overlay a:b --overlay=c:d --destdir=/tmp/pkg positional").unwrap();
+        assert!(parsed.flag("--force"));
+        assert_eq!(parsed.value("--prefix"), Some("/usr"));
+        assert_eq!(parsed.value("--destdir"), Some("/tmp/pkg"));
+        assert_eq!(parsed.values("--overlay").collect::<Vec<_>>(), ["a:b", "c:d"]);
+        assert_eq!(parsed.positionals(), ["positional"]);
+
+        let parsed = parse_str("--prefix=/usr -- --force").unwrap();
+        assert!(!parsed.flag("--force"));
+        assert_eq!(parsed.positionals(), ["--force"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let single = |error| Err(SizeBasedContainer::from_single(error).try_into().unwrap());
+        let test_cases = [
+            ("--prefix=/usr --destdir", single(CliError::KeyWithoutValue { key: "--destdir".to_string() })),
+            ("--prefix=/usr --force=yes", single(CliError::FlagWithValue { flag: "--force".to_string() })),
+            ("--prefix=/usr --lol", single(CliError::UnknownArgument { argument: "--lol".to_string() })),
+            ("--prefix=/usr --prefix /usr/local", single(CliError::OptionSetMultipleTimes { option: "--prefix".to_string() })),
+            ("--force", single(CliError::MissingRequiredOption { option: "--prefix".to_string() })),
+            ("--prefix=/usr a b", single(CliError::WrongNumberOfPositionals { expected: "0-1".to_string(), got: 2 })),
+        ];
+        for (command_line, expected) in test_cases {
+            assert_eq!(parse_str(command_line), expected);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::{fs::{self, File}, io, os::unix::fs::FileExt};
+
+use crate::{pe, template::{SETUP_HEADER_MAGIC, SETUP_HEADER_MAGIC_OFFSET}, utils};
+
+/// The longest command line the kernel takes, without the terminating NUL, if the kernel image
+/// does not say. This is `COMMAND_LINE_SIZE - 1` of the architecture, or None for architectures
+/// where it is not known, like s390x, whose limit is set when the kernel is built or booted.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc", target_arch = "powerpc64"))]
+pub const DEFAULT_COMMAND_LINE_SIZE: Option<usize> = Some(2047);
+#[cfg(any(target_arch = "arm", target_arch = "riscv64"))]
+pub const DEFAULT_COMMAND_LINE_SIZE: Option<usize> = Some(1023);
+#[cfg(not(any(
+    target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc", target_arch = "powerpc64",
+    target_arch = "arm", target_arch = "riscv64",
+)))]
+pub const DEFAULT_COMMAND_LINE_SIZE: Option<usize> = None;
+
+/// Where the boot protocol header of x86 kernels stores its version and `cmdline_size`, which
+/// it has since version 2.06.
+const SETUP_HEADER_VERSION_OFFSET: usize = 0x206;
+const CMDLINE_SIZE_OFFSET: usize = 0x238;
+const CMDLINE_SIZE_MIN_VERSION: u16 = 0x206;
+
+/// Represents a command line that the kernel would not take as it is.
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum CommandLineError {
+    #[error("the command line is {length} bytes long, but the kernel takes at most {limit}")]
+    TooLong {
+        length: usize,
+        limit: usize,
+    },
+    #[error("the command line has an unbalanced double quote")]
+    UnbalancedQuotes,
+    #[error("the command line contains a NUL byte")]
+    Nul,
+    #[error("the command line contains a newline")]
+    Newline,
+}
+
+/// Reads the `cmdline_size` from the boot protocol header of an x86 kernel image.
+pub fn command_line_size(data: &[u8]) -> Option<usize> {
+    if data.get(SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4) != Some(SETUP_HEADER_MAGIC)
+        || pe::read_u16(data, SETUP_HEADER_VERSION_OFFSET)? < CMDLINE_SIZE_MIN_VERSION {
+        return None;
+    }
+    Some(pe::read_u32(data, CMDLINE_SIZE_OFFSET)? as usize)
+}
+
+/// Returns the longest command line `kernel` takes: its `cmdline_size`, or
+/// [`DEFAULT_COMMAND_LINE_SIZE`] if it has none. None if the limit is not known.
+pub fn command_line_limit(kernel: &File) -> io::Result<Option<usize>> {
+    let mut header = vec![0; CMDLINE_SIZE_OFFSET + 4];
+    let length = kernel.read_at(&mut header, 0)?;
+    header.truncate(length);
+    Ok(command_line_size(&header).or(DEFAULT_COMMAND_LINE_SIZE))
+}
+
+/// Checks that the kernel would take `command_line` as it is: that it is at most `limit` bytes
+/// long, if the limit is known, and has no NUL, newline or unbalanced double quote. Only double
+/// quotes are special to the kernel, an unbalanced one makes it take the rest of the command line
+/// as one parameter.
+pub fn validate(command_line: &str, limit: Option<usize>) -> Result<(), CommandLineError> {
+    if command_line.contains('\0') {
+        return Err(CommandLineError::Nul);
+    }
+    if command_line.contains(['\n', '\r']) {
+        return Err(CommandLineError::Newline);
+    }
+    if !command_line.matches('"').count().is_multiple_of(2) {
+        return Err(CommandLineError::UnbalancedQuotes);
+    }
+    match limit {
+        Some(limit) if command_line.len() > limit => Err(CommandLineError::TooLong { length: command_line.len(), limit }),
+        _ => Ok(()),
+    }
+}
+
+/// A parsed kernel command line.
+/// Parameters are split at unquoted spaces, in the same way the kernel splits them.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct KernelCommandLine {
+    parameters: Vec<String>,
+}
+impl KernelCommandLine {
+    pub fn parse(command_line: &str) -> KernelCommandLine {
+        KernelCommandLine {
+            parameters: utils::split_at_unquoted_spaces(command_line.trim_end_matches('\n'))
+                .map(|x| x.to_string())
+                .collect(),
+        }
+    }
+
+    /// Reads the command line of the running kernel from /proc/cmdline.
+    pub fn read() -> io::Result<KernelCommandLine> {
+        Ok(KernelCommandLine::parse(&fs::read_to_string("/proc/cmdline")?))
+    }
+
+    /// Returns every parameter, in order.
+    pub fn parameters(&self) -> impl Iterator<Item=&str> {
+        self.parameters.iter().map(|x| x.as_str())
+    }
+
+    /// Returns the value of the last parameter in the form "key=value" with the given key,
+    /// like the kernel does when a parameter is given multiple times.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.parameters.iter()
+            .rev()
+            .find_map(|x| match x.split_once('=') {
+                Some((k, value)) if k == key => Some(value),
+                _ => None,
+            })
+    }
+
+    /// Returns the values of every parameter in the form "key=value" with the given key, in order.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item=&'a str> + 'a {
+        self.parameters()
+            .filter_map(move |x| match x.split_once('=') {
+                Some((k, value)) if k == key => Some(value),
+                _ => None,
+            })
+    }
+
+    /// Returns true if a parameter with exactly the given name, without a value, is present.
+    pub fn has_flag(&self, name: &str) -> bool {
+        self.parameters().any(|x| x == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_command_line() {
+        let command_line = KernelCommandLine::parse("BOOT_IMAGE=/vmlinuz initrd=\\a.img initrd=\\b.img quiet add=\"x=1 y\" add=2\n");
+        assert_eq!(command_line.get("BOOT_IMAGE"), Some("/vmlinuz"));
+        assert_eq!(command_line.get_all("initrd").collect::<Vec<_>>(), ["\\a.img", "\\b.img"]);
+        assert_eq!(command_line.get("add"), Some("2"));
+        assert_eq!(command_line.get_all("add").next(), Some("\"x=1 y\""));
+        assert_eq!(command_line.get("quiet"), None);
+        assert!(command_line.has_flag("quiet"));
+        assert!(!command_line.has_flag("BOOT_IMAGE"));
+        assert_eq!(command_line.parameters().count(), 6);
+    }
+
+    #[test]
+    fn test_validate() {
+        let test_cases = [
+            (r#"root=/dev/sda2 acpi_osi="!Windows 2020" quiet"#, Some(45), Ok(())),
+            ("root=/dev/sda2 quiet", Some(19), Err(CommandLineError::TooLong { length: 20, limit: 19 })),
+            ("root=/dev/sda2 quiet", None, Ok(())),
+            (r#"x='a b' y="c d"#, Some(100), Err(CommandLineError::UnbalancedQuotes)),
+            ("quiet\n", None, Err(CommandLineError::Newline)),
+            ("quiet\0rw", Some(100), Err(CommandLineError::Nul)),
+        ];
+        for (command_line, limit, expected) in test_cases {
+            assert_eq!(validate(command_line, limit), expected, "{}", command_line);
+        }
+    }
+
+    #[test]
+    fn test_command_line_size() {
+        let mut bzimage = vec![0; 0x240];
+        bzimage[SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4].copy_from_slice(SETUP_HEADER_MAGIC);
+        bzimage[CMDLINE_SIZE_OFFSET..CMDLINE_SIZE_OFFSET + 4].copy_from_slice(&2047u32.to_le_bytes());
+        assert_eq!(command_line_size(&bzimage), None);
+        bzimage[SETUP_HEADER_VERSION_OFFSET..SETUP_HEADER_VERSION_OFFSET + 2].copy_from_slice(&0x20fu16.to_le_bytes());
+        assert_eq!(command_line_size(&bzimage), Some(2047));
+        assert_eq!(command_line_size(&bzimage[..0x230]), None);
+        assert_eq!(command_line_size(b"MZ"), None);
+    }
+}
@@ -0,0 +1,802 @@
+//! Reading and writing of initramfs images without external tools.
+//!
+//! An initramfs image is a sequence of cpio archives in the "newc" format (or its "crc" variant),
+//! each of which may be compressed, separated by zero padding. This is how early microcode is
+//! prepended to an initramfs, and how [`crate::initramfs_kexec_runner`] can append files to one.
+//! [`Reader`] reads a single uncompressed segment, [`InitramfsReader`] reads a whole image the
+//! way the kernel does, and [`Writer`] writes an archive, optionally through an [`Encoder`].
+//! Everything is streaming, so images are never loaded into memory as a whole.
+
+use std::{
+    ffi::OsStr,
+    fmt,
+    io::{self, BufRead, BufReader, Cursor, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Magic of the "newc" format.
+pub const NEWC_MAGIC: &[u8; 6] = b"070701";
+/// Magic of the "crc" format, which is "newc" with a checksum of the data of every file.
+pub const CRC_MAGIC: &[u8; 6] = b"070702";
+/// Name of the entry that ends an archive.
+pub const TRAILER_NAME: &str = "TRAILER!!!";
+const HEADER_LENGTH: u64 = 110;
+/// The longest name, including the terminating NUL, that is read, like `PATH_MAX` of the kernel.
+const MAX_NAME_SIZE: u32 = 4096;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFIFO: u32 = 0o010000;
+pub const S_IFSOCK: u32 = 0o140000;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CpioError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("offset {offset}: not a newc or crc cpio header")]
+    BadMagic {
+        offset: u64,
+    },
+    #[error("offset {offset}: invalid {field} in cpio header")]
+    InvalidHeader {
+        offset: u64,
+        field: &'static str,
+    },
+    #[error("{}: checksum does not match data", .name.display())]
+    ChecksumMismatch {
+        name: PathBuf,
+    },
+    #[error("{}: archive ended inside the entry", .name.display())]
+    Truncated {
+        name: PathBuf,
+    },
+    #[error("archive ended without a trailer")]
+    MissingTrailer,
+    #[error("unrecognized data after the end of a cpio archive")]
+    UnrecognizedData,
+    #[error("junk after the end of a cpio archive inside {0} compressed data")]
+    JunkInCompressedData(Compression),
+    /// The data is compressed in a format the kernel supports, but this program does not.
+    #[error("{0} compressed data is not supported")]
+    UnsupportedCompression(&'static str),
+}
+impl From<CpioError> for io::Error {
+    fn from(error: CpioError) -> io::Error {
+        match error {
+            CpioError::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+/// The header of an entry in a cpio archive.
+/// The data of an entry is [`Entry::size`] bytes long; for symbolic links, it is the target.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Entry {
+    pub name: PathBuf,
+    pub ino: u32,
+    /// File type and permissions, as in `st_mode`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub mtime: u32,
+    pub size: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    /// The sum of every byte of the data, for the crc format. None for the newc format.
+    pub checksum: Option<u32>,
+}
+impl Entry {
+    /// A regular file owned by root, with `size` bytes of data.
+    pub fn file(name: impl Into<PathBuf>, permissions: u32, size: u32) -> Entry {
+        Entry { name: name.into(), mode: S_IFREG | permissions, nlink: 1, size, ..Default::default() }
+    }
+
+    /// A directory owned by root.
+    pub fn directory(name: impl Into<PathBuf>, permissions: u32) -> Entry {
+        Entry { name: name.into(), mode: S_IFDIR | permissions, nlink: 2, ..Default::default() }
+    }
+
+    /// A symbolic link owned by root. Its data must be the target.
+    pub fn symlink(name: impl Into<PathBuf>, target: &Path) -> Entry {
+        Entry {
+            name: name.into(),
+            mode: S_IFLNK | 0o777,
+            nlink: 1,
+            size: target.as_os_str().len() as u32,
+            ..Default::default()
+        }
+    }
+
+    /// The file type bits of the mode, e.g. [`S_IFREG`].
+    pub fn file_type(&self) -> u32 {
+        self.mode & S_IFMT
+    }
+
+    fn is_trailer(&self) -> bool {
+        self.name == Path::new(TRAILER_NAME)
+    }
+}
+
+/// The number of zero bytes needed to align `offset` to 4 bytes.
+fn padding(offset: u64) -> u64 {
+    (4 - offset % 4) % 4
+}
+
+fn checksum(data: &[u8], initial: u32) -> u32 {
+    data.iter().fold(initial, |sum, x| sum.wrapping_add(*x as u32))
+}
+
+/// Reads the entries of an uncompressed cpio segment, which may consist of several archives
+/// separated by zero padding.
+/// The reader stops without consuming anything at data that is not a cpio header following the
+/// end of an archive, so the rest of the input can be read with [`Reader::into_inner`].
+/// The data of the current entry is read through the [`Read`] implementation.
+pub struct Reader<R: BufRead> {
+    inner: R,
+    /// Bytes consumed from `inner`, for alignment and error messages.
+    offset: u64,
+    current_name: PathBuf,
+    /// Bytes of data of the current entry that have not been read yet.
+    data_remaining: u64,
+    /// The expected checksum and the sum so far, if the current entry is in the crc format.
+    checksum: Option<(u32, u32)>,
+    after_trailer: bool,
+    finished: bool,
+}
+impl<R: BufRead> Reader<R> {
+    pub fn new(inner: R) -> Reader<R> {
+        Reader {
+            inner,
+            offset: 0,
+            current_name: PathBuf::new(),
+            data_remaining: 0,
+            checksum: None,
+            after_trailer: false,
+            finished: false,
+        }
+    }
+
+    /// Returns the header of the next entry, skipping the unread data of the current one.
+    /// Returns None at the end of the input, or at data after the end of an archive that is not
+    /// another archive.
+    pub fn next_entry(&mut self) -> Result<Option<Entry>, CpioError> {
+        io::copy(self, &mut io::sink())?;
+        self.skip(padding(self.offset))?;
+        while !self.finished {
+            let buffer = self.inner.fill_buf()?;
+            match buffer.first() {
+                None => {
+                    self.finished = true;
+                    if !self.after_trailer && self.offset != 0 {
+                        return Err(CpioError::MissingTrailer);
+                    }
+                },
+                Some(0) if self.after_trailer => {
+                    let zeros = buffer.iter().take_while(|x| **x == 0).count();
+                    self.inner.consume(zeros);
+                    self.offset += zeros as u64;
+                },
+                Some(b'0') => {
+                    let entry = self.read_header()?;
+                    if entry.is_trailer() {
+                        self.after_trailer = true;
+                        self.skip(entry.size as u64)?;
+                        self.skip(padding(self.offset))?;
+                        continue;
+                    }
+                    self.after_trailer = false;
+                    return Ok(Some(entry));
+                },
+                Some(_) if self.after_trailer => self.finished = true,
+                Some(_) => return Err(CpioError::BadMagic { offset: self.offset }),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the input, positioned after the last archive that was read.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn skip(&mut self, length: u64) -> Result<(), CpioError> {
+        let skipped = io::copy(&mut (&mut self.inner).take(length), &mut io::sink())?;
+        self.offset += skipped;
+        if skipped != length {
+            return Err(CpioError::Truncated { name: self.current_name.clone() });
+        }
+        Ok(())
+    }
+
+    fn read_exact_or_truncated(&mut self, buffer: &mut [u8]) -> Result<(), CpioError> {
+        match self.inner.read_exact(buffer) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(CpioError::Truncated { name: self.current_name.clone() })
+            },
+            result => {
+                self.offset += buffer.len() as u64;
+                Ok(result?)
+            },
+        }
+    }
+
+    fn read_header(&mut self) -> Result<Entry, CpioError> {
+        let header_offset = self.offset;
+        let mut header = [0u8; HEADER_LENGTH as usize];
+        self.read_exact_or_truncated(&mut header)?;
+        let is_crc = match &header[..6] {
+            magic if magic == NEWC_MAGIC => false,
+            magic if magic == CRC_MAGIC => true,
+            _ => return Err(CpioError::BadMagic { offset: header_offset }),
+        };
+
+        const FIELDS: [&str; 13] = [
+            "ino", "mode", "uid", "gid", "nlink", "mtime", "filesize",
+            "devmajor", "devminor", "rdevmajor", "rdevminor", "namesize", "check",
+        ];
+        let mut values = [0u32; 13];
+        for (i, field) in FIELDS.iter().enumerate() {
+            let hex = std::str::from_utf8(&header[6 + 8 * i..14 + 8 * i]).ok();
+            values[i] = hex.and_then(|x| u32::from_str_radix(x, 16).ok())
+                .ok_or(CpioError::InvalidHeader { offset: header_offset, field })?;
+        }
+        let [ino, mode, uid, gid, nlink, mtime, size, dev_major, dev_minor, rdev_major, rdev_minor, name_size, check] = values;
+
+        // The size is not trusted before anything is allocated for it.
+        if name_size == 0 || name_size > MAX_NAME_SIZE {
+            return Err(CpioError::InvalidHeader { offset: header_offset, field: "namesize" });
+        }
+        let mut name = vec![0u8; name_size as usize];
+        self.read_exact_or_truncated(&mut name)?;
+        if name.pop() != Some(0) {
+            return Err(CpioError::InvalidHeader { offset: header_offset, field: "name" });
+        }
+        self.skip(padding(self.offset))?;
+
+        let entry = Entry {
+            name: PathBuf::from(OsStr::from_bytes(&name)),
+            ino, mode, uid, gid, nlink, mtime, size, dev_major, dev_minor, rdev_major, rdev_minor,
+            checksum: is_crc.then_some(check),
+        };
+        self.current_name = entry.name.clone();
+        self.data_remaining = size as u64;
+        self.checksum = entry.checksum.map(|x| (x, 0));
+        Ok(entry)
+    }
+}
+/// Reads the data of the current entry.
+/// Fails with [`io::ErrorKind::InvalidData`] at the end of the data if a crc checksum does not match.
+impl<R: BufRead> Read for Reader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.data_remaining == 0 {
+            return Ok(0);
+        }
+        let length = buffer.len().min(self.data_remaining.try_into().unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buffer[..length])?;
+        if read == 0 {
+            return Err(CpioError::Truncated { name: self.current_name.clone() }.into());
+        }
+        self.offset += read as u64;
+        self.data_remaining -= read as u64;
+        if let Some((expected, sum)) = &mut self.checksum {
+            *sum = checksum(&buffer[..read], *sum);
+            if self.data_remaining == 0 && *sum != *expected {
+                return Err(CpioError::ChecksumMismatch { name: self.current_name.clone() }.into());
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// A compression format of a segment of an initramfs image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Lzma,
+    Zstd,
+}
+impl Compression {
+    /// Detects the compression of data from its first bytes.
+    /// Returns None if the data is neither a cpio archive nor in a supported compression format.
+    pub fn detect(magic: &[u8]) -> Option<Compression> {
+        const MAGICS: [(&[u8], Compression); 5] = [
+            (b"0707", Compression::None),
+            (&[0x1f, 0x8b], Compression::Gzip),
+            (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], Compression::Xz),
+            (&[0x5d, 0x00, 0x00], Compression::Lzma),
+            (&[0x28, 0xb5, 0x2f, 0xfd], Compression::Zstd),
+        ];
+        MAGICS.iter()
+            .find(|(x, _)| magic.starts_with(x))
+            .map(|(_, compression)| *compression)
+    }
+
+    /// Detects the compression formats the kernel can unpack an initramfs from, but
+    /// [`Compression`] does not support, and returns their name.
+    pub fn detect_unsupported(magic: &[u8]) -> Option<&'static str> {
+        const MAGICS: [(&[u8], &str); 4] = [
+            (&[0x02, 0x21, 0x4c, 0x18], "lz4"),
+            (&[0x04, 0x22, 0x4d, 0x18], "lz4"),
+            (b"BZh", "bzip2"),
+            (&[0x89, b'L', b'Z', b'O', 0x00, 0x0d], "lzop"),
+        ];
+        MAGICS.iter()
+            .find(|(x, _)| magic.starts_with(x))
+            .map(|(_, name)| *name)
+    }
+}
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "uncompressed",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Lzma => "lzma",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+type Input<'a> = Box<dyn BufRead + 'a>;
+
+/// A decompressor that stops at the end of its compressed stream, so the input after it can be
+/// taken back.
+enum Decoder<'a> {
+    Gzip(flate2::bufread::GzDecoder<Input<'a>>),
+    Xz(XzDecoder<Input<'a>>),
+    Zstd(zstd::stream::read::Decoder<'static, Input<'a>>),
+}
+impl<'a> Decoder<'a> {
+    fn new(input: Input<'a>, compression: Compression) -> io::Result<Decoder<'a>> {
+        Ok(match compression {
+            Compression::None => unreachable!("uncompressed data does not need a decoder"),
+            Compression::Gzip => Decoder::Gzip(flate2::bufread::GzDecoder::new(input)),
+            Compression::Xz => Decoder::Xz(XzDecoder {
+                input,
+                stream: xz2::stream::Stream::new_stream_decoder(u64::MAX, 0)?,
+                finished: false,
+            }),
+            Compression::Lzma => Decoder::Xz(XzDecoder {
+                input,
+                stream: xz2::stream::Stream::new_lzma_decoder(u64::MAX)?,
+                finished: false,
+            }),
+            Compression::Zstd => Decoder::Zstd(zstd::stream::read::Decoder::with_buffer(input)?.single_frame()),
+        })
+    }
+
+    fn into_inner(self) -> Input<'a> {
+        match self {
+            Decoder::Gzip(x) => x.into_inner(),
+            Decoder::Xz(x) => x.input,
+            Decoder::Zstd(x) => x.finish(),
+        }
+    }
+}
+impl Read for Decoder<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Gzip(x) => x.read(buffer),
+            Decoder::Xz(x) => x.read(buffer),
+            Decoder::Zstd(x) => x.read(buffer),
+        }
+    }
+}
+
+/// Returns a reader of the decompressed data of a single compressed stream, like a compressed
+/// kernel.
+pub fn decoder<'a>(input: impl BufRead + 'a, compression: Compression) -> io::Result<Box<dyn Read + 'a>> {
+    match compression {
+        Compression::None => Ok(Box::new(input)),
+        _ => Ok(Box::new(Decoder::new(Box::new(input), compression)?)),
+    }
+}
+
+/// A decoder for xz and lzma data.
+/// Unlike `xz2::bufread::XzDecoder`, it ends at the end of the compressed stream even if more
+/// input follows.
+struct XzDecoder<R: BufRead> {
+    input: R,
+    stream: xz2::stream::Stream,
+    finished: bool,
+}
+impl<R: BufRead> Read for XzDecoder<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        use xz2::stream::{Action, Status};
+        while !self.finished && !buffer.is_empty() {
+            let input = self.input.fill_buf()?;
+            let action = if input.is_empty() { Action::Finish } else { Action::Run };
+            let (in_before, out_before) = (self.stream.total_in(), self.stream.total_out());
+            let status = self.stream.process(input, buffer, action)?;
+            let consumed = (self.stream.total_in() - in_before) as usize;
+            let read = (self.stream.total_out() - out_before) as usize;
+            self.input.consume(consumed);
+            self.finished = status == Status::StreamEnd;
+            if read > 0 {
+                return Ok(read);
+            }
+            if consumed == 0 && !self.finished {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "xz data ended early"));
+            }
+        }
+        Ok(0)
+    }
+}
+
+enum Segment<'a> {
+    /// Input whose compression has not been detected yet.
+    Start(Input<'a>),
+    Plain(Reader<Input<'a>>),
+    Compressed(Compression, Box<Reader<BufReader<Decoder<'a>>>>),
+}
+
+/// Reads every entry of an initramfs image, across concatenated and compressed segments, in the
+/// order the kernel unpacks them.
+/// The data of the current entry is read through the [`Read`] implementation.
+pub struct InitramfsReader<'a> {
+    segment: Option<Segment<'a>>,
+}
+impl<'a> InitramfsReader<'a> {
+    pub fn new(input: impl Read + 'a) -> InitramfsReader<'a> {
+        InitramfsReader {
+            segment: Some(Segment::Start(Box::new(BufReader::new(input)))),
+        }
+    }
+
+    /// The compression of the segment the current entry is in.
+    pub fn compression(&self) -> Option<Compression> {
+        match &self.segment {
+            Some(Segment::Start(_)) | None => None,
+            Some(Segment::Plain(_)) => Some(Compression::None),
+            Some(Segment::Compressed(compression, _)) => Some(*compression),
+        }
+    }
+
+    /// Returns the header of the next entry, skipping the unread data of the current one.
+    /// Returns None at the end of the image.
+    pub fn next_entry(&mut self) -> Result<Option<Entry>, CpioError> {
+        loop {
+            let input = match self.segment.take() {
+                None => return Ok(None),
+                Some(Segment::Start(input)) => input,
+                Some(Segment::Plain(mut reader)) => match reader.next_entry()? {
+                    Some(entry) => {
+                        self.segment = Some(Segment::Plain(reader));
+                        return Ok(Some(entry));
+                    },
+                    None => reader.into_inner(),
+                },
+                Some(Segment::Compressed(compression, mut reader)) => match reader.next_entry()? {
+                    Some(entry) => {
+                        self.segment = Some(Segment::Compressed(compression, reader));
+                        return Ok(Some(entry));
+                    },
+                    None => {
+                        let mut decompressed = reader.into_inner();
+                        if !decompressed.fill_buf()?.is_empty() {
+                            return Err(CpioError::JunkInCompressedData(compression));
+                        }
+                        decompressed.into_inner().into_inner()
+                    },
+                },
+            };
+            self.segment = next_segment(input)?;
+        }
+    }
+}
+impl Read for InitramfsReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match &mut self.segment {
+            Some(Segment::Plain(reader)) => reader.read(buffer),
+            Some(Segment::Compressed(_, reader)) => reader.read(buffer),
+            Some(Segment::Start(_)) | None => Ok(0),
+        }
+    }
+}
+
+/// Skips zero padding and starts reading the segment that follows, if any.
+fn next_segment(mut input: Input<'_>) -> Result<Option<Segment<'_>>, CpioError> {
+    loop {
+        let buffer = input.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+        let zeros = buffer.iter().take_while(|x| **x == 0).count();
+        if zeros == 0 {
+            break;
+        }
+        input.consume(zeros);
+    }
+
+    // The buffer may end in the middle of a magic, so read it and put it back in front.
+    let mut magic = Vec::with_capacity(6);
+    (&mut input).take(6).read_to_end(&mut magic)?;
+    let compression = match Compression::detect(&magic) {
+        Some(x) => x,
+        None => return Err(match Compression::detect_unsupported(&magic) {
+            Some(name) => CpioError::UnsupportedCompression(name),
+            None => CpioError::UnrecognizedData,
+        }),
+    };
+    let input: Input<'_> = Box::new(Cursor::new(magic).chain(input));
+    Ok(Some(match compression {
+        Compression::None => Segment::Plain(Reader::new(input)),
+        compression => Segment::Compressed(
+            compression,
+            Box::new(Reader::new(BufReader::new(Decoder::new(input, compression)?))),
+        ),
+    }))
+}
+
+/// A compressor for writing a segment of an initramfs image, in a form the kernel can unpack.
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+impl<W: Write> Encoder<W> {
+    pub fn new(output: W, compression: Compression) -> io::Result<Encoder<W>> {
+        use xz2::stream::{Check, LzmaOptions, Stream};
+        Ok(match compression {
+            Compression::None => Encoder::None(output),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(output, flate2::Compression::default())),
+            // The kernel only supports CRC32 checks in xz data.
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new_stream(output, Stream::new_easy_encoder(6, Check::Crc32)?)),
+            Compression::Lzma => Encoder::Xz(xz2::write::XzEncoder::new_stream(output, Stream::new_lzma_encoder(&LzmaOptions::new_preset(6)?)?)),
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(output, 0)?),
+        })
+    }
+
+    /// Ends the compressed stream and returns the output.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(x) => Ok(x),
+            Encoder::Gzip(x) => x.finish(),
+            Encoder::Xz(x) => x.finish(),
+            Encoder::Zstd(x) => x.finish(),
+        }
+    }
+}
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(x) => x.write(buffer),
+            Encoder::Gzip(x) => x.write(buffer),
+            Encoder::Xz(x) => x.write(buffer),
+            Encoder::Zstd(x) => x.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(x) => x.flush(),
+            Encoder::Gzip(x) => x.flush(),
+            Encoder::Xz(x) => x.flush(),
+            Encoder::Zstd(x) => x.flush(),
+        }
+    }
+}
+
+/// Writes a cpio archive in the newc format, or the crc format if created with
+/// [`Writer::with_checksums`].
+/// Entries with an inode number of 0 are given a new inode number.
+pub struct Writer<W: Write> {
+    inner: W,
+    offset: u64,
+    crc: bool,
+    next_ino: u32,
+}
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Writer<W> {
+        Writer { inner, offset: 0, crc: false, next_ino: 1 }
+    }
+
+    /// Creates a writer for the crc format, which the kernel verifies when unpacking.
+    pub fn with_checksums(inner: W) -> Writer<W> {
+        Writer { crc: true, ..Writer::new(inner) }
+    }
+
+    /// Writes an entry with data from a reader, which must provide at least `entry.size` bytes.
+    /// In the crc format, `entry.checksum` must be set; use [`Writer::write_entry`] to compute it.
+    pub fn write_entry_from_reader(&mut self, entry: &Entry, data: &mut dyn Read) -> io::Result<()> {
+        self.write_header(entry)?;
+        let copied = io::copy(&mut data.take(entry.size as u64), &mut self.inner)?;
+        self.offset += copied;
+        if copied != entry.size as u64 {
+            return Err(CpioError::Truncated { name: entry.name.clone() }.into());
+        }
+        self.write_padding()
+    }
+
+    /// Writes an entry with the given data. The size and checksum of `entry` are set from it.
+    /// The data is written directly, without intermediate copies, so it may be a secret.
+    pub fn write_entry(&mut self, entry: &Entry, data: &[u8]) -> io::Result<()> {
+        let entry = Entry {
+            size: data.len().try_into().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large for cpio"))?,
+            checksum: self.crc.then(|| checksum(data, 0)),
+            ..entry.clone()
+        };
+        self.write_header(&entry)?;
+        self.inner.write_all(data)?;
+        self.offset += data.len() as u64;
+        self.write_padding()
+    }
+
+    pub fn add_directory(&mut self, name: impl Into<PathBuf>, permissions: u32) -> io::Result<()> {
+        self.write_entry(&Entry::directory(name, permissions), &[])
+    }
+
+    pub fn add_file(&mut self, name: impl Into<PathBuf>, permissions: u32, data: &[u8]) -> io::Result<()> {
+        self.write_entry(&Entry::file(name, permissions, 0), data)
+    }
+
+    pub fn add_symlink(&mut self, name: impl Into<PathBuf>, target: &Path) -> io::Result<()> {
+        self.write_entry(&Entry::symlink(name, target), target.as_os_str().as_bytes())
+    }
+
+    /// Writes the trailer that ends the archive, and returns the output.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_header(&Entry { name: TRAILER_NAME.into(), nlink: 1, ..Default::default() })?;
+        Ok(self.inner)
+    }
+
+    fn write_header(&mut self, entry: &Entry) -> io::Result<()> {
+        let (magic, check) = match (self.crc, entry.checksum) {
+            (false, _) => (NEWC_MAGIC, 0),
+            (true, Some(check)) => (CRC_MAGIC, check),
+            (true, None) if entry.size == 0 => (CRC_MAGIC, 0),
+            (true, None) => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: missing checksum", entry.name.display()),
+            )),
+        };
+        let ino = match entry.ino {
+            // The trailer has no inode number.
+            _ if entry.is_trailer() => 0,
+            0 => {
+                self.next_ino += 1;
+                self.next_ino - 1
+            },
+            ino => ino,
+        };
+        let name = entry.name.as_os_str().as_bytes();
+
+        let mut header = Vec::with_capacity(HEADER_LENGTH as usize + name.len() + 4);
+        header.extend_from_slice(magic);
+        for value in [
+            ino, entry.mode, entry.uid, entry.gid, entry.nlink, entry.mtime, entry.size,
+            entry.dev_major, entry.dev_minor, entry.rdev_major, entry.rdev_minor, name.len() as u32 + 1, check,
+        ] {
+            header.extend_from_slice(format!("{:08x}", value).as_bytes());
+        }
+        header.extend_from_slice(name);
+        header.push(0);
+        self.inner.write_all(&header)?;
+        self.offset += header.len() as u64;
+        self.write_padding()
+    }
+
+    fn write_padding(&mut self) -> io::Result<()> {
+        let padding = padding(self.offset);
+        self.inner.write_all(&[0; 4][..padding as usize])?;
+        self.offset += padding;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_archive(crc: bool, compression: Compression) -> Vec<u8> {
+        let encoder = Encoder::new(Vec::new(), compression).unwrap();
+        let mut writer = if crc { Writer::with_checksums(encoder) } else { Writer::new(encoder) };
+        writer.add_directory("usr", 0o755).unwrap();
+        writer.add_file("usr/init", 0o755, b"#!/bin/sh\n").unwrap();
+        writer.add_symlink("init", Path::new("usr/init")).unwrap();
+        writer.add_file(format!("{}.img", compression), 0o644, &[]).unwrap();
+        writer.finish().unwrap().finish().unwrap()
+    }
+
+    fn read_all(reader: &mut InitramfsReader) -> Result<Vec<(Entry, Vec<u8>)>, CpioError> {
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            entries.push((entry, data));
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for crc in [false, true] {
+            let archive = write_archive(crc, Compression::None);
+            assert_eq!(archive.len() % 4, 0);
+            let entries = read_all(&mut InitramfsReader::new(archive.as_slice())).unwrap();
+            let summary: Vec<_> = entries.iter()
+                .map(|(entry, data)| (entry.name.to_str().unwrap(), entry.file_type(), entry.mode & 0o7777, data.as_slice()))
+                .collect();
+            assert_eq!(summary, [
+                ("usr", S_IFDIR, 0o755, &b""[..]),
+                ("usr/init", S_IFREG, 0o755, b"#!/bin/sh\n"),
+                ("init", S_IFLNK, 0o777, b"usr/init"),
+                ("uncompressed.img", S_IFREG, 0o644, b""),
+            ]);
+            assert_eq!(entries[1].0.checksum, crc.then_some(b"#!/bin/sh\n".iter().map(|x| *x as u32).sum()));
+            assert_eq!(entries.iter().map(|x| x.0.ino).collect::<Vec<_>>(), [1, 2, 3, 4]);
+        }
+    }
+
+    #[test]
+    fn test_concatenated_and_compressed() {
+        let mut image = write_archive(false, Compression::None);
+        image.extend_from_slice(&[0; 512]);
+        for compression in [Compression::Gzip, Compression::Xz, Compression::Lzma, Compression::Zstd] {
+            image.extend(write_archive(true, compression));
+        }
+
+        let mut reader = InitramfsReader::new(image.as_slice());
+        let mut names = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            if entry.name.extension().is_some() {
+                assert_eq!(entry.name, Path::new(&format!("{}.img", reader.compression().unwrap())));
+            }
+            names.push(entry.name);
+        }
+        assert_eq!(names.len(), 20);
+    }
+
+    #[test]
+    fn test_invalid_archives() {
+        let archive = write_archive(true, Compression::None);
+        let mut corrupted = archive.clone();
+        let data_offset = corrupted.windows(9).position(|x| x == b"#!/bin/sh").unwrap();
+        corrupted[data_offset] = b'?';
+        let mut junk = archive.clone();
+        junk.extend_from_slice(b"junk");
+        let mut compressed_junk = Encoder::new(Vec::new(), Compression::Gzip).unwrap();
+        compressed_junk.write_all(&archive).unwrap();
+        compressed_junk.write_all(b"junk").unwrap();
+        let compressed_junk = compressed_junk.finish().unwrap();
+
+        let bad_magic = [&b"070703"[..], &[b'0'; 120]].concat();
+        let trailer_offset = archive.windows(10).position(|x| x == TRAILER_NAME.as_bytes()).unwrap() - 110;
+        let mut long_name = archive.clone();
+        long_name[94..102].copy_from_slice(b"FFFFFFFF");
+
+        let mut lz4 = archive.clone();
+        lz4.extend_from_slice(&[0x02, 0x21, 0x4c, 0x18, 0x10, 0x00, 0x00, 0x00]);
+        let bzip2 = b"BZh91AY&SY";
+        let lzop = [0x89, b'L', b'Z', b'O', 0x00, 0x0d, 0x0a, 0x1a, 0x0a];
+
+        let test_cases: [(&[u8], &str); 10] = [
+            (&corrupted, "usr/init: checksum does not match data"),
+            (&archive[..data_offset + 3], "usr/init: archive ended inside the entry"),
+            (&archive[..trailer_offset], "archive ended without a trailer"),
+            (&junk, "unrecognized data after the end of a cpio archive"),
+            (&compressed_junk, "junk after the end of a cpio archive inside gzip compressed data"),
+            (&bad_magic, "offset 0: not a newc or crc cpio header"),
+            (&long_name, "offset 0: invalid namesize in cpio header"),
+            (&lz4, "lz4 compressed data is not supported"),
+            (bzip2, "bzip2 compressed data is not supported"),
+            (&lzop, "lzop compressed data is not supported"),
+        ];
+        for (image, expected) in test_cases {
+            let error = read_all(&mut InitramfsReader::new(image)).unwrap_err();
+            assert_eq!(error.to_string(), expected);
+        }
+    }
+}
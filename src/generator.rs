@@ -0,0 +1,140 @@
+//! Systemd generator that enables kexec only when it is requested on the kernel command line.
+//!
+//! Instead of overriding `initrd-switch-root.service` unconditionally, the `usb-boot` mkinitcpio
+//! hook installs this program as a systemd generator in the initramfs, under the name
+//! [`GENERATOR_NAME`], and puts the generated units in [`UNIT_SOURCE_DIRECTORY`] where systemd
+//! does not load them. At boot, the generator links the units into the generator output
+//! directory only if the kernel parameter of the kernel to kexec, a boot loader entry (see
+//! [`crate::bls`]) or a UKI (see [`crate::uki`]) is given, or `usbkexec=on` is given. Otherwise
+//! nothing is generated, and the initramfs switches root as usual.
+//! `usbkexec=off` disables kexec even if the kernel parameter is given.
+//! The kernel parameters are the ones `gen-units` configured the runner with, read from
+//! [`units::KEYS_FILE_NAME`] in [`UNIT_SOURCE_DIRECTORY`].
+
+use std::{fs, io, os::unix, path::Path};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{bls, cmdline::KernelCommandLine, initramfs_kexec_runner::TransformParameters, uki, units};
+
+/// The file name this program is run as, when it is run as a generator.
+pub const GENERATOR_NAME: &str = "usb-boot-generator";
+/// Where the `usb-boot` hook puts the units generated by `gen-units` in the initramfs.
+pub const UNIT_SOURCE_DIRECTORY: &str = "/usr/lib/usb-boot/units";
+/// The kernel parameter that enables or disables kexec explicitly.
+pub const ENABLE_KEY: &str = "usbkexec";
+
+/// Returns true if the kernel command line requests kexec-ing into the real kernel.
+/// This is the case if `usbkexec=on` is given, or if the kernel parameter, a boot loader entry or
+/// a UKI is given and `usbkexec=off` is not.
+pub fn is_enabled(command_line: &KernelCommandLine, keys: &TransformParameters) -> bool {
+    match command_line.get(ENABLE_KEY) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => [keys.kernel.as_str(), bls::ENTRY_KEY, uki::UKI_KEY].iter().any(|x| command_line.get(x).is_some()),
+    }
+}
+
+/// Places the units that make the initramfs kexec into the real kernel into `output_directory`.
+/// Units found in `source_directory` are linked, so that units customized with `gen-units` are
+/// used. Units missing from it are generated with the default options.
+pub fn generate_units(output_directory: &Path, source_directory: &Path) -> Result<()> {
+    for (name, default_contents) in units::generate(&Default::default()) {
+        let destination = output_directory.join(name);
+        fs::create_dir_all(destination.parent().unwrap())?;
+        let source = source_directory.join(name);
+        if source.exists() {
+            unix::fs::symlink(&source, &destination)?;
+        }
+        else {
+            fs::write(&destination, default_contents)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the kernel command line keys recorded by `gen-units` in `source_directory`.
+/// The default keys are used if the file is missing.
+pub fn read_keys(source_directory: &Path) -> Result<TransformParameters> {
+    let path = source_directory.join(units::KEYS_FILE_NAME);
+    match fs::read_to_string(&path) {
+        Ok(contents) => units::parse_keys_file(&contents).with_context(|| format!("failed to parse \"{}\"", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(TransformParameters::default()),
+        Err(e) => Err(e).with_context(|| format!("failed to read \"{}\"", path.display())),
+    }
+}
+
+/// Entry point when this program is run as a systemd generator.
+/// Systemd passes the normal, early and late output directories as arguments.
+pub fn generator_main(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args: Vec<String> = args.into_iter().collect();
+    let output_directory = match args.as_slice() {
+        [normal, _early, _late] => Path::new(normal),
+        // Generators may also be called with a single output directory, e.g. when testing.
+        [normal] => Path::new(normal),
+        _ => bail!("expected 1 or 3 arguments, but got {}", args.len()),
+    };
+
+    let source_directory = Path::new(UNIT_SOURCE_DIRECTORY);
+    let command_line = KernelCommandLine::read()?;
+    if !is_enabled(&command_line, &read_keys(source_directory)?) {
+        return Ok(());
+    }
+    generate_units(output_directory, source_directory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDirectory;
+
+    #[test]
+    fn test_is_enabled() {
+        let keys = TransformParameters::default();
+        let test_cases = [
+            ("root=/dev/sda1 usbkexec.kernel=/boot/vmlinuz-linux usbkexec.initrd=/boot/initramfs-linux.img", true),
+            ("root=/dev/sda1 usbkexec=on", true),
+            ("root=/dev/sda1 usbkexec.kernel=/boot/vmlinuz-linux usbkexec=off", false),
+            ("usbkexec=off root=/dev/sda1 usbkexec=on", true),
+            ("root=/dev/sda1 usbkexec.initrd=/boot/initramfs-linux.img", false),
+            ("root=/dev/sda1 usbkexec.kernel", false),
+            ("root=/dev/sda1 usbkexec.entry=default", true),
+            ("root=/dev/sda1 usbkexec.uki=/boot/EFI/Linux/arch-linux.efi", true),
+            ("root=/dev/sda1", false),
+        ];
+        for (command_line, expected) in test_cases {
+            assert_eq!(is_enabled(&KernelCommandLine::parse(command_line), &keys), expected, "{}", command_line);
+        }
+    }
+
+    #[test]
+    fn test_read_keys() {
+        let test_directory = TestDirectory::new("generator-keys");
+        let directory = test_directory.path();
+        assert_eq!(read_keys(directory).unwrap(), TransformParameters::default());
+
+        let keys = TransformParameters { kernel: "custom.kernel".to_string(), ..Default::default() };
+        fs::write(directory.join(units::KEYS_FILE_NAME), units::keys_file(&keys)).unwrap();
+        let read = read_keys(directory).unwrap();
+        assert_eq!(read, keys);
+        assert!(is_enabled(&KernelCommandLine::parse("root=/dev/sda1 custom.kernel=/vmlinuz"), &read));
+        assert!(!is_enabled(&KernelCommandLine::parse("root=/dev/sda1 usbkexec.kernel=/vmlinuz"), &read));
+
+        fs::write(directory.join(units::KEYS_FILE_NAME), "kernel").unwrap();
+        assert!(read_keys(directory).is_err());
+    }
+
+    #[test]
+    fn test_generate_units() {
+        let test_directory = TestDirectory::new("generator");
+        let directory = test_directory.path();
+        let (source, output) = (directory.join("source"), directory.join("output"));
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join(units::KEXEC_UNIT_NAME), "customized").unwrap();
+
+        generate_units(&output, &source).unwrap();
+        assert_eq!(fs::read_to_string(output.join(units::KEXEC_UNIT_NAME)).unwrap(), "customized");
+        assert!(fs::symlink_metadata(output.join(units::KEXEC_UNIT_NAME)).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(output.join(units::SWITCH_ROOT_DROP_IN)).unwrap(), units::switch_root_drop_in());
+    }
+}
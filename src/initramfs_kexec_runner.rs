@@ -1,12 +1,128 @@
-use std::{fs, process::Command};
-use anyhow::Result;
+use std::{fs::{self, File}, io::{Read, Seek}, path::Path, process::Command};
+use anyhow::{Context, Result};
 use common::{AggregateError, size_based_container::SizeBasedContainer};
 
-use crate::utils;
+use crate::{authenticode::TrustStore, bls::{self, BlsEntry}, cmdline::{self, KernelCommandLine}, generator, kernel_image, kexec_load::{self, Backend, LoadOption, LoadOptionError, LoadRequest}, luks::{self, LuksVolume}, overlay::{self, OverlayFile}, stage1::{self, Fingerprint}, target_root::TargetRoot, template, uki::{self, CmdlinePolicy}, utils};
 
 #[derive(Debug, PartialEq)]
 pub struct Config {
     pub transform_parameters: UniqueTransformParameters,
+    pub inheritance: Inheritance,
+    /// How the kernel is loaded, checked with [`kexec_load::check`].
+    pub backend: Backend,
+    pub load_options: Vec<LoadOption>,
+    pub on_failure: OnFailure,
+}
+
+/// What [`run_or_switch_root`] does when kexec fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OnFailure {
+    /// Return the error, so that systemd starts the `OnFailure=` unit, emergency mode.
+    Emergency,
+    /// Switch root into the real system with the usb kernel, as if kexec was not used.
+    #[default]
+    SwitchRoot,
+}
+
+impl OnFailure {
+    /// Parses `emergency` or `switch-root`.
+    pub fn parse(value: &str) -> Option<OnFailure> {
+        match value {
+            "emergency" => Some(OnFailure::Emergency),
+            "switch-root" => Some(OnFailure::SwitchRoot),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OnFailure::Emergency => "emergency",
+            OnFailure::SwitchRoot => "switch-root",
+        }
+    }
+}
+
+/// Which parameters of the first stage are passed on to the second stage, by their keys. The
+/// patterns are globs with `*` and `?`, like `rd.*`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inheritance {
+    All,
+    None,
+    Allow(Vec<String>),
+    Deny(Vec<String>),
+}
+
+/// Keys that only make sense to the first stage, set by boot loaders. They are always dropped,
+/// unless everything is inherited.
+pub const DEFAULT_DENIED: [&str; 2] = ["BOOT_IMAGE", "initrd"];
+
+/// Keys of the parameters that record which parameters of the first stage were passed on.
+pub const INHERITED_KEY: &str = "usbkexec.inherited";
+pub const DROPPED_KEY: &str = "usbkexec.dropped";
+
+impl Default for Inheritance {
+    fn default() -> Self {
+        Inheritance::Deny(DEFAULT_DENIED.iter().map(|x| x.to_string()).collect())
+    }
+}
+
+impl Inheritance {
+    /// Parses `all`, `none`, `allow:PATTERN,...` or `deny:PATTERN,...`. A deny list always
+    /// includes [`DEFAULT_DENIED`].
+    pub fn parse(value: &str) -> Option<Inheritance> {
+        let patterns = |x: &str| x.split(',').filter(|x| !x.is_empty()).map(str::to_string).collect::<Vec<_>>();
+        match value.split_once(':') {
+            None if value == "all" => Some(Inheritance::All),
+            None if value == "none" => Some(Inheritance::None),
+            Some(("allow", x)) => Some(Inheritance::Allow(patterns(x))),
+            Some(("deny", x)) => Some(Inheritance::Deny(DEFAULT_DENIED.iter().map(|x| x.to_string()).chain(patterns(x)).collect())),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the parameter with `key` is passed on.
+    pub fn inherits(&self, key: &str) -> bool {
+        match self {
+            Inheritance::All => true,
+            Inheritance::None => false,
+            Inheritance::Allow(patterns) => patterns.iter().any(|x| bls::glob_matches(x, key)),
+            Inheritance::Deny(patterns) => !patterns.iter().any(|x| bls::glob_matches(x, key)),
+        }
+    }
+}
+
+/// The keys of the parameters of the first stage that were inherited and dropped, in order and
+/// without duplicates.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InheritanceRecord {
+    pub inherited: Vec<String>,
+    pub dropped: Vec<String>,
+}
+
+impl InheritanceRecord {
+    fn push(&mut self, key: &str, inherited: bool) {
+        let keys = if inherited { &mut self.inherited } else { &mut self.dropped };
+        if !keys.iter().any(|x| x == key) {
+            keys.push(key.to_string());
+        }
+    }
+
+    /// Returns the parameters that record the inheritance on the command line of the second
+    /// stage, or nothing if no parameters were passed from the first stage. Keys that cannot
+    /// be given as they are, like quoted flags, are encoded like
+    /// [`encode_additional_args`].
+    pub fn parameters(&self) -> String {
+        let mut parameters = String::new();
+        for (key, keys) in [(INHERITED_KEY, &self.inherited), (DROPPED_KEY, &self.dropped)] {
+            if keys.is_empty() {
+                continue;
+            }
+            let value = keys.join(",");
+            let value = if value.contains([' ', '"', '\'']) { encode_additional_args(&value) } else { value };
+            parameters.push_str(&format!("{}={} ", key, value));
+        }
+        parameters
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -14,9 +130,27 @@ pub struct TransformParameters {
     pub additional_args: String,
     pub kernel: String,
     pub initrd: String,
+    /// The device tree, see [`kernel_image`]. Unlike the kernel and initrd, it is optional.
+    pub dtb: String,
+}
+/// The default kernel command line keys, used by the generated units.
+impl Default for TransformParameters {
+    fn default() -> Self {
+        TransformParameters {
+            additional_args: "usbkexec.args".to_string(),
+            kernel: "usbkexec.kernel".to_string(),
+            initrd: "usbkexec.initrd".to_string(),
+            dtb: "usbkexec.dtb".to_string(),
+        }
+    }
 }
 #[derive(PartialEq, Debug, Clone)]
 pub struct UniqueTransformParameters(TransformParameters);
+impl UniqueTransformParameters {
+    pub fn keys(&self) -> &TransformParameters {
+        &self.0
+    }
+}
 impl TryFrom<TransformParameters> for UniqueTransformParameters {
     type Error = ();
     fn try_from(transform_parameters: TransformParameters) -> Result<Self, ()> {
@@ -24,6 +158,7 @@ impl TryFrom<TransformParameters> for UniqueTransformParameters {
             &transform_parameters.additional_args,
             &transform_parameters.kernel,
             &transform_parameters.initrd,
+            &transform_parameters.dtb,
         ]) {
             Ok(UniqueTransformParameters(transform_parameters))
         } else {
@@ -42,19 +177,160 @@ pub enum TransformCommandLineError {
     RequiredParameterSetMultipleTimes {
         parameter: String,
     },
+    #[error("the value of {parameter} cannot be decoded: {value}")]
+    InvalidEncoding {
+        parameter: String,
+        value: String,
+    },
 }
 #[derive(Debug, PartialEq)]
-struct KexecArgs {
-    kernel: String,
-    initrd: String,
-    command_line: String,
+pub(crate) struct KexecArgs {
+    pub(crate) kernel: String,
+    /// The initrds, concatenated in this order.
+    pub(crate) initrds: Vec<String>,
+    pub(crate) command_line: String,
+    /// The device tree, a path like the kernel or [`kernel_image::FIRMWARE_DTB`].
+    pub(crate) dtb: Option<String>,
+    /// Which parameters of the first stage were passed on in `command_line`.
+    pub(crate) inheritance: InheritanceRecord,
+}
+
+/// Removes an outer pair of single or double quotes around additional arguments.
+fn unquote(value: &str) -> &str {
+    for c in ['\'', '"'] {
+        if value.starts_with(c) && value.ends_with(c) {
+            return value.get(1..value.len() - 1).unwrap_or("");
+        }
+    }
+    value
+}
+
+/// Prefixes of encoded additional arguments, which can carry any command line, including quotes
+/// of both kinds.
+pub const BASE64_PREFIX: &str = "base64:";
+pub const HEX_PREFIX: &str = "hex:";
+
+/// Decodes the value of the additional arguments. Values with [`BASE64_PREFIX`] or
+/// [`HEX_PREFIX`] are decoded, and other values are [`unquote`]d.
+fn decode_additional_args(key: &str, value: &str) -> Result<String, TransformCommandLineError> {
+    let decoded = if let Some(base64) = value.strip_prefix(BASE64_PREFIX) {
+        utils::from_base64(base64)
+    } else if let Some(hex) = value.strip_prefix(HEX_PREFIX) {
+        utils::from_hex(hex)
+    } else {
+        return Ok(unquote(value).to_string());
+    };
+    decoded.and_then(|x| String::from_utf8(x).ok())
+        .ok_or_else(|| TransformCommandLineError::InvalidEncoding { parameter: key.to_string(), value: value.to_string() })
+}
+
+/// Encodes additional arguments with [`BASE64_PREFIX`], for [`decode_additional_args`].
+pub fn encode_additional_args(args: &str) -> String {
+    format!("{}{}", BASE64_PREFIX, utils::to_base64(args.as_bytes()))
+}
+
+/// Builds the arguments of kexec from a boot loader entry whose `$BOOT` is at `boot` in the real
+/// system. The command line is the parameters of `command_line` that the [`Inheritance`] passes
+/// on, then the `options` of the entry, which win over them, then the additional arguments given
+/// on `command_line`.
+fn entry_kexec_args(command_line: &str, transform_parameters: &TransformParameters, inheritance: &Inheritance, boot: &Path, entry: &BlsEntry) -> Result<KexecArgs, TransformCommandLineError> {
+    let in_boot = |path: &str| boot.join(path.trim_start_matches('/')).to_string_lossy().into_owned();
+    let mut inherited = String::new();
+    let mut additional_args = String::new();
+    let mut record = InheritanceRecord::default();
+    for parameter in utils::split_at_unquoted_spaces(command_line) {
+        let (key, value) = parameter.split_once('=').map_or((parameter, None), |(key, value)| (key, Some(value)));
+        match value {
+            Some(value) if key == transform_parameters.additional_args => {
+                additional_args.push_str(&decode_additional_args(key, value)?);
+                additional_args.push(' ');
+            },
+            // The entry gives the kernel, initrd and device tree.
+            Some(_) if [&transform_parameters.kernel, &transform_parameters.initrd, &transform_parameters.dtb].iter().any(|x| *x == key) => {},
+            _ => {
+                let is_inherited = inheritance.inherits(key);
+                record.push(key, is_inherited);
+                if is_inherited {
+                    inherited.push_str(parameter);
+                    inherited.push(' ');
+                }
+            },
+        }
+    }
+    let mut new_cmdline = inherited;
+    for option in &entry.options {
+        new_cmdline.push_str(option);
+        new_cmdline.push(' ');
+    }
+    new_cmdline.push_str(&additional_args);
+    Ok(KexecArgs {
+        kernel: in_boot(&entry.linux),
+        initrds: entry.initrd.iter().map(|x| in_boot(x)).collect(),
+        command_line: new_cmdline,
+        dtb: entry.devicetree.as_deref().map(in_boot),
+        inheritance: record,
+    })
 }
-fn transform_command_line(command_line: &str, transform_parameters: UniqueTransformParameters) -> Result<KexecArgs, AggregateError<TransformCommandLineError>> {
+
+pub(crate) fn transform_command_line(command_line: &str, transform_parameters: UniqueTransformParameters, inheritance: &Inheritance) -> Result<KexecArgs, AggregateError<TransformCommandLineError>> {
     let transform_parameters = transform_parameters.0;
+    let SplitCommandLine { command_line: new_cmdline, kernel, initrd, dtb, inheritance, mut errors } = split_command_line(command_line, &transform_parameters, inheritance);
+
+    // If kernel or initramfs are not provided on the kernel command line,
+    // return an error.
+    for (value, parameter_str) in [
+        (kernel, transform_parameters.kernel),
+        (initrd, transform_parameters.initrd),
+    ] {
+        if value.is_none() {
+            errors.push(TransformCommandLineError::MissingRequiredParameter {
+                parameter: parameter_str,
+            });
+        }
+    }
+    if let Ok(aggregate) = AggregateError::try_from(errors) {
+        return Err(aggregate);
+    }
+
+    Ok(KexecArgs {
+        command_line: new_cmdline,
+        kernel: kernel.unwrap().to_string(),
+        initrds: vec![initrd.unwrap().to_string()],
+        dtb: dtb.map(str::to_string),
+        inheritance,
+    })
+}
+
+/// Transforms the command line like [`transform_command_line`], for a UKI that carries its own
+/// kernel and initrd. The kernel and initrd parameters are dropped if they are given.
+fn transform_command_line_for_uki<'a>(command_line: &'a str, transform_parameters: &UniqueTransformParameters, inheritance: &Inheritance) -> Result<SplitCommandLine<'a>, AggregateError<TransformCommandLineError>> {
+    let mut split = split_command_line(command_line, &transform_parameters.0, inheritance);
+    if let Ok(aggregate) = AggregateError::try_from(std::mem::take(&mut split.errors)) {
+        return Err(aggregate);
+    }
+    Ok(split)
+}
 
+/// A command line with the transform parameters split off, by [`split_command_line`].
+struct SplitCommandLine<'a> {
+    /// The new command line, with the additional arguments in place and the other parameters
+    /// that the [`Inheritance`] passes on.
+    command_line: String,
+    kernel: Option<&'a str>,
+    initrd: Option<&'a str>,
+    dtb: Option<&'a str>,
+    inheritance: InheritanceRecord,
+    /// The parameters that were set multiple times.
+    errors: Vec<TransformCommandLineError>,
+}
+
+/// Splits the transform parameters off the command line.
+fn split_command_line<'a>(command_line: &'a str, transform_parameters: &TransformParameters, inheritance: &Inheritance) -> SplitCommandLine<'a> {
     let mut new_cmdline = String::new();
     let mut kernel: Option<&str> = None;
     let mut initrd: Option<&str> = None;
+    let mut dtb: Option<&str> = None;
+    let mut record = InheritanceRecord::default();
 
     let mut errors = Vec::new();
 
@@ -64,31 +340,25 @@ fn transform_command_line(command_line: &str, transform_parameters: UniqueTransf
     'args_loop: for parameter in utils::split_at_unquoted_spaces(command_line) {
         // Parameter only matches if it is in the form of "key=value"
         // and the key is equal to one of the transform parameters.
-        if let Some((key, mut value)) = parameter.split_once('=') {
+        if let Some((key, value)) = parameter.split_once('=') {
             if key == transform_parameters.additional_args {
                 // If the additional arguments are wrapped in single quotes or double quotes,
                 // remove the outer pair of quotes before pushing the arguments onto the new
-                // command line.
-                let mut is_wrapped = false;
-                for c in ['\'', '"'] {
-                    is_wrapped = is_wrapped ||
-                        (value.starts_with(c) && value.ends_with(c));
-                }
-                if is_wrapped {
-                    let mut chars = value.chars();
-                    chars.next();
-                    chars.next_back();
-                    value = chars.as_str();
+                // command line. Encoded arguments are decoded instead.
+                match decode_additional_args(key, value) {
+                    Ok(args) => {
+                        new_cmdline.push_str(&args);
+                        new_cmdline.push(' ');
+                    },
+                    Err(e) => errors.push(e),
                 }
-
-                new_cmdline.push_str(value);
-                new_cmdline.push(' ');
                 continue 'args_loop;
             }
             else {
                 for (key_name, set_var) in [
                     (&transform_parameters.kernel, &mut kernel),
                     (&transform_parameters.initrd, &mut initrd),
+                    (&transform_parameters.dtb, &mut dtb),
                 ] {
                     if key == key_name {
                         if set_var.is_some() {
@@ -103,66 +373,239 @@ fn transform_command_line(command_line: &str, transform_parameters: UniqueTransf
             }
         }
         // Parameter did not match any of the keys.
-        // So just add it onto the new cmdline.
-        new_cmdline.push_str(parameter);
-        new_cmdline.push(' ');
-    }
-
-    // If kernel or initramfs are not provided on the kernel command line,
-    // return an error.
-    for (value, parameter_str) in [
-        (kernel, transform_parameters.kernel),
-        (initrd, transform_parameters.initrd),
-    ] {
-        if value.is_none() {
-            errors.push(TransformCommandLineError::MissingRequiredParameter {
-                parameter: parameter_str,
-            });
+        // So add it onto the new cmdline, if it is inherited.
+        let key = parameter.split_once('=').map_or(parameter, |(key, _)| key);
+        let inherited = inheritance.inherits(key);
+        record.push(key, inherited);
+        if inherited {
+            new_cmdline.push_str(parameter);
+            new_cmdline.push(' ');
         }
     }
-    if let Ok(aggregate) = AggregateError::try_from(errors) {
-        return Err(aggregate);
-    }
-
-    Ok(KexecArgs {
-        command_line: new_cmdline,
-        kernel: kernel.unwrap().to_string(),
-        initrd: initrd.unwrap().to_string(),
-    })
+    SplitCommandLine { command_line: new_cmdline, kernel, initrd, dtb, inheritance: record, errors }
 }
 
 pub fn run(config: Config) -> Result<()> {
-    // Get current kernel command line
-    let kernel_command_line = fs::read_to_string("/proc/cmdline")?;
-
-    // Transform command line
-    let new_command_line = transform_command_line(&kernel_command_line, config.transform_parameters)?;
-
-    // Invoke kexec -l
-    let success = Command::new("kexec")
-        .args([
-            "-l",
-            &new_command_line.kernel,
-            &format!("--initrd={}", new_command_line.initrd),
-            &format!("--append={}", new_command_line.command_line),
-        ])
+    // Forward the LUKS key, if requested.
+    let mut extra_overlay_files = Vec::new();
+    if let Some(volume) = LuksVolume::from_command_line(&KernelCommandLine::read()?)? {
+        extra_overlay_files.push(luks::forward_key(&volume)?);
+    }
+
+    load(config, extra_overlay_files)?;
+
+    // Invoke systemctl kexec
+    let success = Command::new("systemctl")
+        .arg("kexec")
         .spawn()?
         .wait()?
         .success();
     if !success {
-        anyhow::bail!("failed to kexec load");
+        anyhow::bail!("failed to systemctl kexec");
     }
 
-    // Invoke systemctl kexec
+    Ok(())
+}
+
+/// Loads the real kernel with `kexec -l`, without executing it.
+/// `extra_overlay_files` are appended to the initrd along with the files of the overlay config.
+pub fn load(config: Config, extra_overlay_files: Vec<OverlayFile>) -> Result<()> {
+    // Get current kernel command line, without the newline /proc/cmdline ends with
+    let kernel_command_line = fs::read_to_string("/proc/cmdline")?.trim_end_matches('\n').to_string();
+
+    let parsed_command_line = KernelCommandLine::parse(&kernel_command_line);
+    let target_root = TargetRoot::locate(&parsed_command_line)?;
+    let trust = TrustStore::from_command_line(&parsed_command_line)?;
+    let keep_zboot = kexec_load::uses_kexec_file_load(config.backend, &config.load_options);
+
+    // Take the kernel, initrds and command line from a UKI or a boot loader entry of the real
+    // system, if one is selected. Otherwise transform the command line.
+    let (mut new_command_line, kernel, initrds, dtb, inheritance) = if let Some(path) = parsed_command_line.get(uki::UKI_KEY) {
+        let policy = CmdlinePolicy::from_command_line(&parsed_command_line)?;
+        let split = transform_command_line_for_uki(&kernel_command_line, &config.transform_parameters, &config.inheritance)?;
+        let uki = uki::extract(&target_root, path, trust.as_ref())?;
+        println!("booting the UKI \"{}\"", uki.pretty_name.as_deref().unwrap_or(path));
+        let kernel = prepared_kernel(uki.kernel, path, keep_zboot)?;
+        let transformed = template::expand_command_line(&split.command_line, &parsed_command_line, &target_root, Some(&kernel), uki.uname.as_deref())?;
+        // Nothing of the first stage is passed on if only the embedded command line is used.
+        let inheritance = if policy == CmdlinePolicy::Embedded { InheritanceRecord::default() } else { split.inheritance };
+        (policy.merge(&uki.cmdline, &transformed), kernel, uki.initrds, split.dtb.map(str::to_string), inheritance)
+    }
+    else {
+        let (kexec_args, kernel_version) = match parsed_command_line.get(bls::ENTRY_KEY) {
+            Some(selector) => {
+                let (boot, entry) = bls::find_entry(&target_root, selector)?;
+                println!("booting the boot loader entry \"{}\"", entry.title.as_deref().unwrap_or(&entry.id));
+                let mut kexec_args = entry_kexec_args(&kernel_command_line, &config.transform_parameters.0, &config.inheritance, &boot, &entry)?;
+                // The device tree of the entry wins over the one on the command line.
+                kexec_args.dtb = kexec_args.dtb.or_else(|| parsed_command_line.get(&config.transform_parameters.0.dtb).map(str::to_string));
+                (kexec_args, entry.version)
+            },
+            None => (transform_command_line(&kernel_command_line, config.transform_parameters.clone(), &config.inheritance)?, None),
+        };
+        let (mut kernel, initrds) = open_boot_files(&target_root, &kexec_args)?;
+        if let Some(trust) = &trust {
+            kernel = verified_kernel(trust, &kernel, &kexec_args.kernel)?;
+        }
+        let kernel = prepared_kernel(kernel, &kexec_args.kernel, keep_zboot)?;
+        let command_line = template::expand_command_line(&kexec_args.command_line, &parsed_command_line, &target_root, Some(&kernel), kernel_version.as_deref())?;
+        (command_line, kernel, initrds, kexec_args.dtb, kexec_args.inheritance)
+    };
+    let dtb = match &dtb {
+        Some(path) => Some(open_dtb(&target_root, path)?),
+        None => None,
+    };
+    new_command_line.push_str(&inheritance.parameters());
+
+    // Hand a fingerprint of the first stage to the real system, so it can
+    // check that it was booted through a known first stage.
+    let fingerprint = Fingerprint::compute(&parsed_command_line);
+    new_command_line.push_str(&format!("{}={} ", stage1::STAGE1_KEY, fingerprint));
+
+    // Check the command line before loading, rather than have the kernel truncate or misparse it.
+    let new_command_line = new_command_line.trim_end();
+    let limit = cmdline::command_line_limit(&kernel)?;
+    cmdline::validate(new_command_line, limit).context("the command line of the real kernel is invalid")?;
+    let initrds: Vec<&File> = initrds.iter().collect();
+
+    // Concatenate the initrds and append the files of the overlay, in memory. The memfd has to
+    // stay open until kexec has loaded it.
+    let mut overlay_files = overlay::read_config()?;
+    overlay_files.extend(extra_overlay_files);
+    let initrd_with_overlay = match (initrds.as_slice(), overlay_files.is_empty()) {
+        ([] | [_], true) => None,
+        _ => Some(overlay::initrd_with_overlay(&initrds, &overlay_files)?),
+    };
+    let initrd = initrd_with_overlay.as_ref().or(initrds.first().copied());
+    // Zeroize the forwarded key, if any, now that it is in the memfd.
+    drop(overlay_files);
+
+    // Invoke kexec -l, or load with the syscall
+    let result = kexec_load::load(config.backend, &config.load_options, &LoadRequest {
+        kernel: &kernel,
+        initrd,
+        dtb: dtb.as_ref(),
+        command_line: new_command_line,
+    });
+    // kexec has copied the initrd, so the memfd is no longer needed.
+    if let Some(memfd) = &initrd_with_overlay {
+        overlay::wipe(memfd)?;
+    }
+    if let Err(e) = result {
+        return Err(match lockdown_mode() {
+            Some(mode) if mode != "none" => e.context(format!("failed to kexec load, the kernel is locked down ({})", mode)),
+            _ => e.context("failed to kexec load"),
+        });
+    }
+    Ok(())
+}
+
+/// Opens the kernel and initrds in the root of the real system, as though it were /. They are
+/// handed to kexec through their file descriptors so it cannot resolve them differently.
+fn open_boot_files(target_root: &TargetRoot, kexec_args: &KexecArgs) -> Result<(File, Vec<File>)> {
+    let paths = [("kernel", &kexec_args.kernel)].into_iter()
+        .chain(kexec_args.initrds.iter().map(|x| ("initrd", x)));
+    let mut files = Vec::new();
+    for (description, path) in paths {
+        let file = target_root.open(path)
+            .with_context(|| format!("failed to open the {} \"{}\" in {}", description, path, target_root.root().display()))?;
+        if !file.metadata()?.is_file() {
+            anyhow::bail!("the {} \"{}\" is not a file", description, path);
+        }
+        files.push(file);
+    }
+    let kernel = files.remove(0);
+    Ok((kernel, files))
+}
+
+/// Verifies the Authenticode signature of the kernel, and returns a memfd with the verified
+/// contents, so that the file cannot be changed between verifying and loading it.
+fn verified_kernel(trust: &TrustStore, mut kernel: &File, path: &str) -> Result<File> {
+    let mut data = Vec::new();
+    kernel.read_to_end(&mut data)?;
+    if let Err(e) = trust.verify(&data) {
+        anyhow::bail!("failed to verify the kernel \"{}\": {}", path, e);
+    }
+    Ok(overlay::memfd_with_contents("usb-boot-kernel", &data)?)
+}
+
+/// Decompresses the kernel into a memfd if it is compressed, like `Image.gz` or a zboot image, and
+/// checks that it is a kernel for this architecture. Zboot images are kept as they are for
+/// `kexec_file_load`, see [`kernel_image::prepare`].
+fn prepared_kernel(mut kernel: File, path: &str, keep_zboot: bool) -> Result<File> {
+    let mut data = Vec::new();
+    kernel.read_to_end(&mut data)?;
+    let prepared = kernel_image::prepare(&data, std::env::consts::ARCH, keep_zboot)
+        .with_context(|| format!("cannot boot the kernel \"{}\"", path))?;
+    match prepared {
+        Some(decompressed) => Ok(overlay::memfd_with_contents("usb-boot-kernel", &decompressed)?),
+        None => {
+            kernel.rewind()?;
+            Ok(kernel)
+        },
+    }
+}
+
+/// Opens the device tree: the one the first stage was booted with for
+/// [`kernel_image::FIRMWARE_DTB`], or a file in the real system.
+fn open_dtb(target_root: &TargetRoot, path: &str) -> Result<File> {
+    let mut dtb = match path {
+        kernel_image::FIRMWARE_DTB => File::open(kernel_image::FIRMWARE_DTB_PATH)
+            .with_context(|| format!("failed to open the device tree of the first stage, {}", kernel_image::FIRMWARE_DTB_PATH))?,
+        _ => target_root.open(path)
+            .with_context(|| format!("failed to open the device tree \"{}\" in {}", path, target_root.root().display()))?,
+    };
+    let mut data = Vec::new();
+    dtb.read_to_end(&mut data)?;
+    kernel_image::validate_dtb(&data).with_context(|| format!("cannot use the device tree \"{}\"", path))?;
+    // kexec reads it through /proc, like the kernel, so the contents must not change.
+    Ok(overlay::memfd_with_contents("usb-boot-dtb", &data)?)
+}
+
+/// Returns the active kernel lockdown mode, e.g. "none" or "integrity", if the kernel supports
+/// lockdown.
+fn lockdown_mode() -> Option<String> {
+    let modes = fs::read_to_string("/sys/kernel/security/lockdown").ok()?;
+    let start = modes.find('[')?;
+    let end = start + modes[start..].find(']')?;
+    Some(modes[start+1..end].to_string())
+}
+
+/// Switches root into the real system with the usb kernel, like the `ExecStart=` of
+/// `initrd-switch-root.service` that the switch-root drop-in overrides.
+pub fn switch_root() -> Result<()> {
     let success = Command::new("systemctl")
-        .arg("kexec")
+        .args(["--no-block", "switch-root"])
         .spawn()?
         .wait()?
         .success();
     if !success {
-        anyhow::bail!("failed to systemctl kexec");
+        anyhow::bail!("failed to systemctl switch-root");
+    }
+    Ok(())
+}
+
+/// Runs [`run`], unless kexec is disabled with `usbkexec=off` on the kernel command line.
+/// If kexec is disabled or fails, e.g. because the kernel is missing, loading it fails or the
+/// kernel is locked down, switches root with the usb kernel instead of leaving the machine
+/// stranded. Only if that fails too, the error is returned, and systemd starts emergency mode.
+/// With [`OnFailure::Emergency`], a failure of kexec is returned right away instead.
+pub fn run_or_switch_root(config: Config) -> Result<()> {
+    let command_line = KernelCommandLine::read()?;
+    if command_line.get(generator::ENABLE_KEY) == Some("off") {
+        println!("kexec is disabled with {}=off, switching root instead", generator::ENABLE_KEY);
+        return switch_root();
     }
 
+    let on_failure = config.on_failure;
+    if let Err(e) = run(config) {
+        if on_failure == OnFailure::Emergency {
+            return Err(e.context("failed to kexec into the real kernel"));
+        }
+        eprintln!("failed to kexec into the real kernel: {:#}", e);
+        eprintln!("falling back to switching root with the usb kernel");
+        return switch_root().with_context(|| format!("failed to kexec into the real kernel: {:#}", e));
+    }
     Ok(())
 }
 
@@ -223,11 +666,60 @@ pub enum ParseArgsError {
     },
     #[error("multiple options were set to the same value")]
     MultipleOptionSameValue,
+    /// The value of [`INHERIT_OPTION`] is not a valid [`Inheritance`].
+    #[error("invalid inheritance \"{value}\", expected all, none, allow:PATTERN,... or deny:PATTERN,...")]
+    InvalidInheritance {
+        value: String,
+    },
+    /// The value of [`ON_FAILURE_OPTION`] is not a valid [`OnFailure`].
+    #[error("invalid failure action \"{value}\", expected emergency or switch-root")]
+    InvalidOnFailure {
+        value: String,
+    },
+    /// The value of [`BACKEND_OPTION`] or [`LOAD_OPTIONS_OPTION`] is invalid, or the backend
+    /// cannot honour the load options.
+    #[error(transparent)]
+    LoadOption(#[from] LoadOptionError),
+    /// [`LoadOption::ReuseCmdline`] is given, but not every parameter of the first stage is
+    /// inherited, which it would undo.
+    #[error("the kexec load option reuse-cmdline passes on every parameter of the first stage, so it needs {}=all", INHERIT_OPTION)]
+    ReuseCmdlineWithoutInheritAll,
+}
+
+/// The optional option of the `kexec` subcommand that sets the [`Inheritance`], which defaults
+/// to dropping [`DEFAULT_DENIED`].
+pub const INHERIT_OPTION: &str = "--inherit";
+/// The optional options of the `kexec` subcommand that select the [`Backend`], `kexec-tools` by
+/// default, and the [`LoadOption`]s, as a comma separated list.
+pub const BACKEND_OPTION: &str = "--backend";
+pub const LOAD_OPTIONS_OPTION: &str = "--load-options";
+/// The optional option of the `kexec` subcommand that selects the [`OnFailure`] action.
+pub const ON_FAILURE_OPTION: &str = "--on-failure";
+
+/// Returns the names of the options of the `kexec` subcommand, used as the
+/// `option_names` parameter of [`parse_args`].
+pub fn option_names() -> TransformParameters {
+    TransformParameters {
+        additional_args: "--additional_args".to_string(),
+        kernel: "--kernel".to_string(),
+        initrd: "--initrd".to_string(),
+        dtb: "--dtb".to_string(),
+    }
+}
+
+/// Parses the values of [`BACKEND_OPTION`] and [`LOAD_OPTIONS_OPTION`], and checks that the
+/// backend can honour the load options on this architecture.
+fn parse_load_options(backend: Option<&str>, load_options: Option<&str>) -> Result<(Backend, Vec<LoadOption>), LoadOptionError> {
+    let backend = backend.map_or(Ok(Backend::default()), Backend::parse)?;
+    let load_options = load_options.map_or(Ok(Vec::new()), LoadOption::parse_list)?;
+    kexec_load::check(backend, &load_options, std::env::consts::ARCH)?;
+    Ok((backend, load_options))
 }
 
 /// This function parses the command line arguments of this program.
 /// There must be exactly three options specified, with one option for each option name / key in
-/// the `option_names` parameter.
+/// the `option_names` parameter except the dtb, which is optional like [`INHERIT_OPTION`],
+/// [`BACKEND_OPTION`] and [`LOAD_OPTIONS_OPTION`].
 /// Each option must be in the form of "key=value" (1 argument) or "key value" (2 arguments).
 /// The 3 options are the strings stored in the 3 fields of the `option_names` parameter of
 /// this function.
@@ -242,6 +734,11 @@ pub fn parse_args(args: impl IntoIterator<Item=String>, option_names: UniqueTran
     let mut additional_args = None;
     let mut kernel = None;
     let mut initrd = None;
+    let mut inherit = None;
+    let mut dtb = None;
+    let mut backend = None;
+    let mut load_options = None;
+    let mut on_failure = None;
 
     let mut errors = Vec::new();
 
@@ -253,28 +750,31 @@ pub fn parse_args(args: impl IntoIterator<Item=String>, option_names: UniqueTran
         (option_names.kernel, &mut kernel),
         (option_names.initrd, &mut initrd),
     ];
+    // Same as above, for options that may be left out.
+    let mut optional_mappings = [
+        (option_names.dtb, &mut dtb),
+        (INHERIT_OPTION.to_string(), &mut inherit),
+        (BACKEND_OPTION.to_string(), &mut backend),
+        (LOAD_OPTIONS_OPTION.to_string(), &mut load_options),
+        (ON_FAILURE_OPTION.to_string(), &mut on_failure),
+    ];
 
     // This is basically a for loop over the args argument.
     // It is done this way to allow access to the iterator.
     let mut args = args.into_iter();
-    'args_loop: loop {
-        let arg = match args.next() {
-            Some(x) => x,
-            None => break,
-        };
+    'args_loop: while let Some(arg) = args.next() {
         // For each possible option, check if the argument matches the option.
-        for (key_name, set_var) in mappings.iter_mut() {
+        for (key_name, set_var) in mappings.iter_mut().chain(optional_mappings.iter_mut()) {
             // There are two ways to specify an option with value on the command line.
             // Check if the current argument matches the current option
             // in any of the two ways, and set the value variable to the option's value
             // if it matches.
             // If it does not match in any of the two ways, move on to the next possible option.
-            let value;
-            if arg == **key_name {
+            let value = if arg == **key_name {
                 // The option is specified in the form of "--option value", with the key
                 // in one argument and the value of the option in the next.
                 // Get the next argument and set the value variable to that.
-                value = match args.next() {
+                match args.next() {
                     Some(x) => x,
                     None => {
                         // If there is no next argument (the iterator is exhausted),
@@ -282,7 +782,7 @@ pub fn parse_args(args: impl IntoIterator<Item=String>, option_names: UniqueTran
                         errors.push(ParseArgsError::KeyWithoutValue { key: key_name.clone() });
                         break 'args_loop;
                     },
-                };
+                }
             }
             else {
                 // Check if the option is specified in the
@@ -291,8 +791,8 @@ pub fn parse_args(args: impl IntoIterator<Item=String>, option_names: UniqueTran
                 if !arg.starts_with(&beginning_part) {
                     continue;
                 }
-                value = arg[beginning_part.len()..].to_string();
-            }
+                arg[beginning_part.len()..].to_string()
+            };
 
             // If the corresponding variable to set has
             // already been set, this means the current option
@@ -320,6 +820,28 @@ pub fn parse_args(args: impl IntoIterator<Item=String>, option_names: UniqueTran
         }
     }
 
+    let inheritance = match inherit {
+        Some(value) => Inheritance::parse(&value).unwrap_or_else(|| {
+            errors.push(ParseArgsError::InvalidInheritance { value });
+            Inheritance::default()
+        }),
+        None => Inheritance::default(),
+    };
+    let on_failure = match on_failure {
+        Some(value) => OnFailure::parse(&value).unwrap_or_else(|| {
+            errors.push(ParseArgsError::InvalidOnFailure { value });
+            OnFailure::default()
+        }),
+        None => OnFailure::default(),
+    };
+    let (backend, load_options) = parse_load_options(backend.as_deref(), load_options.as_deref()).unwrap_or_else(|e| {
+        errors.push(e.into());
+        (Backend::default(), Vec::new())
+    });
+    if load_options.contains(&LoadOption::ReuseCmdline) && inheritance != Inheritance::All {
+        errors.push(ParseArgsError::ReuseCmdlineWithoutInheritAll);
+    }
+
     // Check if any errors have been raised.
     // If so, exit the function with an error.
     if let Ok(aggregate) = AggregateError::try_from(errors) {
@@ -330,11 +852,16 @@ pub fn parse_args(args: impl IntoIterator<Item=String>, option_names: UniqueTran
         additional_args: additional_args.unwrap(),
         kernel: kernel.unwrap(),
         initrd: initrd.unwrap(),
+        dtb: dtb.unwrap_or_else(|| TransformParameters::default().dtb),
     }.try_into();
 
     match unique_transform_parameters {
         Ok(x) => Ok(Config {
             transform_parameters: x,
+            inheritance,
+            backend,
+            load_options,
+            on_failure,
         }),
         Err(_) => Err(SizeBasedContainer::from_single(ParseArgsError::MultipleOptionSameValue)
                       .try_into()
@@ -368,11 +895,13 @@ mod tests {
             additional_args: "hello".to_string(),
             kernel: "goodbye".to_string(),
             initrd: "cheese".to_string(),
+            dtb: "usbkexec.dtb".to_string(),
         };
         let not_unique = TransformParameters {
             additional_args: "hello".to_string(),
             kernel: "hello".to_string(),
             initrd: "cheese".to_string(),
+            dtb: "usbkexec.dtb".to_string(),
         };
 
         assert_eq!(UniqueTransformParameters::try_from(unique.clone()), Ok(UniqueTransformParameters(unique)));
@@ -385,13 +914,20 @@ mod tests {
             additional_args: "--asdf".to_string(),
             kernel: "--kernel-lol".to_string(),
             initrd: "--see-initrd".to_string(),
+            dtb: "--see-dtb".to_string(),
         }.try_into().unwrap();
+        let inherited = |keys: &[&str]| InheritanceRecord {
+            inherited: keys.iter().map(|x| x.to_string()).collect(),
+            dropped: Vec::new(),
+        };
 
-        let working_command_line = r#"2312 --kernel-lol=tty390=zxcvr lol=5 --asdf="tee=4 sasd=1 83      dfds 983=5=das"     see 3 cx=8ijds --see-initrd=--kernel-lol"#;
+        let working_command_line = r#"2312 --kernel-lol=tty390=zxcvr --see-dtb=/boot/board.dtb lol=5 --asdf="tee=4 sasd=1 83      dfds 983=5=das"     see 3 cx=8ijds --see-initrd=--kernel-lol"#;
         let working_expected = Ok(KexecArgs {
             kernel: "tty390=zxcvr".to_string(),
-            initrd: "--kernel-lol".to_string(),
+            initrds: vec!["--kernel-lol".to_string()],
             command_line: "2312 lol=5 tee=4 sasd=1 83      dfds 983=5=das see 3 cx=8ijds ".to_string(),
+            dtb: Some("/boot/board.dtb".to_string()),
+            inheritance: inherited(&["2312", "lol", "see", "3", "cx"]),
         });
 
         let missing_kernel_command_line = r#"2312 --kernel-lol lol=5 --asdf="tee=4 sasd=1 83      dfds 983=5=das"     see 3 cx=8ijds --see-initrd=--kernel-lol"#;
@@ -415,15 +951,19 @@ mod tests {
         let no_additional_args_command_line = r#"lololololol --kernel-lol= --see-initrd="#;
         let no_additional_args_expected = Ok(KexecArgs {
             kernel: "".to_string(),
-            initrd: "".to_string(),
+            initrds: vec!["".to_string()],
             command_line: "lololololol ".to_string(),
+            dtb: None,
+            inheritance: inherited(&["lololololol"]),
         });
 
         let additional_args_quotes_command_line = r#"an_option="32 cxds" 'jcxn ewi' --kernel-lol= --see-initrd= --asdf="lol=3" ewji  --asdf=""fdji   e32 cx=3"" --asdf="'hello goodbye c32=gfda'" --asdf="x="hello    fdjs"  id=4"   ejkncxv"#;
         let additional_args_quotes_expected = Ok(KexecArgs {
             kernel: "".to_string(),
-            initrd: "".to_string(),
+            initrds: vec!["".to_string()],
             command_line: r#"an_option="32 cxds" 'jcxn ewi' lol=3 ewji "fdji   e32 cx=3" 'hello goodbye c32=gfda' x="hello    fdjs"  id=4 ejkncxv "#.to_string(),
+            dtb: None,
+            inheritance: inherited(&["an_option", "'jcxn ewi'", "ewji", "ejkncxv"]),
         });
 
         for (command_line, expected) in [
@@ -433,7 +973,111 @@ mod tests {
             (no_additional_args_command_line, no_additional_args_expected),
             (additional_args_quotes_command_line, additional_args_quotes_expected),
         ] {
-            assert_eq!(transform_command_line(command_line, transform_parameters.clone()), expected);
+            assert_eq!(transform_command_line(command_line, transform_parameters.clone(), &Inheritance::default()), expected);
+        }
+    }
+
+    #[test]
+    fn test_entry_kexec_args() {
+        let entry = BlsEntry {
+            id: "arch".to_string(),
+            linux: "/vmlinuz-linux".to_string(),
+            initrd: vec!["/intel-ucode.img".to_string(), "initramfs-linux.img".to_string()],
+            options: vec!["root=/dev/mapper/root rw".to_string(), "quiet".to_string()],
+            devicetree: Some("dtbs/board.dtb".to_string()),
+            ..Default::default()
+        };
+        let command_line = r#"BOOT_IMAGE=/vmlinuz root=/dev/sda1 usbkexec.entry=default usbkexec.kernel=/vmlinuz usbkexec.args="debug loglevel=7" usbkexec.args=nomodeset"#;
+        let transform_parameters = TransformParameters::default();
+        let kexec_args = |command_line: &str, inherited: &[&str], dropped: &[&str]| KexecArgs {
+            kernel: "/boot/vmlinuz-linux".to_string(),
+            initrds: vec!["/boot/intel-ucode.img".to_string(), "/boot/initramfs-linux.img".to_string()],
+            command_line: command_line.to_string(),
+            dtb: Some("/boot/dtbs/board.dtb".to_string()),
+            inheritance: InheritanceRecord {
+                inherited: inherited.iter().map(|x| x.to_string()).collect(),
+                dropped: dropped.iter().map(|x| x.to_string()).collect(),
+            },
+        };
+        let test_cases = [
+            (Inheritance::None, "root=/dev/mapper/root rw quiet debug loglevel=7 nomodeset ", &[][..], &["BOOT_IMAGE", "root", "usbkexec.entry"][..]),
+            (Inheritance::default(), "root=/dev/sda1 usbkexec.entry=default root=/dev/mapper/root rw quiet debug loglevel=7 nomodeset ", &["root", "usbkexec.entry"], &["BOOT_IMAGE"]),
+        ];
+        for (inheritance, expected_command_line, inherited, dropped) in test_cases {
+            assert_eq!(
+                entry_kexec_args(command_line, &transform_parameters, &inheritance, Path::new("/boot"), &entry),
+                Ok(kexec_args(expected_command_line, inherited, dropped)),
+                "{:?}", inheritance,
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_additional_args() {
+        let args = r#"acpi_osi="!Windows 2020" x='a "b"'"#;
+        let invalid = |value: &str| Err(TransformCommandLineError::InvalidEncoding {
+            parameter: "usbkexec.args".to_string(),
+            value: value.to_string(),
+        });
+        let test_cases = [
+            (r#""quiet rw""#, Ok("quiet rw".to_string())),
+            ("'quiet'", Ok("quiet".to_string())),
+            ("hex:71756965742072773d2722", Ok("quiet rw='\"".to_string())),
+            (&encode_additional_args(args), Ok(args.to_string())),
+            ("base64:cXVpZXQ", Ok("quiet".to_string())),
+            ("base64:cXVpZXQ*", invalid("base64:cXVpZXQ*")),
+            ("hex:7", invalid("hex:7")),
+            ("hex:ff", invalid("hex:ff")),
+        ];
+        for (value, expected) in test_cases {
+            assert_eq!(decode_additional_args("usbkexec.args", value), expected);
+        }
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let command_line = r#"BOOT_IMAGE=/vmlinuz root=/dev/sdb1 rd.luks.uuid=1234 console=ttyS0 quiet 'a b' initrd=/initrd.img usbkexec.kernel=/vmlinuz usbkexec.initrd=/initrd"#;
+        let record = |inherited: &[&str], dropped: &[&str]| InheritanceRecord {
+            inherited: inherited.iter().map(|x| x.to_string()).collect(),
+            dropped: dropped.iter().map(|x| x.to_string()).collect(),
+        };
+        let test_cases = [
+            ("all", "BOOT_IMAGE=/vmlinuz root=/dev/sdb1 rd.luks.uuid=1234 console=ttyS0 quiet 'a b' initrd=/initrd.img ",
+             record(&["BOOT_IMAGE", "root", "rd.luks.uuid", "console", "quiet", "'a b'", "initrd"], &[])),
+            ("none", "", record(&[], &["BOOT_IMAGE", "root", "rd.luks.uuid", "console", "quiet", "'a b'", "initrd"])),
+            ("allow:console,qu?et", "console=ttyS0 quiet ", record(&["console", "quiet"], &["BOOT_IMAGE", "root", "rd.luks.uuid", "'a b'", "initrd"])),
+            ("deny:rd.*,root", "console=ttyS0 quiet 'a b' ", record(&["console", "quiet", "'a b'"], &["BOOT_IMAGE", "root", "rd.luks.uuid", "initrd"])),
+            ("deny:", "root=/dev/sdb1 rd.luks.uuid=1234 console=ttyS0 quiet 'a b' ", record(&["root", "rd.luks.uuid", "console", "quiet", "'a b'"], &["BOOT_IMAGE", "initrd"])),
+        ];
+        for (policy, command_line_expected, record_expected) in test_cases {
+            let inheritance = Inheritance::parse(policy).unwrap();
+            let kexec_args = transform_command_line(command_line, TransformParameters::default().try_into().unwrap(), &inheritance).unwrap();
+            assert_eq!((kexec_args.command_line.as_str(), &kexec_args.inheritance), (command_line_expected, &record_expected), "{}", policy);
+        }
+        assert_eq!(Inheritance::parse("deny:"), Some(Inheritance::default()));
+        assert_eq!(Inheritance::parse("allow"), None);
+
+        assert_eq!(record(&["console", "quiet"], &["BOOT_IMAGE"]).parameters(), "usbkexec.inherited=console,quiet usbkexec.dropped=BOOT_IMAGE ");
+        assert_eq!(record(&["'a b'"], &[]).parameters(), format!("usbkexec.inherited={} ", encode_additional_args("'a b'")));
+        assert_eq!(record(&[], &[]).parameters(), "");
+    }
+
+    #[test]
+    fn test_reuse_cmdline_passes_parameters_once() {
+        let command_line = r#"BOOT_IMAGE=/vmlinuz root=/dev/sdb1 console=ttyS0 'a b' usbkexec.kernel=/vmlinuz-linux usbkexec.initrd=/initrd usbkexec.args=rw"#;
+        let entry = BlsEntry {
+            linux: "/vmlinuz-linux".to_string(),
+            options: vec!["quiet".to_string()],
+            ..Default::default()
+        };
+        let transformed = transform_command_line(command_line, TransformParameters::default().try_into().unwrap(), &Inheritance::All).unwrap();
+        let from_entry = entry_kexec_args(command_line, &TransformParameters::default(), &Inheritance::All, Path::new("/boot"), &entry).unwrap();
+        for kexec_args in [transformed, from_entry] {
+            let parameters: Vec<_> = utils::split_at_unquoted_spaces(&kexec_args.command_line).collect();
+            for parameter in ["BOOT_IMAGE=/vmlinuz", "root=/dev/sdb1", "console=ttyS0", "'a b'", "rw"] {
+                assert_eq!(parameters.iter().filter(|x| **x == parameter).count(), 1, "{} in {:?}", parameter, kexec_args.command_line);
+            }
+            assert!(!kexec_args.command_line.contains("usbkexec.kernel"), "{:?}", kexec_args.command_line);
         }
     }
 
@@ -443,6 +1087,7 @@ mod tests {
             additional_args: "--add-args".to_string(),
             kernel: "--popcorn-kernel".to_string(),
             initrd: "--initramfs".to_string(),
+            dtb: "--devicetree".to_string(),
         }.try_into().unwrap();
 
         let working_command_line = "--add-args --cpio --popcorn-kernel=--casdf --initramfs --9anime.to";
@@ -452,10 +1097,56 @@ mod tests {
                     additional_args: "--cpio".to_string(),
                     kernel: "--casdf".to_string(),
                     initrd: "--9anime.to".to_string(),
+                    dtb: "usbkexec.dtb".to_string(),
                 }.try_into().unwrap(),
+                inheritance: Inheritance::default(),
+                backend: Backend::KexecTools,
+                load_options: Vec::new(),
+                on_failure: OnFailure::SwitchRoot,
             }
         );
 
+        let inherit_command_line = "--inherit=allow:console,rd.* --devicetree usbkexec.fdt --backend=syscall --load-options kexec-file-syscall --on-failure emergency --add-args --cpio --popcorn-kernel=--casdf --initramfs --9anime.to";
+        let inherit_expected = Ok(
+            Config {
+                transform_parameters: TransformParameters {
+                    additional_args: "--cpio".to_string(),
+                    kernel: "--casdf".to_string(),
+                    initrd: "--9anime.to".to_string(),
+                    dtb: "usbkexec.fdt".to_string(),
+                }.try_into().unwrap(),
+                inheritance: Inheritance::Allow(vec!["console".to_string(), "rd.*".to_string()]),
+                backend: Backend::Syscall,
+                load_options: vec![LoadOption::KexecFileSyscall],
+                on_failure: OnFailure::Emergency,
+            }
+        );
+
+        let partial_reuse_command_line = "--inherit=deny:quiet --load-options=reuse-cmdline --add-args --cpio --popcorn-kernel=--casdf --initramfs --9anime.to";
+        let partial_reuse_expected = Err(
+            SizeBasedContainer::from_single(
+                ParseArgsError::ReuseCmdlineWithoutInheritAll
+            ).try_into().unwrap()
+        );
+
+        let invalid_inherit_command_line = "--add-args --cpio --popcorn-kernel=--casdf --initramfs --9anime.to --inherit some";
+        let invalid_inherit_expected = Err(
+            SizeBasedContainer::from_single(
+                ParseArgsError::InvalidInheritance {
+                    value: "some".to_string(),
+                }
+            ).try_into().unwrap()
+        );
+
+        let invalid_on_failure_command_line = "--add-args --cpio --popcorn-kernel=--casdf --initramfs --9anime.to --on-failure=reboot";
+        let invalid_on_failure_expected = Err(
+            SizeBasedContainer::from_single(
+                ParseArgsError::InvalidOnFailure {
+                    value: "reboot".to_string(),
+                }
+            ).try_into().unwrap()
+        );
+
         let excessive_args_command_line = "--add-args --cpio --add-rgs --popcorn-kernel=--casdf --initramfs --9anime.to";
         let excessive_args_expected = Err(
             SizeBasedContainer::from_single(
@@ -499,6 +1190,15 @@ mod tests {
             ).try_into().unwrap()
         );
 
+        let invalid_load_options_command_line = "--add-args --cpio --popcorn-kernel=--casdf --initramfs --9anime.to --load-options=reuse-cmdline,vga";
+        let invalid_load_options_expected = Err(
+            SizeBasedContainer::from_single(
+                ParseArgsError::LoadOption(LoadOptionError::UnknownOption {
+                    name: "vga".to_string(),
+                })
+            ).try_into().unwrap()
+        );
+
         for (command_line, expected) in [
             (working_command_line, working_expected),
             (excessive_args_command_line, excessive_args_expected),
@@ -506,6 +1206,11 @@ mod tests {
             (key_without_value_command_line, key_without_value_expected),
             (missing_options_command_line, missing_options_expected),
             (same_value_command_line, same_value_expected),
+            (inherit_command_line, inherit_expected),
+            (partial_reuse_command_line, partial_reuse_expected),
+            (invalid_inherit_command_line, invalid_inherit_expected),
+            (invalid_on_failure_command_line, invalid_on_failure_expected),
+            (invalid_load_options_command_line, invalid_load_options_expected),
         ] {
             assert_eq!(parse_args(command_line.split_whitespace().map(|x| x.to_string()), option_names.clone()), expected);
         }
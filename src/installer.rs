@@ -0,0 +1,457 @@
+//! Installs the files of this program onto the machine.
+//!
+//! Every installed file is described by an entry in [`FILES`]. Apart from this program itself,
+//! the contents of every file are embedded into the program at compile time, so the program can
+//! be run from anywhere, e.g. from a package build directory.
+//!
+//! Every installation records the paths and digests of the installed files in a state file.
+//! The state file is used to tell files that were modified locally apart from files that are
+//! merely outdated, and by the `uninstall` subcommand to remove only files that are unchanged.
+
+use std::{fs, io::Write, os::unix::fs::{MetadataExt, PermissionsExt}, path::{Path, PathBuf}, process::{Command, Stdio}};
+
+use anyhow::{Result, bail};
+
+use crate::{cli, manifest::{self, Manifest, ManifestEntry}, units};
+
+/// Where the contents of an installed file come from.
+#[derive(Clone, Copy, Debug)]
+pub enum Source {
+    /// The contents are embedded in this program.
+    /// The string `@prefix@` in the contents is replaced with the installation prefix.
+    Embedded(&'static [u8]),
+    /// The file is this program itself.
+    CurrentExe,
+    /// The contents are generated when installing.
+    Generated(fn() -> String),
+}
+
+/// A file to install.
+#[derive(Clone, Copy, Debug)]
+pub struct InstallFile {
+    pub source: Source,
+    /// The absolute path to install the file to.
+    /// The string `{prefix}` is replaced with the installation prefix.
+    pub destination: &'static str,
+    pub mode: u32,
+    pub owner: u32,
+    pub group: u32,
+}
+
+const fn root_file(source: Source, destination: &'static str, mode: u32) -> InstallFile {
+    InstallFile { source, destination, mode, owner: 0, group: 0 }
+}
+macro_rules! embedded {
+    ($path:literal) => {
+        Source::Embedded(include_bytes!(concat!("actual_stuff/", $path)))
+    };
+}
+
+/// The state file recording every installed file, in the format of a [`Manifest`] with
+/// absolute paths.
+pub const STATE_FILE: &str = "/var/lib/usb-boot/installed.manifest";
+
+/// Every file installed by the `install` subcommand.
+pub const FILES: &[InstallFile] = &[
+    root_file(Source::CurrentExe, "{prefix}/bin/usb-boot", 0o755),
+    root_file(embedded!("update_usb_boot"), "{prefix}/bin/update_usb_boot", 0o755),
+    root_file(embedded!("kexec_into_real_kernel"), "/etc/usb-boot/kexec_into_real_kernel", 0o755),
+    root_file(embedded!("mkinitcpio_hooks/usb-boot"), "/etc/initcpio/install/usb-boot", 0o644),
+    root_file(embedded!("mkinitcpio_runtime_hooks/usb-boot"), "/etc/initcpio/hooks/usb-boot", 0o644),
+    root_file(embedded!("mkinitcpio_hooks/user_accounts_base"), "/etc/initcpio/install/user_accounts_base", 0o644),
+    root_file(embedded!("mkinitcpio_hooks/disable_root_password"), "/etc/initcpio/install/disable_root_password", 0o644),
+    root_file(embedded!("mkinitcpio_hooks/mirror_root_password"), "/etc/initcpio/install/mirror_root_password", 0o644),
+    root_file(embedded!("usb-boot.preset"), "/etc/mkinitcpio.d/usb-boot.preset", 0o644),
+    root_file(Source::Generated(|| units::kexec_unit(&Default::default())), "/etc/usb-boot/initrd-kexec-real-kernel.service", 0o644),
+    root_file(Source::Generated(units::switch_root_drop_in), "/etc/usb-boot/initrd-switch-root.service.d/usb_initramfs.conf", 0o644),
+    root_file(Source::Generated(|| units::keys_file(&Default::default())), "/etc/usb-boot/keys.conf", 0o644),
+    root_file(embedded!("usb-boot-audit.service"), "{prefix}/lib/systemd/system/usb-boot-audit.service", 0o644),
+    root_file(embedded!("usb-boot-stage1-check.service"), "{prefix}/lib/systemd/system/usb-boot-stage1-check.service", 0o644),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstallOptions {
+    /// Directory that every file is installed under, for packaging.
+    pub destdir: PathBuf,
+    pub prefix: String,
+    /// Overwrite files even if they were modified locally.
+    pub force: bool,
+    /// Only show what would change, without installing anything.
+    pub diff_only: bool,
+}
+impl Default for InstallOptions {
+    fn default() -> Self {
+        InstallOptions {
+            destdir: PathBuf::from("/"),
+            prefix: "/usr/local".to_string(),
+            force: false,
+            diff_only: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// The file does not exist yet.
+    Create,
+    /// The file exists, but its contents or metadata differ from the file to install.
+    Update {
+        contents_differ: bool,
+        /// The existing contents differ from the contents recorded in the state file at the last
+        /// installation, or the file was not installed by this program.
+        locally_modified: bool,
+    },
+    Unchanged,
+}
+
+/// A file from [`FILES`], resolved for a particular installation.
+#[derive(Clone, Debug)]
+pub struct PlannedFile {
+    /// The installed path, without the destdir.
+    pub path: PathBuf,
+    /// The installed path, under the destdir.
+    pub full_path: PathBuf,
+    pub contents: Vec<u8>,
+    pub mode: u32,
+    pub owner: u32,
+    pub group: u32,
+    pub action: Action,
+}
+
+/// Whether file ownership can be, and should be, set.
+fn can_change_owner() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Returns `path` under `destdir`.
+fn under_destdir(destdir: &Path, path: &Path) -> PathBuf {
+    destdir.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Reads the state file under `destdir`.
+/// Returns an empty state if nothing was installed yet.
+pub fn read_state(destdir: &Path) -> Result<Manifest> {
+    let path = under_destdir(destdir, Path::new(STATE_FILE));
+    match fs::read_to_string(&path) {
+        Ok(x) => Ok(Manifest::parse_absolute(&x)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Manifest::default()),
+        Err(e) => bail!("failed to read the state file \"{}\": {}", path.display(), e),
+    }
+}
+
+/// Writes the state file under `destdir`, or removes it if `state` is empty.
+fn write_state(destdir: &Path, state: &Manifest) -> Result<()> {
+    let path = under_destdir(destdir, Path::new(STATE_FILE));
+    if state.entries.is_empty() {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => return Ok(()),
+        }
+    }
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(&path, state.to_string())?;
+    Ok(())
+}
+
+/// Returns true if the file at `full_path` does not match the entry recorded for it.
+fn differs_from_record(full_path: &Path, record: Option<&ManifestEntry>) -> Result<bool> {
+    let record = match record {
+        Some(x) => x,
+        None => return Ok(true),
+    };
+    let (size, sha256) = manifest::hash_file(full_path, false)?;
+    Ok(size != record.size || sha256 != record.sha256)
+}
+
+/// Works out what has to be done to install each of `files`.
+pub fn plan(files: &[InstallFile], options: &InstallOptions) -> Result<Vec<PlannedFile>> {
+    let state = read_state(&options.destdir)?;
+    let mut planned = Vec::new();
+    for file in files {
+        let path = PathBuf::from(file.destination.replace("{prefix}", &options.prefix));
+        let full_path = under_destdir(&options.destdir, &path);
+        let contents = match file.source {
+            Source::Embedded(contents) => match std::str::from_utf8(contents) {
+                Ok(text) => text.replace("@prefix@", &options.prefix).into_bytes(),
+                Err(_) => contents.to_vec(),
+            },
+            Source::CurrentExe => fs::read("/proc/self/exe")?,
+            Source::Generated(generate) => generate().into_bytes(),
+        };
+
+        let action = match fs::symlink_metadata(&full_path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Action::Create,
+            Err(e) => bail!("failed to inspect \"{}\": {}", full_path.display(), e),
+            Ok(metadata) => {
+                let contents_differ = !metadata.is_file() || fs::read(&full_path)? != contents;
+                let mode_differs = metadata.mode() & 0o7777 != file.mode;
+                let owner_differs = can_change_owner() && (metadata.uid(), metadata.gid()) != (file.owner, file.group);
+                if contents_differ || mode_differs || owner_differs {
+                    let record = state.entries.iter().find(|x| x.path == path);
+                    Action::Update {
+                        contents_differ,
+                        locally_modified: contents_differ && differs_from_record(&full_path, record)?,
+                    }
+                }
+                else {
+                    Action::Unchanged
+                }
+            },
+        };
+
+        planned.push(PlannedFile {
+            path,
+            full_path,
+            contents,
+            mode: file.mode,
+            owner: file.owner,
+            group: file.group,
+            action,
+        });
+    }
+    Ok(planned)
+}
+
+/// Returns the planned files whose local modifications would be lost by overwriting them.
+pub fn overwritten_files(plan: &[PlannedFile]) -> Vec<&PlannedFile> {
+    plan.iter()
+        .filter(|x| matches!(x.action, Action::Update { locally_modified: true, .. }))
+        .collect()
+}
+
+/// Prints a unified diff between the existing file and the file that would be installed.
+fn show_diff(file: &PlannedFile) -> Result<()> {
+    if std::str::from_utf8(&file.contents).is_err() {
+        println!("Binary file {} differs", file.path.display());
+        return Ok(());
+    }
+    let old = match file.action {
+        Action::Create => Path::new("/dev/null"),
+        _ => &file.full_path,
+    };
+    let label = file.path.display().to_string();
+
+    let mut child = Command::new("diff")
+        .args(["-u", "--label", &label, "--label", &label])
+        .arg(old)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(&file.contents)?;
+    // diff exits with 1 when the files differ, and 2 on trouble.
+    if child.wait()?.code() == Some(2) {
+        bail!("failed to diff \"{}\"", file.full_path.display());
+    }
+    Ok(())
+}
+
+/// Writes a planned file to its destination, replacing any existing file atomically.
+fn write_file(file: &PlannedFile) -> Result<()> {
+    let parent = file.full_path.parent().unwrap();
+    fs::create_dir_all(parent)?;
+
+    let mut temporary = file.full_path.clone().into_os_string();
+    temporary.push(".usb-boot-new");
+    let temporary = PathBuf::from(temporary);
+    fs::write(&temporary, &file.contents)?;
+    fs::set_permissions(&temporary, fs::Permissions::from_mode(file.mode))?;
+    if can_change_owner() {
+        std::os::unix::fs::chown(&temporary, Some(file.owner), Some(file.group))?;
+    }
+    fs::rename(&temporary, &file.full_path)?;
+    Ok(())
+}
+
+/// Installs `files` according to `options`.
+/// Files that have not changed are skipped.
+///
+/// # Errors:
+///   If any locally modified file would be overwritten and `options.force` is false,
+///   nothing is installed and an error listing those files is returned.
+pub fn install(files: &[InstallFile], options: &InstallOptions) -> Result<()> {
+    let plan = plan(files, options)?;
+
+    if options.diff_only {
+        for file in plan.iter().filter(|x| x.action != Action::Unchanged) {
+            show_diff(file)?;
+        }
+        return Ok(());
+    }
+
+    let overwritten = overwritten_files(&plan);
+    if !overwritten.is_empty() && !options.force {
+        for file in &overwritten {
+            eprintln!("{} was modified locally", file.full_path.display());
+        }
+        bail!("refusing to overwrite {} modified files. Use --diff to see the changes, and --force to overwrite them", overwritten.len());
+    }
+
+    for file in &plan {
+        let verb = match file.action {
+            Action::Unchanged => {
+                println!("unchanged {}", file.full_path.display());
+                continue;
+            },
+            Action::Create => "installed",
+            Action::Update { .. } => "updated",
+        };
+        write_file(file)
+            .map_err(|e| anyhow::anyhow!("failed to install \"{}\": {}", file.full_path.display(), e))?;
+        println!("{} {}", verb, file.full_path.display());
+    }
+
+    // Record the installed files. Files recorded by a previous installation that are no longer
+    // installed stay recorded, so that they can still be uninstalled.
+    let mut state = read_state(&options.destdir)?;
+    state.entries.retain(|x| !plan.iter().any(|file| file.path == x.path));
+    for file in &plan {
+        let (size, sha256) = manifest::hash_reader(&mut file.contents.as_slice())?;
+        state.entries.push(ManifestEntry {
+            path: file.path.clone(),
+            size,
+            sha256,
+        });
+    }
+    state.entries.sort_by(|a, b| a.path.cmp(&b.path));
+    write_state(&options.destdir, &state)
+}
+
+/// Removes every file recorded in the state file under `destdir` that is unchanged since it was
+/// installed. Modified files are kept, reported, and stay recorded in the state file.
+/// Returns the number of files kept because they were modified.
+pub fn uninstall(destdir: &Path) -> Result<usize> {
+    let state = read_state(destdir)?;
+    let mut kept = Vec::new();
+    for entry in state.entries {
+        let full_path = under_destdir(destdir, &entry.path);
+        if !full_path.exists() {
+            println!("already removed {}", full_path.display());
+            continue;
+        }
+        if differs_from_record(&full_path, Some(&entry))? {
+            eprintln!("warning: keeping {}, it was modified after it was installed", full_path.display());
+            kept.push(entry);
+            continue;
+        }
+        fs::remove_file(&full_path)
+            .map_err(|e| anyhow::anyhow!("failed to remove \"{}\": {}", full_path.display(), e))?;
+        println!("removed {}", full_path.display());
+    }
+
+    let kept_count = kept.len();
+    write_state(destdir, &Manifest { entries: kept })?;
+    Ok(kept_count)
+}
+
+/// Entry point of the `install` subcommand.
+///
+/// Usage: `install [--destdir <dir>] [--prefix <prefix>] [--diff] [--force]`
+pub fn install_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        flags: &["--diff", "--force"],
+        options: &["--destdir", "--prefix"],
+        ..Default::default()
+    })?;
+    let defaults = InstallOptions::default();
+    let options = InstallOptions {
+        destdir: args.value("--destdir").map_or(defaults.destdir, PathBuf::from),
+        prefix: args.value("--prefix").map_or(defaults.prefix, |x| x.trim_end_matches('/').to_string()),
+        force: args.flag("--force"),
+        diff_only: args.flag("--diff"),
+    };
+
+    if args.value("--destdir").is_none() && !options.diff_only && !can_change_owner() {
+        bail!("please run as root");
+    }
+    install(FILES, &options)
+}
+
+/// Entry point of the `uninstall` subcommand.
+///
+/// Usage: `uninstall [--destdir <dir>]`
+pub fn uninstall_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        options: &["--destdir"],
+        ..Default::default()
+    })?;
+    let destdir = args.value("--destdir").map_or(InstallOptions::default().destdir, PathBuf::from);
+
+    if args.value("--destdir").is_none() && !can_change_owner() {
+        bail!("please run as root");
+    }
+    let kept = uninstall(&destdir)?;
+    if kept != 0 {
+        bail!("{} modified files were not removed", kept);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDirectory;
+
+    const TEST_FILES: &[InstallFile] = &[
+        root_file(Source::Embedded(b"prefix is @prefix@\n"), "{prefix}/bin/script", 0o755),
+        root_file(Source::Embedded(b"config\n"), "/etc/usb-boot/config", 0o644),
+    ];
+
+    /// Returns options installing into a new destdir, which is removed when the returned
+    /// [`TestDirectory`] is dropped.
+    fn test_options(name: &str) -> (TestDirectory, InstallOptions) {
+        let destdir = TestDirectory::new(name);
+        let options = InstallOptions {
+            destdir: destdir.path().to_path_buf(),
+            prefix: "/usr".to_string(),
+            ..Default::default()
+        };
+        (destdir, options)
+    }
+
+    #[test]
+    fn test_install() {
+        let (_destdir, options) = test_options("install");
+        let actions = |options| plan(TEST_FILES, options).unwrap().iter().map(|x| x.action).collect::<Vec<_>>();
+
+        assert_eq!(actions(&options), [Action::Create, Action::Create]);
+        install(TEST_FILES, &options).unwrap();
+        let script = options.destdir.join("usr/bin/script");
+        assert_eq!(fs::read_to_string(&script).unwrap(), "prefix is /usr\n");
+        assert_eq!(fs::metadata(&script).unwrap().mode() & 0o7777, 0o755);
+        assert_eq!(actions(&options), [Action::Unchanged, Action::Unchanged]);
+
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o700)).unwrap();
+        fs::write(options.destdir.join("etc/usb-boot/config"), "modified\n").unwrap();
+        assert_eq!(actions(&options), [
+            Action::Update { contents_differ: false, locally_modified: false },
+            Action::Update { contents_differ: true, locally_modified: true },
+        ]);
+        assert!(install(TEST_FILES, &options).is_err());
+        assert_eq!(fs::read_to_string(options.destdir.join("etc/usb-boot/config")).unwrap(), "modified\n");
+
+        install(TEST_FILES, &InstallOptions { force: true, ..options.clone() }).unwrap();
+        assert_eq!(actions(&options), [Action::Unchanged, Action::Unchanged]);
+
+        // A file that is only outdated, not modified locally, is updated without --force.
+        let updated_files = &[TEST_FILES[0], root_file(Source::Embedded(b"new config\n"), "/etc/usb-boot/config", 0o644)];
+        assert_eq!(plan(updated_files, &options).unwrap()[1].action, Action::Update { contents_differ: true, locally_modified: false });
+        install(updated_files, &options).unwrap();
+    }
+
+    #[test]
+    fn test_uninstall() {
+        let (_destdir, options) = test_options("uninstall");
+        install(TEST_FILES, &options).unwrap();
+        let config = options.destdir.join("etc/usb-boot/config");
+        fs::write(&config, "modified\n").unwrap();
+
+        assert_eq!(uninstall(&options.destdir).unwrap(), 1);
+        assert!(!options.destdir.join("usr/bin/script").exists());
+        assert!(config.exists());
+        assert_eq!(read_state(&options.destdir).unwrap().entries.len(), 1);
+
+        fs::remove_file(&config).unwrap();
+        assert_eq!(uninstall(&options.destdir).unwrap(), 0);
+        assert!(!options.destdir.join(STATE_FILE.trim_start_matches('/')).exists());
+    }
+}
@@ -0,0 +1,220 @@
+//! Kernel images and device trees for the architectures the runner boots.
+//!
+//! kexec wants the kernel in the form the architecture boots it: a bzImage on x86, an `Image`
+//! on arm64 and riscv64, a zImage on 32-bit arm. arm64 and riscv64 kernels are often installed
+//! compressed, as `Image.gz` or as an EFI zboot image, which is a small PE image whose payload
+//! is the compressed `Image`. [`decompress`] unpacks both, and [`validate`] checks that the
+//! result is a kernel of the running architecture, before kexec fails with a less helpful
+//! message. `kexec_file_load` takes zboot images as they are, and under lockdown only signed
+//! ones, so [`prepare`] keeps them compressed for it.
+//!
+//! The device tree for the second stage is given with `usbkexec.dtb=<path>` on the kernel
+//! command line, a path in the real system like the `devicetree` of a boot loader entry, or
+//! [`FIRMWARE_DTB`] for the device tree the first stage was booted with.
+
+use std::io::Read;
+
+use crate::{cpio::{self, Compression}, pe};
+
+/// The value of the dtb parameter that selects the device tree of the first stage.
+pub const FIRMWARE_DTB: &str = "firmware";
+/// Where the kernel exposes the device tree it was booted with.
+pub const FIRMWARE_DTB_PATH: &str = "/sys/firmware/fdt";
+
+/// The header of an EFI zboot image: `MZ`, then `zimg`, the offset and size of the payload, and
+/// the name of its compression.
+const ZBOOT_MAGIC_OFFSET: usize = 4;
+const ZBOOT_MAGIC: &[u8; 4] = b"zimg";
+const ZBOOT_PAYLOAD_OFFSET: usize = 8;
+const ZBOOT_PAYLOAD_SIZE: usize = 12;
+const ZBOOT_COMPRESSION: usize = 0x18;
+const ZBOOT_COMPRESSION_LENGTH: usize = 32;
+
+/// The big-endian magic of a flattened device tree, followed by its total size.
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+/// Where each architecture marks its kernel image, and the magic.
+const IMAGE_MAGICS: [(&str, usize, &[u8]); 5] = [
+    ("x86_64", 0x202, b"HdrS"),
+    ("x86", 0x202, b"HdrS"),
+    ("aarch64", 0x38, b"ARM\x64"),
+    ("riscv64", 0x38, b"RSC\x05"),
+    ("arm", 0x24, &[0x18, 0x28, 0x6f, 0x01]),
+];
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ImageError {
+    #[error("the kernel is not a kernel image for {arch}")]
+    WrongArchitecture {
+        arch: String,
+    },
+    #[error("the zboot image is truncated")]
+    TruncatedZboot,
+    #[error("the kernel is compressed with {compression}, which is not supported")]
+    UnsupportedCompression {
+        compression: String,
+    },
+    #[error("failed to decompress the {compression} kernel: {reason}")]
+    Decompress {
+        compression: Compression,
+        reason: String,
+    },
+    #[error("the device tree is not a flattened device tree")]
+    InvalidDtb,
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+/// Whether `data` is an EFI zboot image.
+pub fn is_zboot(data: &[u8]) -> bool {
+    data.starts_with(b"MZ") && data.get(ZBOOT_MAGIC_OFFSET..ZBOOT_MAGIC_OFFSET + 4) == Some(ZBOOT_MAGIC)
+}
+
+/// Decompresses a zboot image or a kernel compressed as a whole, like `Image.gz`. Returns None
+/// if the kernel is not compressed.
+pub fn decompress(data: &[u8]) -> Result<Option<Vec<u8>>, ImageError> {
+    let (payload, compression) = if is_zboot(data) {
+        let offset = pe::read_u32(data, ZBOOT_PAYLOAD_OFFSET).ok_or(ImageError::TruncatedZboot)? as usize;
+        let size = pe::read_u32(data, ZBOOT_PAYLOAD_SIZE).ok_or(ImageError::TruncatedZboot)? as usize;
+        let name = data.get(ZBOOT_COMPRESSION..ZBOOT_COMPRESSION + ZBOOT_COMPRESSION_LENGTH).ok_or(ImageError::TruncatedZboot)?;
+        let name = String::from_utf8_lossy(&name[..name.iter().position(|x| *x == 0).unwrap_or(name.len())]).into_owned();
+        let compression = match name.as_str() {
+            "gzip" => Compression::Gzip,
+            "xz" => Compression::Xz,
+            "lzma" => Compression::Lzma,
+            "zstd" => Compression::Zstd,
+            _ => return Err(ImageError::UnsupportedCompression { compression: name }),
+        };
+        let payload = offset.checked_add(size).and_then(|end| data.get(offset..end)).ok_or(ImageError::TruncatedZboot)?;
+        (payload, compression)
+    } else {
+        match Compression::detect(data) {
+            Some(compression) if compression != Compression::None => (data, compression),
+            _ => match Compression::detect_unsupported(data) {
+                Some(name) => return Err(ImageError::UnsupportedCompression { compression: name.to_string() }),
+                None => return Ok(None),
+            },
+        }
+    };
+    let mut decompressed = Vec::new();
+    cpio::decoder(payload, compression)
+        .and_then(|mut x| x.read_to_end(&mut decompressed))
+        .map_err(|e| ImageError::Decompress { compression, reason: e.to_string() })?;
+    Ok(Some(decompressed))
+}
+
+/// Checks that an uncompressed kernel image is for `arch`, like [`std::env::consts::ARCH`].
+/// Kernels for other architectures are not checked.
+pub fn validate(data: &[u8], arch: &str) -> Result<(), ImageError> {
+    match IMAGE_MAGICS.iter().find(|(x, _, _)| *x == arch) {
+        Some((_, offset, magic)) if data.get(*offset..offset + magic.len()) != Some(*magic) => {
+            Err(ImageError::WrongArchitecture { arch: arch.to_string() })
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Decompresses the kernel and checks that it is a kernel for `arch`. Returns the kernel to load,
+/// or None if it is loaded as it is: if it is not compressed, or if it is a zboot image and
+/// `keep_zboot` is set, because it is loaded with `kexec_file_load`. Unpacking a zboot image
+/// strips its signature.
+pub fn prepare(data: &[u8], arch: &str, keep_zboot: bool) -> Result<Option<Vec<u8>>, ImageError> {
+    let decompressed = decompress(data)?;
+    validate(decompressed.as_deref().unwrap_or(data), arch)?;
+    if keep_zboot && is_zboot(data) {
+        return Ok(None);
+    }
+    Ok(decompressed)
+}
+
+/// Checks that `data` is a whole flattened device tree.
+pub fn validate_dtb(data: &[u8]) -> Result<(), ImageError> {
+    match (read_u32_be(data, 0), read_u32_be(data, 4)) {
+        (Some(FDT_MAGIC), Some(size)) if size as usize <= data.len() => Ok(()),
+        _ => Err(ImageError::InvalidDtb),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn arm64_image() -> Vec<u8> {
+        let mut image = vec![0; 0x100];
+        image[0x38..0x3c].copy_from_slice(b"ARM\x64");
+        image
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zboot(compression: &str, payload: &[u8]) -> Vec<u8> {
+        let mut image = vec![0; 0x40];
+        image[..2].copy_from_slice(b"MZ");
+        image[ZBOOT_MAGIC_OFFSET..ZBOOT_MAGIC_OFFSET + 4].copy_from_slice(ZBOOT_MAGIC);
+        pe::write_u32(&mut image, ZBOOT_PAYLOAD_OFFSET, 0x40);
+        pe::write_u32(&mut image, ZBOOT_PAYLOAD_SIZE, payload.len() as u32);
+        image[ZBOOT_COMPRESSION..ZBOOT_COMPRESSION + compression.len()].copy_from_slice(compression.as_bytes());
+        image.extend_from_slice(payload);
+        image
+    }
+
+    #[test]
+    fn test_decompress() {
+        let image = arm64_image();
+        let mut truncated = zboot("gzip", &gzip(&image));
+        truncated.pop();
+        let test_cases = [
+            (image.clone(), Ok(None)),
+            (gzip(&image), Ok(Some(image.clone()))),
+            (zboot("gzip", &gzip(&image)), Ok(Some(image.clone()))),
+            (zboot("lz4", b"payload"), Err(ImageError::UnsupportedCompression { compression: "lz4".to_string() })),
+            (b"BZh91AY&SY".to_vec(), Err(ImageError::UnsupportedCompression { compression: "bzip2".to_string() })),
+            (truncated, Err(ImageError::TruncatedZboot)),
+        ];
+        for (data, expected) in test_cases {
+            assert_eq!(decompress(&data), expected);
+        }
+        assert!(matches!(decompress(&[0x1f, 0x8b, 0x08, 0x00]), Err(ImageError::Decompress { compression: Compression::Gzip, .. })));
+    }
+
+    #[test]
+    fn test_prepare() {
+        let image = arm64_image();
+        let zboot_image = zboot("gzip", &gzip(&image));
+        let test_cases = [
+            (image.clone(), false, Ok(None)),
+            (gzip(&image), false, Ok(Some(image.clone()))),
+            (gzip(&image), true, Ok(Some(image.clone()))),
+            (zboot_image.clone(), false, Ok(Some(image.clone()))),
+            (zboot_image.clone(), true, Ok(None)),
+            // The payload of a kept zboot image is still checked.
+            (zboot("gzip", &gzip(&[0; 0x100])), true, Err(ImageError::WrongArchitecture { arch: "aarch64".to_string() })),
+        ];
+        for (data, keep_zboot, expected) in test_cases {
+            assert_eq!(prepare(&data, "aarch64", keep_zboot), expected, "{}", keep_zboot);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let image = arm64_image();
+        assert_eq!(validate(&image, "aarch64"), Ok(()));
+        assert_eq!(validate(&image, "x86_64"), Err(ImageError::WrongArchitecture { arch: "x86_64".to_string() }));
+        assert_eq!(validate(&image, "s390x"), Ok(()));
+        assert_eq!(validate(&gzip(&image), "aarch64"), Err(ImageError::WrongArchitecture { arch: "aarch64".to_string() }));
+
+        let mut dtb = vec![0xd0, 0x0d, 0xfe, 0xed, 0, 0, 0, 0x10];
+        dtb.resize(0x10, 0);
+        assert_eq!(validate_dtb(&dtb), Ok(()));
+        assert_eq!(validate_dtb(&dtb[..0xf]), Err(ImageError::InvalidDtb));
+        assert_eq!(validate_dtb(&image), Err(ImageError::InvalidDtb));
+    }
+}
@@ -0,0 +1,262 @@
+//! Loading the real kernel, with kexec-tools or with the `kexec_file_load` syscall directly.
+//!
+//! The runner loads with `kexec -l` by default. [`Backend::Syscall`] calls `kexec_file_load`
+//! itself, so kexec-tools is not needed in the initramfs. [`LoadOption`]s change how the kernel
+//! is loaded, like the options of the same names of kexec-tools, and are checked against the
+//! backend and the architecture before anything is loaded, since kexec-tools ignores some of
+//! them silently.
+
+use std::{ffi::CString, fs::File, os::fd::AsRawFd, process::Command};
+
+use anyhow::{Result, bail};
+
+use crate::overlay;
+
+/// The flag of `kexec_file_load` for loading without an initrd.
+const KEXEC_FILE_NO_INITRAMFS: libc::c_ulong = 0x4;
+
+/// Architectures whose kexec-tools purgatory can reset the VGA card and print to the consoles.
+const CONSOLE_ARCHITECTURES: [&str; 2] = ["x86", "x86_64"];
+
+/// How the kernel is loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Backend {
+    /// `kexec -l`.
+    #[default]
+    KexecTools,
+    /// The `kexec_file_load` syscall.
+    Syscall,
+}
+
+/// An option for loading the kernel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadOption {
+    /// Resets the VGA card to text mode before jumping to the kernel.
+    ResetVga,
+    /// Prints the progress of the purgatory to the VGA console.
+    ConsoleVga,
+    /// Prints the progress of the purgatory to the serial console.
+    ConsoleSerial,
+    /// Passes the command line of the first stage, without the transform parameters, on to the
+    /// second stage. The runner requires `--inherit=all` with it, which already copies every
+    /// such parameter, so it applies to both backends and nothing is added twice.
+    ReuseCmdline,
+    /// Makes kexec-tools load with `kexec_file_load` instead of `kexec_load`.
+    KexecFileSyscall,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum LoadOptionError {
+    #[error("unknown kexec load option \"{name}\", expected reset-vga, console-vga, console-serial, reuse-cmdline or kexec-file-syscall")]
+    UnknownOption {
+        name: String,
+    },
+    #[error("unknown kexec backend \"{name}\", expected kexec-tools or syscall")]
+    UnknownBackend {
+        name: String,
+    },
+    #[error("the kexec load option {option} is not supported on {arch}")]
+    UnsupportedArchitecture {
+        option: &'static str,
+        arch: String,
+    },
+    #[error("the {backend} backend cannot honour {option}")]
+    Unsupported {
+        option: &'static str,
+        backend: &'static str,
+    },
+    #[error("the kexec load option {option} cannot be used with {other}")]
+    Conflict {
+        option: &'static str,
+        other: &'static str,
+    },
+}
+
+impl Backend {
+    pub fn parse(name: &str) -> Result<Backend, LoadOptionError> {
+        match name {
+            "kexec-tools" => Ok(Backend::KexecTools),
+            "syscall" => Ok(Backend::Syscall),
+            _ => Err(LoadOptionError::UnknownBackend { name: name.to_string() }),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::KexecTools => "kexec-tools",
+            Backend::Syscall => "syscall",
+        }
+    }
+}
+
+impl LoadOption {
+    const ALL: [LoadOption; 5] = [
+        LoadOption::ResetVga,
+        LoadOption::ConsoleVga,
+        LoadOption::ConsoleSerial,
+        LoadOption::ReuseCmdline,
+        LoadOption::KexecFileSyscall,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LoadOption::ResetVga => "reset-vga",
+            LoadOption::ConsoleVga => "console-vga",
+            LoadOption::ConsoleSerial => "console-serial",
+            LoadOption::ReuseCmdline => "reuse-cmdline",
+            LoadOption::KexecFileSyscall => "kexec-file-syscall",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<LoadOption, LoadOptionError> {
+        LoadOption::ALL.into_iter()
+            .find(|x| x.name() == name)
+            .ok_or_else(|| LoadOptionError::UnknownOption { name: name.to_string() })
+    }
+
+    /// Parses a comma separated list of options, like `reset-vga,console-serial`. Options given
+    /// more than once are only kept once.
+    pub fn parse_list(value: &str) -> Result<Vec<LoadOption>, LoadOptionError> {
+        let mut options = Vec::new();
+        for name in value.split(',').filter(|x| !x.is_empty()) {
+            let option = LoadOption::parse(name)?;
+            if !options.contains(&option) {
+                options.push(option);
+            }
+        }
+        Ok(options)
+    }
+
+    /// The argument of kexec-tools for the option.
+    fn kexec_tools_arg(self) -> String {
+        format!("--{}", self.name())
+    }
+}
+
+/// Checks that the backend can honour every option on `arch`, like
+/// [`std::env::consts::ARCH`].
+pub fn check(backend: Backend, options: &[LoadOption], arch: &str) -> Result<(), LoadOptionError> {
+    for option in options {
+        match option {
+            LoadOption::ResetVga | LoadOption::ConsoleVga | LoadOption::ConsoleSerial => {
+                if !CONSOLE_ARCHITECTURES.contains(&arch) {
+                    return Err(LoadOptionError::UnsupportedArchitecture { option: option.name(), arch: arch.to_string() });
+                }
+                // The purgatory of kexec_file_load is the kernel's, which does neither.
+                if backend == Backend::Syscall {
+                    return Err(LoadOptionError::Unsupported { option: option.name(), backend: backend.name() });
+                }
+                if options.contains(&LoadOption::KexecFileSyscall) {
+                    return Err(LoadOptionError::Conflict { option: option.name(), other: LoadOption::KexecFileSyscall.name() });
+                }
+            },
+            LoadOption::ReuseCmdline | LoadOption::KexecFileSyscall => {},
+        }
+    }
+    Ok(())
+}
+
+/// Whether the kernel is loaded with `kexec_file_load`, by the syscall backend or by kexec-tools
+/// with [`LoadOption::KexecFileSyscall`], rather than with `kexec_load`.
+pub fn uses_kexec_file_load(backend: Backend, options: &[LoadOption]) -> bool {
+    backend == Backend::Syscall || options.contains(&LoadOption::KexecFileSyscall)
+}
+
+/// What to load.
+pub struct LoadRequest<'a> {
+    pub kernel: &'a File,
+    pub initrd: Option<&'a File>,
+    pub dtb: Option<&'a File>,
+    /// The command line of the second stage, which already holds the reused one.
+    pub command_line: &'a str,
+}
+
+/// Loads the kernel with `backend`, without executing it. The options must have been checked
+/// with [`check`].
+pub fn load(backend: Backend, options: &[LoadOption], request: &LoadRequest) -> Result<()> {
+    match backend {
+        Backend::KexecTools => {
+            let mut command = Command::new("kexec");
+            command.args(["-l", &overlay::fd_path(request.kernel).to_string_lossy()]);
+            // The reused command line is already in the command line.
+            command.args(options.iter().filter(|x| **x != LoadOption::ReuseCmdline).map(|x| x.kexec_tools_arg()));
+            if let Some(initrd) = request.initrd {
+                command.arg(format!("--initrd={}", overlay::fd_path(initrd).display()));
+            }
+            if let Some(dtb) = request.dtb {
+                command.arg(format!("--dtb={}", overlay::fd_path(dtb).display()));
+            }
+            let status = command
+                .arg(format!("--append={}", request.command_line))
+                .status()?;
+            if !status.success() {
+                bail!("kexec {}", status);
+            }
+            Ok(())
+        },
+        Backend::Syscall => {
+            // kexec_file_load takes the device tree of the running kernel.
+            if request.dtb.is_some() {
+                return Err(LoadOptionError::Unsupported { option: "a device tree", backend: backend.name() }.into());
+            }
+            kexec_file_load(request.kernel, request.initrd, request.command_line)
+        },
+    }
+}
+
+fn kexec_file_load(kernel: &File, initrd: Option<&File>, command_line: &str) -> Result<()> {
+    let command_line = CString::new(command_line)?;
+    let flags = if initrd.is_none() { KEXEC_FILE_NO_INITRAMFS } else { 0 };
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_kexec_file_load,
+            kernel.as_raw_fd(),
+            initrd.map_or(-1, |x| x.as_raw_fd()),
+            // The length includes the terminating NUL.
+            command_line.as_bytes_with_nul().len(),
+            command_line.as_ptr(),
+            flags,
+        )
+    };
+    if result < 0 {
+        bail!("kexec_file_load failed: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        use LoadOption::*;
+        let test_cases = [
+            (Backend::KexecTools, vec![ResetVga, ConsoleSerial, ReuseCmdline], "x86_64", Ok(())),
+            (Backend::Syscall, vec![ReuseCmdline, KexecFileSyscall], "aarch64", Ok(())),
+            (Backend::KexecTools, vec![ConsoleVga], "aarch64", Err(LoadOptionError::UnsupportedArchitecture { option: "console-vga", arch: "aarch64".to_string() })),
+            (Backend::Syscall, vec![ResetVga], "x86_64", Err(LoadOptionError::Unsupported { option: "reset-vga", backend: "syscall" })),
+            (Backend::KexecTools, vec![KexecFileSyscall, ConsoleSerial], "x86", Err(LoadOptionError::Conflict { option: "console-serial", other: "kexec-file-syscall" })),
+        ];
+        for (backend, options, arch, expected) in test_cases {
+            assert_eq!(check(backend, &options, arch), expected, "{:?} {:?}", backend, options);
+        }
+    }
+
+    #[test]
+    fn test_uses_kexec_file_load() {
+        assert!(!uses_kexec_file_load(Backend::KexecTools, &[LoadOption::ReuseCmdline]));
+        assert!(uses_kexec_file_load(Backend::KexecTools, &[LoadOption::KexecFileSyscall]));
+        assert!(uses_kexec_file_load(Backend::Syscall, &[]));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(LoadOption::parse_list("reset-vga,,console-serial,reset-vga"), Ok(vec![LoadOption::ResetVga, LoadOption::ConsoleSerial]));
+        assert_eq!(LoadOption::parse_list(""), Ok(vec![]));
+        assert_eq!(LoadOption::parse_list("reset-vga,vga"), Err(LoadOptionError::UnknownOption { name: "vga".to_string() }));
+        assert_eq!(Backend::parse("syscall"), Ok(Backend::Syscall));
+        assert_eq!(Backend::parse("kexec"), Err(LoadOptionError::UnknownBackend { name: "kexec".to_string() }));
+        assert_eq!(LoadOption::KexecFileSyscall.kexec_tools_arg(), "--kexec-file-syscall");
+    }
+}
@@ -1,3 +1,24 @@
 mod utils;
+pub mod audit;
+pub mod authenticode;
+pub mod bls;
+pub mod bootloader;
+pub mod cli;
+pub mod cmdline;
+pub mod cpio;
+pub mod generator;
 pub mod initramfs_kexec_runner;
-
+pub mod installer;
+pub mod kernel_image;
+pub mod kexec_load;
+pub mod luks;
+pub mod manifest;
+pub mod overlay;
+pub mod pe;
+pub mod resolve;
+pub mod stage1;
+pub mod standalone;
+pub mod target_root;
+pub mod template;
+pub mod uki;
+pub mod units;
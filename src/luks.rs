@@ -0,0 +1,240 @@
+//! Single-passphrase boot: forwarding the LUKS key of the real root to the real kernel.
+//!
+//! Normally, the passphrase of an encrypted root is asked in the usb initramfs, to read the real
+//! kernel, and again in the initramfs of the real kernel. With `usbkexec.luks=<device>` on the
+//! kernel command line, the runner asks for the passphrase (reusing the one cached by
+//! systemd-cryptsetup, if any), opens or checks the volume with it, and hands it to the real
+//! kernel as the keyfile `/etc/cryptsetup-keys.d/<name>.key` in the overlay (see
+//! [`crate::overlay`]), with mode 0400. systemd-cryptsetup in the real initramfs uses that keyfile
+//! for the volume `<name>` without asking, and the keyfile disappears with the initramfs when it
+//! switches root.
+//!
+//! `<name>` is given with `usbkexec.luks.name=`, and defaults to `luks-<UUID>`, the name systemd
+//! uses for volumes given with `rd.luks.uuid=`.
+//! Every copy of the passphrase in this program is zeroized when it is dropped.
+
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Result, bail};
+use zeroize::Zeroizing;
+
+use crate::{cmdline::KernelCommandLine, overlay::{OverlayFile, Source}};
+
+/// The kernel parameter that enables forwarding the key, and gives the LUKS device.
+pub const LUKS_KEY: &str = "usbkexec.luks";
+/// The kernel parameter with the name of the volume in the real initramfs.
+pub const NAME_KEY: &str = "usbkexec.luks.name";
+/// Where systemd-cryptsetup looks for keyfiles of volumes without one in crypttab.
+pub const KEY_DIRECTORY: &str = "/etc/cryptsetup-keys.d";
+/// The longest passphrase that is read, like the limit cryptsetup has for interactive
+/// passphrases.
+const MAX_PASSPHRASE_LENGTH: usize = 512;
+
+/// The LUKS volume whose key is forwarded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuksVolume {
+    pub device: PathBuf,
+    /// The name of the volume in the initramfs of the real kernel.
+    pub name: String,
+}
+impl LuksVolume {
+    /// Returns the volume given on the kernel command line, or None if forwarding the key is not
+    /// requested.
+    pub fn from_command_line(command_line: &KernelCommandLine) -> Result<Option<LuksVolume>> {
+        let device = match command_line.get(LUKS_KEY) {
+            Some(x) => device_path(x),
+            None => return Ok(None),
+        };
+        let name = match command_line.get(NAME_KEY) {
+            Some(x) => x.to_string(),
+            None => format!("luks-{}", luks_uuid(&device)?),
+        };
+        Ok(Some(LuksVolume { device, name }))
+    }
+
+    /// The path of the keyfile in the initramfs of the real kernel.
+    pub fn key_file(&self) -> PathBuf {
+        Path::new(KEY_DIRECTORY).join(format!("{}.key", self.name))
+    }
+}
+
+/// Converts a device given like in crypttab, e.g. `UUID=...`, into a path.
+pub(crate) fn device_path(device: &str) -> PathBuf {
+    let by = [("UUID=", "by-uuid"), ("PARTUUID=", "by-partuuid"), ("LABEL=", "by-label"), ("PARTLABEL=", "by-partlabel")];
+    for (prefix, directory) in by {
+        if let Some(value) = device.strip_prefix(prefix) {
+            return Path::new("/dev/disk").join(directory).join(value);
+        }
+    }
+    PathBuf::from(device)
+}
+
+fn luks_uuid(device: &Path) -> Result<String> {
+    let output = Command::new("cryptsetup").arg("luksUUID").arg(device).output()?;
+    if !output.status.success() {
+        bail!("\"{}\" is not a LUKS device", device.display());
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Asks for the passphrase of the volume, or takes the one cached in the kernel keyring by
+/// systemd-cryptsetup.
+pub fn ask_passphrase(volume: &LuksVolume) -> Result<Zeroizing<Vec<u8>>> {
+    let mut child = Command::new("systemd-ask-password")
+        .args(["--id=usb-boot", "--keyname=cryptsetup", "--accept-cached", "--timeout=0"])
+        .arg(format!("Please enter passphrase for disk {}:", volume.device.display()))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let result = read_passphrase(child.stdout.take().unwrap());
+    if result.is_err() {
+        let _ = child.kill();
+    }
+    let success = child.wait()?.success();
+    let passphrase = result?;
+    if !success {
+        bail!("failed to ask for the passphrase");
+    }
+    Ok(passphrase)
+}
+
+/// Reads a passphrase ended by an optional newline, like systemd-ask-password prints it.
+/// The buffer is allocated once, so that no copy of the passphrase is left behind by growing it.
+fn read_passphrase(mut input: impl Read) -> Result<Zeroizing<Vec<u8>>> {
+    // One more byte than the passphrase and its newline tells a longer passphrase apart.
+    let mut passphrase = Zeroizing::new(vec![0u8; MAX_PASSPHRASE_LENGTH + 2]);
+    let mut length = 0;
+    while length < passphrase.len() {
+        match input.read(&mut passphrase[length..]) {
+            Ok(0) => break,
+            Ok(n) => length += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    passphrase.truncate(length);
+    // systemd-ask-password ends the passphrase with a newline, which is not part of it.
+    if passphrase.last() == Some(&b'\n') {
+        passphrase.pop();
+    }
+    if passphrase.len() > MAX_PASSPHRASE_LENGTH {
+        bail!("the passphrase is longer than {} bytes", MAX_PASSPHRASE_LENGTH);
+    }
+    Ok(passphrase)
+}
+
+/// Opens the volume with the passphrase, under its name. If it is already open, only checks the
+/// passphrase, so that a wrong passphrase is not forwarded.
+pub fn unlock(volume: &LuksVolume, passphrase: &[u8]) -> Result<()> {
+    let mut command = Command::new("cryptsetup");
+    if Path::new("/dev/mapper").join(&volume.name).exists() {
+        command.args(["open", "--test-passphrase"]).arg(&volume.device);
+    }
+    else {
+        command.arg("open").arg(&volume.device).arg(&volume.name);
+    }
+    let mut child = command
+        .arg("--key-file=-")
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(passphrase)?;
+    if !child.wait()?.success() {
+        bail!("failed to unlock \"{}\" with the passphrase", volume.device.display());
+    }
+    Ok(())
+}
+
+/// Returns the keyfile of the volume, with the passphrase as contents, to put into the overlay.
+pub fn key_file_overlay(volume: &LuksVolume, passphrase: Zeroizing<Vec<u8>>) -> OverlayFile {
+    OverlayFile { source: Source::Secret(passphrase), destination: volume.key_file(), mode: 0o400 }
+}
+
+/// Asks for the passphrase, unlocks the volume with it, and returns the keyfile to put into the
+/// overlay.
+pub fn forward_key(volume: &LuksVolume) -> Result<OverlayFile> {
+    let passphrase = ask_passphrase(volume)?;
+    unlock(volume, &passphrase)?;
+    Ok(key_file_overlay(volume, passphrase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use crate::{cpio, overlay, utils::TestDirectory};
+
+    #[test]
+    fn test_from_command_line() {
+        let command_line = KernelCommandLine::parse("root=/dev/mapper/root usbkexec.luks=UUID=1234 usbkexec.luks.name=root");
+        let volume = LuksVolume::from_command_line(&command_line).unwrap().unwrap();
+        assert_eq!(volume, LuksVolume { device: "/dev/disk/by-uuid/1234".into(), name: "root".to_string() });
+        assert_eq!(volume.key_file(), Path::new("/etc/cryptsetup-keys.d/root.key"));
+        assert_eq!(LuksVolume::from_command_line(&KernelCommandLine::parse("root=/dev/sda2")).unwrap(), None);
+        assert_eq!(device_path("/dev/sda2"), Path::new("/dev/sda2"));
+    }
+
+    #[test]
+    fn test_read_passphrase() {
+        let longest = vec![b'x'; MAX_PASSPHRASE_LENGTH];
+        assert_eq!(*read_passphrase(&b"correct horse\n"[..]).unwrap(), b"correct horse");
+        assert_eq!(*read_passphrase(&b"no newline"[..]).unwrap(), b"no newline");
+        assert_eq!(*read_passphrase(&[&longest[..], b"\n"].concat()[..]).unwrap(), longest);
+        let error = read_passphrase(&[&longest[..], b"x\n"].concat()[..]).unwrap_err();
+        assert_eq!(error.to_string(), "the passphrase is longer than 512 bytes");
+        assert!(read_passphrase(&vec![b'x'; 4096][..]).is_err());
+    }
+
+    /// Unlocks a LUKS image on a loop device and checks that the forwarded keyfile opens it.
+    #[test]
+    #[ignore = "needs root, cryptsetup and loop devices"]
+    fn test_forward_key_loopback() {
+        let test_directory = TestDirectory::new("luks");
+        let directory = test_directory.path();
+        let image = directory.join("luks.img");
+        fs::File::create(&image).unwrap().set_len(32 << 20).unwrap();
+        let passphrase = Zeroizing::new(b"correct horse".to_vec());
+
+        let mut format = Command::new("cryptsetup")
+            .args(["luksFormat", "--batch-mode", "--type=luks2", "--pbkdf=pbkdf2", "--pbkdf-force-iterations=1000", "--key-file=-"])
+            .arg(&image)
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        format.stdin.take().unwrap().write_all(&passphrase).unwrap();
+        assert!(format.wait().unwrap().success());
+
+        let volume = LuksVolume { device: image.clone(), name: format!("usb-boot-test-{}", std::process::id()) };
+        assert!(unlock(&volume, b"wrong").is_err());
+        unlock(&volume, &passphrase).unwrap();
+        // Already open, so only the passphrase is checked.
+        unlock(&volume, &passphrase).unwrap();
+        assert!(Command::new("cryptsetup").arg("close").arg(&volume.name).status().unwrap().success());
+
+        let key_file = key_file_overlay(&volume, passphrase.clone());
+        let mut archive = Vec::new();
+        overlay::write_overlay(&[key_file], &mut archive).unwrap();
+        let mut reader = cpio::InitramfsReader::new(archive.as_slice());
+        let mut key = Zeroizing::new(Vec::new());
+        while let Some(entry) = reader.next_entry().unwrap() {
+            if entry.name == volume.key_file().strip_prefix("/").unwrap() {
+                assert_eq!(entry.mode, cpio::S_IFREG | 0o400);
+                reader.read_to_end(&mut key).unwrap();
+            }
+        }
+        let key_path = directory.join("root.key");
+        fs::write(&key_path, &*key).unwrap();
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o400)).unwrap();
+        let status = Command::new("cryptsetup")
+            .args(["open", "--test-passphrase"])
+            .arg(format!("--key-file={}", key_path.display()))
+            .arg(&image)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
@@ -1,28 +1,79 @@
-//! This program is used for kexec-ing into the real kernel when
-//! booting from usb into encrypted root, while in first initrd.
+//! This program contains the tools used for booting from usb into encrypted root.
+//! The first argument selects the subcommand to run:
 //!
-//! The program does these things:
+//! - `kexec`: Used for kexec-ing into the real kernel, while in first initrd.
 //!     1. Reads kernel command line from /proc/cmdline
-//!     2. Parses command line and alters it according to specific parameters
-//!     3. Runs kexec -l
+//!     2. Parses command line and alters it according to specific parameters, or takes the
+//!        kernel, initrds and options from a boot loader entry or a UKI of the real system
+//!        and verifies the Authenticode signature of the kernel or UKI, if there are trusted
+//!        certificates in /etc/usb-boot/certs or `usbkexec.verify=on` is given
+//!        Only the parameters of the first stage allowed by `--inherit` are passed on, by default
+//!        all but `BOOT_IMAGE=` and `initrd=`, and which were dropped is recorded with
+//!        `usbkexec.inherited=` and `usbkexec.dropped=`.
+//!        Compressed kernels, like `Image.gz` or zboot images, are decompressed, except zboot
+//!        images loaded with `kexec_file_load`, and the device tree of a boot loader entry or
+//!        `usbkexec.dtb=` is passed on, see the `kernel_image` module.
+//!     3. Runs kexec -l, with the files listed in /etc/usb-boot/overlay.conf appended to the initrd,
+//!        or calls kexec_file_load itself with `--backend=syscall`, see the `kexec_load` module
 //!     4. Runs systemctl kexec
+//!
+//!   If kexec is disabled with `usbkexec=off` or fails, it switches root with the usb kernel instead.
+//!   With `--on-failure=emergency`, a failure of kexec starts emergency mode instead.
+//! - `kexec-standalone`: Like `kexec`, but for initramfs images without systemd. Unlocks and
+//!   mounts the root itself, then runs kexec -e.
+//! - `build-uki`: Builds the first stage as a Unified Kernel Image, with the `usbkexec.*`
+//!   parameters in its command line.
+//! - `gen-bootloader`: Generates a systemd-boot entry, syslinux.cfg or grub.cfg menu entry for
+//!   the first stage, and checks that kexec would reconstruct the desired second stage from it.
+//! - `expand-cmdline`: Expands the placeholders like `${first:root}` in a command line, see
+//!   the `template` module.
+//! - `manifest`: Writes a checksum manifest of the boot files.
+//! - `verify`: Verifies the boot files on the usb against their manifest.
+//! - `audit`: Compares the usb with the state recorded at the last trusted update, to detect
+//!   tampering.
+//! - `check-stage1`: Checks the fingerprint of the first stage handed over by `kexec`, on the
+//!   real system.
+//! - `install`: Installs this program, the mkinitcpio hooks and preset, the systemd units and the
+//!   updater onto this machine.
+//! - `uninstall`: Removes the installed files that were not modified since they were installed.
+//! - `gen-units`: Generates the systemd units used in the initramfs, or checks them.
+//!
+//! When run under the name `usb-boot-generator`, this program runs as a systemd generator in the
+//! initramfs instead, and enables kexec only when it is requested on the kernel command line.
 
-use std::env;
+use std::{env, path::Path};
 
 use anyhow::{Result, bail};
-use usb_boot_kexec::initramfs_kexec_runner::TransformParameters;
+use usb_boot_kexec::{audit, bootloader, generator, initramfs_kexec_runner, installer, manifest, stage1, standalone, template, uki, units};
 
 fn main() -> Result<()> {
-    /*
-    let config = usb_boot_kexec::parse_args(env::args(), TransformParameters {
-        additional_args: "--additional_args".to_string(),
-        kernel: "--kernel".to_string(),
-        initrd: "--initrd".to_string(),
-    }.try_into().unwrap())?;
+    let mut args = env::args();
+    let program = args.next().unwrap_or_default();
+    if Path::new(&program).file_name() == Some(generator::GENERATOR_NAME.as_ref()) {
+        return generator::generator_main(args);
+    }
 
-    usb_boot_kexec::run(config)?;
-    Ok(())
-    */
+    let subcommand = match args.next() {
+        Some(x) => x,
+        None => bail!("no subcommand given"),
+    };
 
-    bail!("test error message");
+    match subcommand.as_str() {
+        "kexec" => {
+            let config = initramfs_kexec_runner::parse_args(args, initramfs_kexec_runner::option_names().try_into().unwrap())?;
+            initramfs_kexec_runner::run_or_switch_root(config)
+        },
+        "kexec-standalone" => standalone::standalone_command(args),
+        "build-uki" => uki::build_uki_command(args),
+        "gen-bootloader" => bootloader::gen_bootloader_command(args),
+        "expand-cmdline" => template::expand_cmdline_command(args),
+        "manifest" => manifest::manifest_command(args),
+        "verify" => manifest::verify_command(args),
+        "audit" => audit::audit_command(args),
+        "check-stage1" => stage1::check_command(args),
+        "install" => installer::install_command(args),
+        "uninstall" => installer::uninstall_command(args),
+        "gen-units" => units::gen_units_command(args),
+        _ => bail!("unknown subcommand: {}", subcommand),
+    }
 }
@@ -0,0 +1,428 @@
+//! Checksum manifests for the boot files on the usb.
+//!
+//! A manifest lists every file in a directory with its size and SHA-256 digest.
+//! The updater writes a manifest of the source directory next to the boot files on the usb,
+//! and verifies the copied files against it. Because cheap flash can corrupt data silently,
+//! files are verified with their page cache dropped, so the data is actually read back from
+//! the device instead of from memory.
+//!
+//! Each line of a manifest has the form `<sha256>  <size>  <path>`, where path is relative to the
+//! directory the manifest describes.
+
+use std::{fmt, fs::{self, File}, io::{self, Read}, os::unix::io::AsRawFd, path::{Component, Path, PathBuf}};
+
+use anyhow::{Result, bail};
+use sha2::{Digest, Sha256};
+
+use crate::{cli, utils};
+
+/// The file name of the manifest, placed in the directory it describes.
+pub const MANIFEST_FILE_NAME: &str = "usb-boot.manifest";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    /// Path of the file, relative to the directory the manifest describes.
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Manifest {
+    /// Entries sorted by path.
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ParseManifestError {
+    #[error("line {line}: expected \"<sha256>  <size>  <path>\"")]
+    MalformedLine {
+        line: usize,
+    },
+    #[error("line {line}: invalid sha256 digest")]
+    InvalidDigest {
+        line: usize,
+    },
+    #[error("line {line}: invalid size")]
+    InvalidSize {
+        line: usize,
+    },
+    #[error("line {line}: the path must be relative, or absolute in a state file, and must not contain \"..\" or \".\"")]
+    InvalidPath {
+        line: usize,
+    },
+}
+
+/// A difference between a file on disk and its manifest entry.
+#[derive(thiserror::Error, Debug)]
+pub enum Mismatch {
+    #[error("{path}: file is missing")]
+    Missing {
+        path: String,
+    },
+    #[error("{path}: expected size {expected}, but file has size {actual}")]
+    SizeDiffers {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("{path}: sha256 digest does not match")]
+    DigestDiffers {
+        path: String,
+    },
+    #[error("{path}: failed to read file: {error}")]
+    Unreadable {
+        path: String,
+        error: io::Error,
+    },
+}
+
+/// A difference between two manifests, as found by [`Manifest::differences`].
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum Difference {
+    #[error("{path}: file was added")]
+    Added {
+        path: String,
+    },
+    #[error("{path}: file was removed")]
+    Removed {
+        path: String,
+    },
+    #[error("{path}: file was modified")]
+    Modified {
+        path: String,
+    },
+}
+
+impl Manifest {
+    /// Creates a manifest of every regular file under `directory`, recursively.
+    /// Symbolic links are followed, matching `cp --dereference`.
+    /// A manifest file already present at the top of the directory is not included.
+    pub fn from_directory(directory: &Path) -> io::Result<Manifest> {
+        let mut entries = Vec::new();
+        collect_entries(directory, Path::new(""), &mut entries)?;
+        entries.retain(|x: &ManifestEntry| x.path != Path::new(MANIFEST_FILE_NAME));
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Manifest { entries })
+    }
+
+    /// Parses a manifest whose paths are relative to the directory it describes. Paths that are
+    /// absolute or contain `..` are rejected, since they would lead out of the directory.
+    pub fn parse(manifest: &str) -> Result<Manifest, ParseManifestError> {
+        Manifest::parse_paths(manifest, false)
+    }
+
+    /// Parses a manifest with absolute paths, like the state file of the installer.
+    pub fn parse_absolute(manifest: &str) -> Result<Manifest, ParseManifestError> {
+        Manifest::parse_paths(manifest, true)
+    }
+
+    fn parse_paths(manifest: &str, absolute: bool) -> Result<Manifest, ParseManifestError> {
+        let mut entries = Vec::new();
+        for (i, line) in manifest.lines().enumerate() {
+            let line_number = i + 1;
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(3, "  ");
+            let (digest, size, path) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(digest), Some(size), Some(path)) if !path.is_empty() => (digest, size, path),
+                _ => return Err(ParseManifestError::MalformedLine { line: line_number }),
+            };
+            let sha256 = utils::from_hex(digest)
+                .and_then(|x| <[u8; 32]>::try_from(x).ok())
+                .ok_or(ParseManifestError::InvalidDigest { line: line_number })?;
+            let size = size.parse()
+                .map_err(|_| ParseManifestError::InvalidSize { line: line_number })?;
+            // The path is joined to the directory being verified, so it must stay inside it.
+            let path = PathBuf::from(path);
+            let mut components = path.components().peekable();
+            if absolute && components.next_if_eq(&Component::RootDir).is_none() {
+                return Err(ParseManifestError::InvalidPath { line: line_number });
+            }
+            if !components.all(|x| matches!(x, Component::Normal(_))) {
+                return Err(ParseManifestError::InvalidPath { line: line_number });
+            }
+            entries.push(ManifestEntry {
+                path,
+                size,
+                sha256,
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Manifest { entries })
+    }
+
+    /// Reads the manifest stored in `directory`.
+    pub fn read_from(directory: &Path) -> Result<Manifest> {
+        let path = directory.join(MANIFEST_FILE_NAME);
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("failed to read manifest \"{}\": {}", path.display(), e))?;
+        Ok(Manifest::parse(&contents)?)
+    }
+
+    /// Checks every file listed in the manifest against the files in `directory`.
+    /// If `drop_cache` is true, each file's page cache is dropped before reading it, so the
+    /// contents are read from the underlying device.
+    /// Returns every mismatch found. An empty vector means all files match.
+    pub fn verify(&self, directory: &Path, drop_cache: bool) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        for entry in &self.entries {
+            let path = entry.path.display().to_string();
+            let full_path = directory.join(&entry.path);
+            if !full_path.exists() {
+                mismatches.push(Mismatch::Missing { path });
+                continue;
+            }
+            match hash_file(&full_path, drop_cache) {
+                Ok((size, _)) if size != entry.size => mismatches.push(Mismatch::SizeDiffers {
+                    path,
+                    expected: entry.size,
+                    actual: size,
+                }),
+                Ok((_, sha256)) if sha256 != entry.sha256 => mismatches.push(Mismatch::DigestDiffers { path }),
+                Ok(_) => (),
+                Err(error) => mismatches.push(Mismatch::Unreadable { path, error }),
+            }
+        }
+        mismatches
+    }
+
+    /// Compares this manifest with a newer manifest, `other`.
+    /// Returns every file that was added, removed or modified, sorted by path.
+    pub fn differences(&self, other: &Manifest) -> Vec<Difference> {
+        let mut differences = Vec::new();
+        let mut old = self.entries.iter().peekable();
+        let mut new = other.entries.iter().peekable();
+        loop {
+            let ordering = match (old.peek(), new.peek()) {
+                (None, None) => break,
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some(a), Some(b)) => a.path.cmp(&b.path),
+            };
+            match ordering {
+                std::cmp::Ordering::Less => {
+                    let path = old.next().unwrap().path.display().to_string();
+                    differences.push(Difference::Removed { path });
+                },
+                std::cmp::Ordering::Greater => {
+                    let path = new.next().unwrap().path.display().to_string();
+                    differences.push(Difference::Added { path });
+                },
+                std::cmp::Ordering::Equal => {
+                    let (a, b) = (old.next().unwrap(), new.next().unwrap());
+                    if a != b {
+                        differences.push(Difference::Modified { path: a.path.display().to_string() });
+                    }
+                },
+            }
+        }
+        differences
+    }
+}
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(f, "{}  {}  {}", utils::to_hex(&entry.sha256), entry.size, entry.path.display())?;
+        }
+        Ok(())
+    }
+}
+
+fn collect_entries(root: &Path, relative: &Path, entries: &mut Vec<ManifestEntry>) -> io::Result<()> {
+    for dir_entry in fs::read_dir(root.join(relative))? {
+        let relative_path = relative.join(dir_entry?.file_name());
+        let full_path = root.join(&relative_path);
+        // Follow symbolic links.
+        let metadata = fs::metadata(&full_path)?;
+        if metadata.is_dir() {
+            collect_entries(root, &relative_path, entries)?;
+        }
+        else if metadata.is_file() {
+            // Paths are stored one per line, so they must not contain newlines.
+            if relative_path.to_str().is_none_or(|x| x.contains('\n')) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("file name is not valid utf-8 or contains a newline: {:?}", relative_path),
+                ));
+            }
+            let (size, sha256) = hash_file(&full_path, false)?;
+            entries.push(ManifestEntry {
+                path: relative_path,
+                size,
+                sha256,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Computes the size and SHA-256 digest of a file.
+/// If `drop_cache` is true, any dirty pages of the file are written out and the file's
+/// page cache is dropped first, so the data is read back from the device.
+pub fn hash_file(path: &Path, drop_cache: bool) -> io::Result<(u64, [u8; 32])> {
+    let mut file = File::open(path)?;
+    if drop_cache {
+        drop_page_cache(&file)?;
+    }
+    hash_reader(&mut file)
+}
+
+/// Computes the number of bytes read and SHA-256 digest of everything in `reader`.
+pub fn hash_reader(reader: &mut impl Read) -> io::Result<(u64, [u8; 32])> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    let mut size = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((size, hasher.finalize().into()))
+}
+
+fn drop_page_cache(file: &File) -> io::Result<()> {
+    // The pages have to be clean before they can be dropped.
+    file.sync_data()?;
+    let result = unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED)
+    };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result));
+    }
+    Ok(())
+}
+
+/// Entry point of the `manifest` subcommand.
+///
+/// Usage: `manifest [--output <file>] <directory>`
+///
+/// Writes a manifest of `directory` to `<file>`, or to the manifest file inside `directory`
+/// if `--output` is not given.
+pub fn manifest_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        options: &["--output"],
+        positionals: (1, 1),
+        ..Default::default()
+    })?;
+    let directory = Path::new(&args.positionals()[0]);
+    let output = match args.value("--output") {
+        Some(x) => PathBuf::from(x),
+        None => directory.join(MANIFEST_FILE_NAME),
+    };
+
+    let manifest = Manifest::from_directory(directory)?;
+    fs::write(&output, manifest.to_string())?;
+    Ok(())
+}
+
+/// Entry point of the `verify` subcommand.
+///
+/// Usage: `verify [--device <block device>] [--no-drop-cache] <directory>`
+///
+/// Verifies the files in `directory` against the manifest inside it.
+/// If `--device` is given, the device is mounted read-only first, and `directory` is
+/// interpreted relative to the root of the device.
+pub fn verify_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        flags: &["--no-drop-cache"],
+        options: &["--device"],
+        positionals: (1, 1),
+        ..Default::default()
+    })?;
+
+    let mount = match args.value("--device") {
+        Some(device) => Some(utils::TemporaryMount::read_only(Path::new(device))?),
+        None => None,
+    };
+    let directory = match &mount {
+        Some(mount) => mount.path().join(args.positionals()[0].trim_start_matches('/')),
+        None => PathBuf::from(&args.positionals()[0]),
+    };
+
+    let manifest = Manifest::read_from(&directory)?;
+    let mismatches = manifest.verify(&directory, !args.flag("--no-drop-cache"));
+    for mismatch in &mismatches {
+        eprintln!("{}", mismatch);
+    }
+    if !mismatches.is_empty() {
+        bail!("{} of {} files failed verification", mismatches.len(), manifest.entries.len());
+    }
+    println!("all {} files match the manifest", manifest.entries.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDirectory;
+
+    // sha256 of the empty string and of "hello\n".
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const HELLO_SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn test_parse_and_display() {
+        let text = format!("{}  6  sub dir/hello\n{}  0  empty\n", HELLO_SHA256, EMPTY_SHA256);
+        let manifest = Manifest::parse(&text).unwrap();
+        assert_eq!(manifest.entries[0].path, Path::new("empty"));
+        assert_eq!(manifest.entries[1].path, Path::new("sub dir/hello"));
+        assert_eq!(manifest.entries[1].size, 6);
+        assert_eq!(Manifest::parse(&manifest.to_string()), Ok(manifest));
+
+        let test_cases = [
+            ("abcd  5", ParseManifestError::MalformedLine { line: 1 }),
+            ("\nabcd  5  file", ParseManifestError::InvalidDigest { line: 2 }),
+            (&format!("{}  -1  file", EMPTY_SHA256), ParseManifestError::InvalidSize { line: 1 }),
+            (&format!("{}  0  /etc/shadow", EMPTY_SHA256), ParseManifestError::InvalidPath { line: 1 }),
+            (&format!("{}  0  boot/../../etc/shadow", EMPTY_SHA256), ParseManifestError::InvalidPath { line: 1 }),
+            (&format!("{}  0  ./vmlinuz", EMPTY_SHA256), ParseManifestError::InvalidPath { line: 1 }),
+        ];
+        for (text, expected) in test_cases {
+            assert_eq!(Manifest::parse(text), Err(expected));
+        }
+
+        let state = format!("{}  0  /usr/bin/usb-boot\n", EMPTY_SHA256);
+        assert_eq!(Manifest::parse_absolute(&state).unwrap().entries[0].path, Path::new("/usr/bin/usb-boot"));
+        assert_eq!(Manifest::parse(&state), Err(ParseManifestError::InvalidPath { line: 1 }));
+        assert_eq!(Manifest::parse_absolute(&format!("{}  0  usr/bin/usb-boot", EMPTY_SHA256)), Err(ParseManifestError::InvalidPath { line: 1 }));
+        assert_eq!(Manifest::parse_absolute(&format!("{}  0  /usr/../etc/shadow", EMPTY_SHA256)), Err(ParseManifestError::InvalidPath { line: 1 }));
+    }
+
+    #[test]
+    fn test_from_directory_and_verify() {
+        let test_directory = TestDirectory::new("manifest");
+        let directory = test_directory.path();
+        fs::create_dir(directory.join("sub")).unwrap();
+        fs::write(directory.join("sub/hello"), "hello\n").unwrap();
+        fs::write(directory.join("empty"), "").unwrap();
+        fs::write(directory.join(MANIFEST_FILE_NAME), "ignored").unwrap();
+
+        let manifest = Manifest::from_directory(directory).unwrap();
+        assert_eq!(manifest.to_string(), format!("{}  0  empty\n{}  6  sub/hello\n", EMPTY_SHA256, HELLO_SHA256));
+        assert!(manifest.verify(directory, true).is_empty());
+
+        fs::write(directory.join("sub/hello"), "jello\n").unwrap();
+        fs::remove_file(directory.join("empty")).unwrap();
+        let mismatches = manifest.verify(directory, true);
+        assert!(matches!(&mismatches[..], [Mismatch::Missing { .. }, Mismatch::DigestDiffers { .. }]));
+    }
+
+    #[test]
+    fn test_differences() {
+        let old = Manifest::parse(&format!("{0}  0  a\n{0}  0  b\n{0}  0  c\n", EMPTY_SHA256)).unwrap();
+        let new = Manifest::parse(&format!("{0}  0  a\n{1}  6  c\n{0}  0  d\n", EMPTY_SHA256, HELLO_SHA256)).unwrap();
+        assert_eq!(old.differences(&old), []);
+        assert_eq!(old.differences(&new), [
+            Difference::Removed { path: "b".to_string() },
+            Difference::Modified { path: "c".to_string() },
+            Difference::Added { path: "d".to_string() },
+        ]);
+    }
+}
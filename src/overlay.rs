@@ -0,0 +1,255 @@
+//! Files handed from the usb initramfs to the initramfs of the real kernel.
+//!
+//! Before loading the real kernel, the runner builds a cpio archive of the files listed in
+//! [`OVERLAY_CONFIG`] and appends it to a copy of the initrd, in a memfd, so nothing is written to
+//! disk. The real kernel unpacks the archive over its initramfs, so the files show up there, e.g.
+//! a keyfile, a random seed or extra configuration.
+//!
+//! Every non-empty line of the config that is not a comment maps a file to a destination:
+//!
+//! ```text
+//! # <source> <destination> [<mode>]
+//! /run/usb-boot/handoff.json /etc/usb-boot/handoff.json
+//! /run/cryptsetup-keys/root.key /etc/cryptsetup-keys.d/root.key 0400
+//! ```
+//!
+//! Sources are paths in the usb initramfs, destinations are absolute paths in the initramfs of
+//! the real kernel, and modes are octal permissions, 0644 by default. Missing parent directories
+//! of destinations are created with mode 0755.
+//!
+//! Other parts of the runner add files with contents from memory, like a forwarded LUKS key (see
+//! [`crate::luks`]). Since those may be secrets, the memfd is wiped with [`wipe`] once kexec has
+//! loaded it.
+
+use std::{
+    collections::BTreeSet,
+    ffi::CString,
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
+    os::fd::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+};
+
+use zeroize::Zeroizing;
+
+use crate::cpio;
+
+/// The config listing the files of the overlay, in the usb initramfs.
+pub const OVERLAY_CONFIG: &str = "/etc/usb-boot/overlay.conf";
+const DEFAULT_MODE: u32 = 0o644;
+
+/// Where the contents of an [`OverlayFile`] come from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    /// A file in the usb initramfs.
+    File(PathBuf),
+    /// Contents in memory, which are zeroized when dropped.
+    Secret(Zeroizing<Vec<u8>>),
+}
+
+/// A file to put into the initramfs of the real kernel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverlayFile {
+    pub source: Source,
+    /// Absolute path in the initramfs of the real kernel.
+    pub destination: PathBuf,
+    /// Permissions of the file, without the file type.
+    pub mode: u32,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ParseOverlayError {
+    #[error("line {line}: expected \"<source> <destination> [<mode>]\"")]
+    MalformedLine {
+        line: usize,
+    },
+    #[error("line {line}: the destination must be an absolute path")]
+    RelativeDestination {
+        line: usize,
+    },
+    #[error("line {line}: invalid mode, expected octal permissions like 0644")]
+    InvalidMode {
+        line: usize,
+    },
+}
+
+/// Parses the overlay config. See the [module documentation](self) for the format.
+pub fn parse_config(config: &str) -> Result<Vec<OverlayFile>, ParseOverlayError> {
+    let mut files = Vec::new();
+    for (i, line) in config.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (source, destination, mode) = match fields.as_slice() {
+            [source, destination] => (source, destination, None),
+            [source, destination, mode] => (source, destination, Some(mode)),
+            _ => return Err(ParseOverlayError::MalformedLine { line: line_number }),
+        };
+        if !destination.starts_with('/') {
+            return Err(ParseOverlayError::RelativeDestination { line: line_number });
+        }
+        let mode = match mode {
+            None => DEFAULT_MODE,
+            Some(mode) => u32::from_str_radix(mode, 8)
+                .ok()
+                .filter(|x| *x <= 0o7777)
+                .ok_or(ParseOverlayError::InvalidMode { line: line_number })?,
+        };
+        files.push(OverlayFile { source: Source::File(source.into()), destination: destination.into(), mode });
+    }
+    Ok(files)
+}
+
+/// Writes a cpio archive of the files to `output`, along with their parent directories.
+pub fn write_overlay(files: &[OverlayFile], output: impl Write) -> io::Result<()> {
+    let mut writer = cpio::Writer::new(output);
+
+    let directories: BTreeSet<&Path> = files.iter()
+        .flat_map(|x| x.destination.ancestors().skip(1))
+        .filter(|x| *x != Path::new("/"))
+        .collect();
+    for directory in directories {
+        writer.add_directory(directory.strip_prefix("/").unwrap(), 0o755)?;
+    }
+
+    for file in files {
+        let name = file.destination.strip_prefix("/").unwrap();
+        match &file.source {
+            Source::File(path) => {
+                let mut source = File::open(path).map_err(|e| io::Error::new(
+                    e.kind(),
+                    format!("failed to open \"{}\": {}", path.display(), e),
+                ))?;
+                let size = source.metadata()?.len().try_into()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large for cpio"))?;
+                writer.write_entry_from_reader(&cpio::Entry::file(name, file.mode, size), &mut source)?;
+            },
+            Source::Secret(contents) => writer.write_entry(&cpio::Entry::file(name, file.mode, 0), contents)?,
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Creates a memfd containing the initrds followed by the overlay, each aligned to 4 bytes as the
+/// kernel requires. The file has to stay open while kexec reads it through [`fd_path`].
+pub fn initrd_with_overlay(initrds: &[&File], files: &[OverlayFile]) -> io::Result<File> {
+    let mut memfd = create_memfd("usb-boot-initrd")?;
+    for mut initrd in initrds.iter().copied() {
+        io::copy(&mut initrd, &mut memfd)?;
+        let length = memfd.stream_position()?;
+        memfd.write_all(&[0; 4][..((4 - length % 4) % 4) as usize])?;
+    }
+    write_overlay(files, &mut memfd)?;
+    memfd.rewind()?;
+    Ok(memfd)
+}
+
+/// Creates a memfd with the given contents, positioned at the start.
+pub(crate) fn memfd_with_contents(name: &str, contents: &[u8]) -> io::Result<File> {
+    let mut memfd = create_memfd(name)?;
+    memfd.write_all(contents)?;
+    memfd.rewind()?;
+    Ok(memfd)
+}
+
+/// A path through which other processes, like kexec, can open the file.
+pub fn fd_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/{}/fd/{}", std::process::id(), file.as_raw_fd()))
+}
+
+/// Overwrites the whole file with zeros and truncates it, so that secrets in it do not linger in
+/// memory that is freed.
+pub fn wipe(mut file: &File) -> io::Result<()> {
+    let length = file.metadata()?.len();
+    file.rewind()?;
+    io::copy(&mut io::repeat(0).take(length), &mut file)?;
+    file.sync_data()?;
+    file.set_len(0)
+}
+
+/// Reads the overlay config from [`OVERLAY_CONFIG`]. Returns an empty list if it does not exist.
+pub fn read_config() -> anyhow::Result<Vec<OverlayFile>> {
+    match fs::read_to_string(OVERLAY_CONFIG) {
+        Ok(config) => Ok(parse_config(&config)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn create_memfd(name: &str) -> io::Result<File> {
+    let name = CString::new(name).unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDirectory;
+
+    #[test]
+    fn test_parse_config() {
+        let config = "# comment\n\n/run/a /etc/a\n  /run/b   /etc/keys/b 0400  \n";
+        assert_eq!(parse_config(config), Ok(vec![
+            OverlayFile { source: Source::File("/run/a".into()), destination: "/etc/a".into(), mode: 0o644 },
+            OverlayFile { source: Source::File("/run/b".into()), destination: "/etc/keys/b".into(), mode: 0o400 },
+        ]));
+
+        let test_cases = [
+            ("/run/a", ParseOverlayError::MalformedLine { line: 1 }),
+            ("/run/a /etc/a 0644 extra", ParseOverlayError::MalformedLine { line: 1 }),
+            ("\n/run/a etc/a", ParseOverlayError::RelativeDestination { line: 2 }),
+            ("/run/a /etc/a 0999", ParseOverlayError::InvalidMode { line: 1 }),
+            ("/run/a /etc/a 10000", ParseOverlayError::InvalidMode { line: 1 }),
+        ];
+        for (config, expected) in test_cases {
+            assert_eq!(parse_config(config), Err(expected), "{}", config);
+        }
+    }
+
+    #[test]
+    fn test_initrd_with_overlay() {
+        let test_directory = TestDirectory::new("overlay");
+        let directory = test_directory.path();
+        let mut initrd = cpio::Writer::new(Vec::new());
+        initrd.add_file("init", 0o755, b"#!/bin/sh").unwrap();
+        let mut initrd = initrd.finish().unwrap();
+        // Initrds do not have to end aligned.
+        initrd.push(0);
+        fs::write(directory.join("initrd"), initrd).unwrap();
+        fs::write(directory.join("config"), "extra").unwrap();
+
+        let files = [
+            OverlayFile { source: Source::File(directory.join("config")), destination: "/etc/config".into(), mode: 0o644 },
+            OverlayFile { source: Source::Secret(Zeroizing::new(b"secret".to_vec())), destination: "/etc/keys/root.key".into(), mode: 0o400 },
+        ];
+        let mut memfd = initrd_with_overlay(&[&File::open(directory.join("initrd")).unwrap()], &files).unwrap();
+        assert!(File::open(fd_path(&memfd)).is_ok());
+
+        let mut reader = cpio::InitramfsReader::new(&mut memfd);
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            let mut data = String::new();
+            reader.read_to_string(&mut data).unwrap();
+            entries.push((entry.name.to_str().unwrap().to_string(), entry.mode, data));
+        }
+        assert_eq!(entries, [
+            ("init".to_string(), cpio::S_IFREG | 0o755, "#!/bin/sh".to_string()),
+            ("etc".to_string(), cpio::S_IFDIR | 0o755, String::new()),
+            ("etc/keys".to_string(), cpio::S_IFDIR | 0o755, String::new()),
+            ("etc/config".to_string(), cpio::S_IFREG | 0o644, "extra".to_string()),
+            ("etc/keys/root.key".to_string(), cpio::S_IFREG | 0o400, "secret".to_string()),
+        ]);
+
+        drop(reader);
+        wipe(&memfd).unwrap();
+        assert_eq!(memfd.metadata().unwrap().len(), 0);
+    }
+}
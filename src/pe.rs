@@ -0,0 +1,359 @@
+//! Reading and extending the section table of PE/COFF images, like EFI kernels and Unified Kernel
+//! Images.
+//!
+//! Only what is needed to find the sections is parsed. The contents of a section are its raw
+//! data, cut to its virtual size, since the raw data is padded to the file alignment.
+//! [`add_sections`] appends sections to an image, which is how a UKI is built from an EFI stub.
+
+/// Offset of the field with the offset of the PE header, in the DOS header.
+const PE_OFFSET_FIELD: usize = 0x3c;
+const PE_SIGNATURE: &[u8; 4] = b"PE\0\0";
+const COFF_HEADER_SIZE: usize = 20;
+const SECTION_HEADER_SIZE: usize = 40;
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+/// Offsets of fields in the optional header, which are the same for PE32 and PE32+.
+const SECTION_ALIGNMENT_OFFSET: usize = 32;
+const FILE_ALIGNMENT_OFFSET: usize = 36;
+const SIZE_OF_IMAGE_OFFSET: usize = 56;
+const SIZE_OF_HEADERS_OFFSET: usize = 60;
+const CHECKSUM_OFFSET: usize = 64;
+/// The index of the certificate table, with the Authenticode signatures, in the data directories.
+const CERTIFICATE_TABLE: usize = 4;
+/// Initialized, read-only data.
+const DATA_SECTION_CHARACTERISTICS: u32 = 0x40000040;
+
+/// A section of a PE image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub virtual_size: u32,
+    pub virtual_address: u32,
+    pub raw_size: u32,
+    pub raw_offset: u32,
+}
+
+/// The headers of a PE image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeImage {
+    /// The machine type, e.g. 0x8664 for x86_64 and 0xaa64 for arm64.
+    pub machine: u16,
+    /// The offset of the optional header in the file.
+    pub optional_header_offset: usize,
+    pub optional_header_size: usize,
+    pub sections: Vec<Section>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum PeError {
+    #[error("not a PE image, there is no MZ signature")]
+    NotPe,
+    #[error("the PE header at offset {offset} is invalid")]
+    InvalidHeader {
+        offset: usize,
+    },
+    #[error("the section \"{name}\" lies outside of the file")]
+    SectionOutOfBounds {
+        name: String,
+    },
+    #[error("the optional header is missing or has an unknown format")]
+    InvalidOptionalHeader,
+    #[error("the image is signed, and adding sections would break the signature")]
+    Signed,
+    #[error("there is no room for more section headers in the headers of the image")]
+    NoRoomForSections,
+    #[error("the section name \"{name}\" is longer than 8 bytes")]
+    NameTooLong {
+        name: String,
+    },
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().unwrap()))
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment.max(1)) * alignment.max(1)
+}
+
+impl PeImage {
+    pub fn parse(data: &[u8]) -> Result<PeImage, PeError> {
+        if !data.starts_with(b"MZ") {
+            return Err(PeError::NotPe);
+        }
+        let offset = read_u32(data, PE_OFFSET_FIELD).ok_or(PeError::NotPe)? as usize;
+        let invalid = PeError::InvalidHeader { offset };
+        if data.get(offset..offset + 4) != Some(PE_SIGNATURE) {
+            return Err(invalid);
+        }
+        let coff = offset + 4;
+        let (machine, section_count, optional_header_size) = match (
+            read_u16(data, coff),
+            read_u16(data, coff + 2),
+            read_u16(data, coff + 16),
+        ) {
+            (Some(machine), Some(count), Some(size)) => (machine, count as usize, size as usize),
+            _ => return Err(invalid),
+        };
+
+        let optional_header_offset = coff + COFF_HEADER_SIZE;
+        let section_table = optional_header_offset + optional_header_size;
+        let mut sections = Vec::with_capacity(section_count);
+        for i in 0..section_count {
+            let header = data.get(section_table + i * SECTION_HEADER_SIZE..section_table + (i + 1) * SECTION_HEADER_SIZE)
+                .ok_or(PeError::InvalidHeader { offset })?;
+            // Names are padded with nulls, and are not null-terminated if they are 8 bytes long.
+            let name = &header[..8];
+            let name = String::from_utf8_lossy(&name[..name.iter().position(|x| *x == 0).unwrap_or(8)]).into_owned();
+            let field = |offset| read_u32(header, offset).unwrap();
+            sections.push(Section {
+                name,
+                virtual_size: field(8),
+                virtual_address: field(12),
+                raw_size: field(16),
+                raw_offset: field(20),
+            });
+        }
+        Ok(PeImage { machine, optional_header_offset, optional_header_size, sections })
+    }
+
+    /// The offset of the section table in the file.
+    fn section_table_offset(&self) -> usize {
+        self.optional_header_offset + self.optional_header_size
+    }
+
+    /// The offset of the checksum field in the file.
+    pub fn checksum_offset(&self) -> usize {
+        self.optional_header_offset + CHECKSUM_OFFSET
+    }
+
+    /// The size of the headers, which are followed by the raw data of the sections.
+    pub fn size_of_headers(&self, data: &[u8]) -> Result<usize, PeError> {
+        if self.optional_header_size < SIZE_OF_HEADERS_OFFSET + 4 {
+            return Err(PeError::InvalidOptionalHeader);
+        }
+        read_u32(data, self.optional_header_offset + SIZE_OF_HEADERS_OFFSET)
+            .map(|x| x as usize)
+            .ok_or(PeError::InvalidOptionalHeader)
+    }
+
+    /// The offset of the entry of the certificate table in the data directories, in the file.
+    /// The entry is the offset and size of the table, which is not mapped into memory.
+    pub fn certificate_table_entry_offset(&self, data: &[u8]) -> Result<usize, PeError> {
+        let directories = match read_u16(data, self.optional_header_offset) {
+            Some(PE32_MAGIC) => 96,
+            Some(PE32_PLUS_MAGIC) => 112,
+            _ => return Err(PeError::InvalidOptionalHeader),
+        };
+        if self.optional_header_size < directories + (CERTIFICATE_TABLE + 1) * 8 {
+            return Err(PeError::InvalidOptionalHeader);
+        }
+        Ok(self.optional_header_offset + directories + CERTIFICATE_TABLE * 8)
+    }
+
+    /// Returns the offset and size of the certificate table, or None if the image is unsigned.
+    pub fn certificate_table(&self, data: &[u8]) -> Result<Option<(usize, usize)>, PeError> {
+        let entry = self.certificate_table_entry_offset(data)?;
+        match (read_u32(data, entry), read_u32(data, entry + 4)) {
+            (Some(_), Some(0)) => Ok(None),
+            (Some(offset), Some(size)) => Ok(Some((offset as usize, size as usize))),
+            _ => Err(PeError::InvalidOptionalHeader),
+        }
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|x| x.name == name)
+    }
+
+    /// Returns the contents of the section with the given name, if there is one.
+    pub fn section_data<'a>(&self, data: &'a [u8], name: &str) -> Result<Option<&'a [u8]>, PeError> {
+        let section = match self.section(name) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let size = match section.virtual_size {
+            0 => section.raw_size,
+            virtual_size => virtual_size.min(section.raw_size),
+        };
+        let start = section.raw_offset as usize;
+        data.get(start..start + size as usize)
+            .map(Some)
+            .ok_or(PeError::SectionOutOfBounds { name: name.to_string() })
+    }
+}
+
+/// Computes the checksum of the optional header, skipping the checksum field itself.
+pub fn checksum(data: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u64 = 0;
+    for (i, chunk) in data.chunks(2).enumerate() {
+        if i * 2 == checksum_offset || i * 2 == checksum_offset + 2 {
+            continue;
+        }
+        let word = match chunk {
+            [low, high] => u16::from_le_bytes([*low, *high]),
+            [low] => *low as u16,
+            _ => unreachable!(),
+        };
+        sum += word as u64;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    (sum as u32).wrapping_add(data.len() as u32)
+}
+
+/// Appends sections with the given names and contents to an unsigned image, and updates its
+/// headers and checksum. The sections are read-only data, placed after the existing sections in
+/// the given order. The result only depends on the inputs, so builds are reproducible.
+pub fn add_sections(data: &[u8], sections: &[(&str, &[u8])]) -> Result<Vec<u8>, PeError> {
+    let image = PeImage::parse(data)?;
+    if image.optional_header_size < CHECKSUM_OFFSET + 4 {
+        return Err(PeError::InvalidOptionalHeader);
+    }
+    if image.certificate_table(data)?.is_some() {
+        return Err(PeError::Signed);
+    }
+    let field = |offset| read_u32(data, image.optional_header_offset + offset).unwrap() as usize;
+    let (section_alignment, file_alignment) = (field(SECTION_ALIGNMENT_OFFSET), field(FILE_ALIGNMENT_OFFSET));
+
+    // The new section headers have to fit before the headers end and the first section starts.
+    let headers_end = image.sections.iter()
+        .filter(|x| x.raw_size > 0)
+        .map(|x| x.raw_offset as usize)
+        .fold(field(SIZE_OF_HEADERS_OFFSET), usize::min);
+    let table = image.section_table_offset();
+    let section_count = image.sections.len() + sections.len();
+    if table + section_count * SECTION_HEADER_SIZE > headers_end || section_count > u16::MAX as usize {
+        return Err(PeError::NoRoomForSections);
+    }
+
+    let mut output = data.to_vec();
+    output.resize(align_up(output.len(), file_alignment), 0);
+    let mut virtual_address = image.sections.iter()
+        .map(|x| x.virtual_address as usize + x.virtual_size.max(x.raw_size) as usize)
+        .max()
+        .unwrap_or(headers_end);
+    virtual_address = align_up(virtual_address, section_alignment);
+    for (i, (name, contents)) in sections.iter().enumerate() {
+        if name.len() > 8 {
+            return Err(PeError::NameTooLong { name: name.to_string() });
+        }
+        let header = table + (image.sections.len() + i) * SECTION_HEADER_SIZE;
+        let raw_offset = output.len();
+        let raw_size = align_up(contents.len(), file_alignment);
+        output[header..header + SECTION_HEADER_SIZE].fill(0);
+        output[header..header + name.len()].copy_from_slice(name.as_bytes());
+        write_u32(&mut output, header + 8, contents.len() as u32);
+        write_u32(&mut output, header + 12, virtual_address as u32);
+        write_u32(&mut output, header + 16, raw_size as u32);
+        write_u32(&mut output, header + 20, raw_offset as u32);
+        write_u32(&mut output, header + 36, DATA_SECTION_CHARACTERISTICS);
+        output.extend_from_slice(contents);
+        output.resize(raw_offset + raw_size, 0);
+        virtual_address = align_up(virtual_address + contents.len(), section_alignment);
+    }
+
+    let coff = image.optional_header_offset - COFF_HEADER_SIZE;
+    write_u16(&mut output, coff + 2, section_count as u16);
+    write_u32(&mut output, image.optional_header_offset + SIZE_OF_IMAGE_OFFSET, virtual_address as u32);
+    let checksum = checksum(&output, image.checksum_offset());
+    write_u32(&mut output, image.checksum_offset(), checksum);
+    Ok(output)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds a minimal PE32+ image with the given sections, with headers that have room for 17
+    /// sections and a file alignment of 512 bytes.
+    pub(crate) fn build_image(sections: &[(&str, &[u8])]) -> Vec<u8> {
+        let pe_offset = 0x40;
+        let optional_header = pe_offset + 4 + COFF_HEADER_SIZE;
+        let optional_header_size = 112 + 16 * 8;
+        let table = optional_header + optional_header_size;
+        let headers_size = 0x400;
+        let mut data = vec![0; headers_size];
+        data[..2].copy_from_slice(b"MZ");
+        write_u32(&mut data, PE_OFFSET_FIELD, pe_offset as u32);
+        data[pe_offset..pe_offset + 4].copy_from_slice(PE_SIGNATURE);
+        write_u16(&mut data, pe_offset + 4, 0x8664);
+        write_u16(&mut data, pe_offset + 6, sections.len() as u16);
+        write_u16(&mut data, pe_offset + 20, optional_header_size as u16);
+        write_u16(&mut data, optional_header, PE32_PLUS_MAGIC);
+        write_u32(&mut data, optional_header + SECTION_ALIGNMENT_OFFSET, 0x1000);
+        write_u32(&mut data, optional_header + FILE_ALIGNMENT_OFFSET, 0x200);
+        write_u32(&mut data, optional_header + SIZE_OF_HEADERS_OFFSET, headers_size as u32);
+        write_u32(&mut data, optional_header + 108, 16);
+        for (i, (name, contents)) in sections.iter().enumerate() {
+            let raw_offset = data.len();
+            let raw_size = align_up(contents.len(), 0x200);
+            let header = table + i * SECTION_HEADER_SIZE;
+            data[header..header + name.len()].copy_from_slice(name.as_bytes());
+            for (offset, value) in [(8, contents.len()), (12, 0x1000 * (i + 1)), (16, raw_size), (20, raw_offset)] {
+                write_u32(&mut data, header + offset, value as u32);
+            }
+            data.extend_from_slice(contents);
+            data.resize(raw_offset + raw_size, 0);
+        }
+        write_u32(&mut data, optional_header + SIZE_OF_IMAGE_OFFSET, 0x1000 * (sections.len() as u32 + 1));
+        data
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = build_image(&[(".linux", b"kernel"), (".cmdline", b"quiet\0")]);
+        let image = PeImage::parse(&data).unwrap();
+        assert_eq!(image.machine, 0x8664);
+        assert_eq!(image.sections.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), [".linux", ".cmdline"]);
+        assert_eq!(image.section_data(&data, ".linux"), Ok(Some(&b"kernel"[..])));
+        assert_eq!(image.section_data(&data, ".cmdline"), Ok(Some(&b"quiet\0"[..])));
+        assert_eq!(image.section_data(&data, ".initrd"), Ok(None));
+        assert_eq!(image.section_data(&data[..data.len() - 512], ".cmdline"), Err(PeError::SectionOutOfBounds { name: ".cmdline".to_string() }));
+
+        assert_eq!(PeImage::parse(b"\x7fELF"), Err(PeError::NotPe));
+        let mut bad_signature = data.clone();
+        bad_signature[0x40] = b'X';
+        assert_eq!(PeImage::parse(&bad_signature), Err(PeError::InvalidHeader { offset: 0x40 }));
+        assert_eq!(PeImage::parse(&data[..0x50]), Err(PeError::InvalidHeader { offset: 0x40 }));
+    }
+
+    #[test]
+    fn test_add_sections() {
+        let stub = build_image(&[(".text", b"stub code"), (".data", &[1; 0x1800])]);
+        let uki = add_sections(&stub, &[(".cmdline", b"quiet"), (".linux", &[2; 0x300])]).unwrap();
+        assert_eq!(add_sections(&stub, &[(".cmdline", b"quiet"), (".linux", &[2; 0x300])]).unwrap(), uki);
+
+        let image = PeImage::parse(&uki).unwrap();
+        assert_eq!(image.section_data(&uki, ".text"), Ok(Some(&b"stub code"[..])));
+        assert_eq!(image.section_data(&uki, ".cmdline"), Ok(Some(&b"quiet"[..])));
+        assert_eq!(image.section_data(&uki, ".linux"), Ok(Some(&[2; 0x300][..])));
+        let addresses: Vec<u32> = image.sections.iter().map(|x| x.virtual_address).collect();
+        assert_eq!(addresses, [0x1000, 0x2000, 0x4000, 0x5000]);
+        assert_eq!(read_u32(&uki, image.optional_header_offset + SIZE_OF_IMAGE_OFFSET), Some(0x6000));
+        assert!(image.sections.iter().all(|x| x.raw_offset % 0x200 == 0 && x.raw_size % 0x200 == 0));
+        assert_eq!(read_u32(&uki, image.checksum_offset()), Some(checksum(&uki, image.checksum_offset())));
+
+        let test_cases = [
+            (vec![(".toolong1", &b""[..])], PeError::NameTooLong { name: ".toolong1".to_string() }),
+            (vec![(".a", &b""[..]); 16], PeError::NoRoomForSections),
+        ];
+        for (sections, expected) in test_cases {
+            assert_eq!(add_sections(&stub, &sections), Err(expected));
+        }
+        let mut signed = stub.clone();
+        write_u32(&mut signed, image.certificate_table_entry_offset(&stub).unwrap() + 4, 8);
+        assert_eq!(add_sections(&signed, &[]), Err(PeError::Signed));
+    }
+}
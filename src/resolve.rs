@@ -0,0 +1,176 @@
+//! Opening files of the real system as though its root were `/`.
+//!
+//! The root of the real system is mounted somewhere in the initramfs, like `/sysroot`, so an
+//! absolute symlink in it, like `/sysroot/boot/vmlinuz-linux -> /vmlinuz`, would point into the
+//! initramfs if opened directly, and `..` could escape the root. [`open_in_root`] resolves paths
+//! with `openat2` and `RESOLVE_IN_ROOT`, which makes the kernel treat the root as `/` for
+//! absolute symlinks and `..`. On kernels without `openat2` (before 5.6), the resolution is
+//! emulated in userspace with the same rules.
+
+use std::{
+    collections::VecDeque,
+    ffi::{CString, OsString},
+    fs::{self, File, OpenOptions},
+    io, mem,
+    os::{fd::{AsRawFd, FromRawFd}, unix::{ffi::OsStrExt, fs::OpenOptionsExt}},
+    path::{Component, Path, PathBuf},
+};
+
+/// The maximum number of symlinks followed while resolving a path, like the kernel's limit.
+const MAX_SYMLINKS: usize = 40;
+
+/// Opens `path` for reading, resolving it as though `root` were `/`.
+/// Absolute symlinks and `..` never leave `root`.
+pub fn open_in_root(root: &Path, path: &Path) -> io::Result<File> {
+    match openat2_in_root(root, path) {
+        // openat2 is missing, or blocked by a seccomp filter.
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) || e.raw_os_error() == Some(libc::EPERM) => {
+            open_emulated(root, path)
+        },
+        result => result,
+    }
+}
+
+fn openat2_in_root(root: &Path, path: &Path) -> io::Result<File> {
+    let root_directory = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+        .open(root)?;
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a null byte"))?;
+
+    // open_how is non-exhaustive, so it cannot be built with a struct expression.
+    let mut how: libc::open_how = unsafe { mem::zeroed() };
+    how.flags = (libc::O_RDONLY | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root_directory.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const libc::open_how,
+            mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as i32) })
+}
+
+/// Splits a path into the components that matter for resolution: names and `..`.
+fn components(path: &Path) -> impl DoubleEndedIterator<Item=OsString> + '_ {
+    path.components().filter_map(|x| match x {
+        Component::Normal(name) => Some(name.to_os_string()),
+        Component::ParentDir => Some("..".into()),
+        Component::RootDir | Component::CurDir | Component::Prefix(_) => None,
+    })
+}
+
+/// Resolves `path` as though `root` were `/`, following symlinks one component at a time.
+/// This races with changes to the tree, unlike `openat2`, so it is only a fallback.
+pub fn resolve_emulated(root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let mut resolved: Vec<OsString> = Vec::new();
+    let mut pending: VecDeque<OsString> = components(path).collect();
+    let mut symlinks = 0;
+
+    while let Some(component) = pending.pop_front() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+        resolved.push(component);
+        let current: PathBuf = root.join(resolved.iter().collect::<PathBuf>());
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+                let target = fs::read_link(&current)?;
+                resolved.pop();
+                if target.is_absolute() {
+                    resolved.clear();
+                }
+                for component in components(&target).rev() {
+                    pending.push_front(component);
+                }
+            },
+            Ok(_) => {},
+            // Opening the path reports the error.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(root.join(resolved.into_iter().collect::<PathBuf>()))
+}
+
+fn open_emulated(root: &Path, path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC)
+        .open(resolve_emulated(root, path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, os::unix::fs::symlink};
+
+    use crate::utils::TestDirectory;
+
+    fn read(file: io::Result<File>) -> Result<String, Option<i32>> {
+        let mut contents = String::new();
+        file.map_err(|e| e.raw_os_error())?.read_to_string(&mut contents).unwrap();
+        Ok(contents)
+    }
+
+    #[test]
+    fn test_open_in_root() {
+        let test_directory = TestDirectory::new("resolve");
+        let directory = test_directory.path();
+        let root = directory.join("root");
+        for subdirectory in ["boot", "usr/lib"] {
+            fs::create_dir_all(root.join(subdirectory)).unwrap();
+        }
+        fs::write(directory.join("outside"), "outside").unwrap();
+        fs::write(root.join("outside"), "inside").unwrap();
+        fs::write(root.join("vmlinuz"), "kernel").unwrap();
+        fs::write(root.join("usr/kernel"), "usr kernel").unwrap();
+        for (link, target) in [
+            ("boot/absolute", "/vmlinuz"),
+            ("boot/relative", "../vmlinuz"),
+            ("boot/chain", "relative"),
+            ("boot/escape", "../../../../../../outside"),
+            ("boot/absolute_escape", "/../../outside"),
+            ("boot/directory", "/usr/lib"),
+            ("boot/loop1", "loop2"),
+            ("boot/loop2", "loop1"),
+            ("boot/dangling", "/missing"),
+        ] {
+            symlink(target, root.join(link)).unwrap();
+        }
+
+        let test_cases = [
+            ("/vmlinuz", Ok("kernel")),
+            ("vmlinuz", Ok("kernel")),
+            ("/boot/absolute", Ok("kernel")),
+            ("/boot/relative", Ok("kernel")),
+            ("/boot/chain", Ok("kernel")),
+            ("/boot/escape", Ok("inside")),
+            ("/boot/absolute_escape", Ok("inside")),
+            ("/../../outside", Ok("inside")),
+            // ".." after a symlink to a directory goes to the parent of the target.
+            ("/boot/directory/../kernel", Ok("usr kernel")),
+            ("/boot/./absolute", Ok("kernel")),
+            ("/boot/loop1", Err(Some(libc::ELOOP))),
+            ("/boot/dangling", Err(Some(libc::ENOENT))),
+            ("/boot/missing", Err(Some(libc::ENOENT))),
+        ];
+        for (path, expected) in test_cases {
+            let expected = expected.map(|x| x.to_string());
+            assert_eq!(read(open_in_root(&root, Path::new(path))), expected, "openat2: {}", path);
+            assert_eq!(read(open_emulated(&root, Path::new(path))), expected, "emulated: {}", path);
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Fingerprint of the first stage, handed to the real system.
+//!
+//! Before kexec-ing, the runner computes SHA-256 digests of the usb kernel (from `BOOT_IMAGE`),
+//! its initramfs images (from `initrd=`) and the runner binary itself, and adds them to the
+//! command line of the real kernel as `usbkexec.stage1=`. The real system can then check them
+//! against the manifest recorded at the last trusted update with the `check-stage1` subcommand.
+//!
+//! The format of the value is a comma separated list of `<component>:<digest>[:<path>]` entries,
+//! where component is `kernel`, `initrd` or `runner`, digest is a hex SHA-256 digest, or
+//! `unknown` if the file could not be read in the first stage, and path is the path of the
+//! kernel or initrd on the partition the boot loader loaded it from. Each file is checked against
+//! the digest the manifest records for its own path, so that a file cannot pass as another file
+//! of the usb, like an old kernel.
+
+use std::{collections::HashMap, fmt, fs, path::{Path, PathBuf}};
+
+use anyhow::{Result, bail};
+
+use crate::{cli, cmdline::KernelCommandLine, manifest::{self, Manifest}, utils};
+
+/// The kernel command line key the fingerprint is passed in.
+pub const STAGE1_KEY: &str = "usbkexec.stage1";
+
+/// EFI variable set by the boot loader to the partition uuid of the partition it was loaded from.
+const LOADER_DEVICE_PART_UUID: &str = "/sys/firmware/efi/efivars/LoaderDevicePartUUID-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Component {
+    Kernel,
+    Initrd,
+    Runner,
+}
+impl Component {
+    fn name(self) -> &'static str {
+        match self {
+            Component::Kernel => "kernel",
+            Component::Initrd => "initrd",
+            Component::Runner => "runner",
+        }
+    }
+}
+
+/// The digest of a file making up the first stage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ComponentDigest {
+    pub component: Component,
+    /// None if the file could not be read.
+    pub digest: Option<[u8; 32]>,
+    /// The path on the partition the boot loader loaded the file from, for the kernel and
+    /// initrds. Paths that cannot be put in the fingerprint, because they contain a comma, are
+    /// left out.
+    pub path: Option<String>,
+}
+
+/// Digests of the files making up the first stage.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Fingerprint {
+    pub digests: Vec<ComponentDigest>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ParseFingerprintError {
+    #[error("malformed fingerprint entry: {entry}")]
+    MalformedEntry {
+        entry: String,
+    },
+    #[error("unknown fingerprint component: {component}")]
+    UnknownComponent {
+        component: String,
+    },
+}
+
+impl Fingerprint {
+    pub fn parse(fingerprint: &str) -> Result<Fingerprint, ParseFingerprintError> {
+        let mut digests = Vec::new();
+        for entry in fingerprint.split(',').filter(|x| !x.is_empty()) {
+            let malformed = || ParseFingerprintError::MalformedEntry { entry: entry.to_string() };
+            let mut parts = entry.splitn(3, ':');
+            let (name, digest, path) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(digest), path) => (name, digest, path),
+                _ => return Err(malformed()),
+            };
+            let component = match name {
+                "kernel" => Component::Kernel,
+                "initrd" => Component::Initrd,
+                "runner" => Component::Runner,
+                _ => return Err(ParseFingerprintError::UnknownComponent { component: name.to_string() }),
+            };
+            let digest = match digest {
+                "unknown" => None,
+                _ => Some(utils::from_hex(digest)
+                    .and_then(|x| <[u8; 32]>::try_from(x).ok())
+                    .ok_or_else(malformed)?),
+            };
+            digests.push(ComponentDigest { component, digest, path: path.map(str::to_string) });
+        }
+        Ok(Fingerprint { digests })
+    }
+
+    /// Computes the fingerprint of the running first stage.
+    /// Files that cannot be read are recorded as unknown, so that a missing file never
+    /// prevents booting.
+    pub fn compute(command_line: &KernelCommandLine) -> Fingerprint {
+        // Paths given by the boot loader are relative to the partition it was loaded from,
+        // which is not normally mounted in the initramfs.
+        let loader_partition = loader_partition_uuid()
+            .and_then(|x| utils::TemporaryMount::read_only(&Path::new("/dev/disk/by-partuuid").join(x)).ok());
+        let resolve = |path: &str| -> PathBuf {
+            match &loader_partition {
+                Some(mount) => mount.path().join(path.trim_start_matches('/')),
+                None => PathBuf::from(path),
+            }
+        };
+        let digest = |path: &Path| manifest::hash_file(path, false).ok().map(|(_, digest)| digest);
+        let boot_file = |component, path: Option<&str>| {
+            let path = path.map(boot_loader_path);
+            ComponentDigest {
+                component,
+                digest: path.as_deref().and_then(|x| digest(&resolve(x))),
+                path: path.filter(|x| !x.contains(',')),
+            }
+        };
+
+        let mut digests = Vec::new();
+        digests.push(boot_file(Component::Kernel, command_line.get("BOOT_IMAGE")));
+        for initrd in command_line.get_all("initrd") {
+            digests.push(boot_file(Component::Initrd, Some(initrd)));
+        }
+        digests.push(ComponentDigest {
+            component: Component::Runner,
+            digest: digest(Path::new("/proc/self/exe")),
+            path: None,
+        });
+        Fingerprint { digests }
+    }
+}
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, entry) in self.digests.iter().enumerate() {
+            if i != 0 {
+                write!(f, ",")?;
+            }
+            match &entry.digest {
+                Some(digest) => write!(f, "{}:{}", entry.component.name(), utils::to_hex(digest))?,
+                None => write!(f, "{}:unknown", entry.component.name())?,
+            }
+            if let Some(path) = &entry.path {
+                write!(f, ":{}", path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts a path as given by a boot loader into a unix path.
+/// Removes a grub device prefix such as `(hd0,gpt1)`, and converts the backslashes used by EFI
+/// boot loaders into slashes.
+fn boot_loader_path(path: &str) -> String {
+    let path = match path.strip_prefix('(').and_then(|x| x.split_once(')')) {
+        Some((_, rest)) => rest,
+        None => path,
+    };
+    path.replace('\\', "/")
+}
+
+/// Reads the partition uuid of the partition the boot loader was loaded from, if the boot
+/// loader set it.
+fn loader_partition_uuid() -> Option<String> {
+    decode_efi_string(&fs::read(LOADER_DEVICE_PART_UUID).ok()?)
+        .map(|x| x.to_lowercase())
+}
+
+/// Decodes an EFI variable containing a UTF-16LE string, as read from efivarfs.
+/// The first 4 bytes are the attributes of the variable.
+fn decode_efi_string(variable: &[u8]) -> Option<String> {
+    let data = variable.get(4..)?;
+    let units: Vec<u16> = data.chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .take_while(|x| *x != 0)
+        .collect();
+    String::from_utf16(&units).ok()
+}
+
+/// Entry point of the `check-stage1` subcommand, run on the real system.
+///
+/// Usage: `check-stage1 [--record <file>] [--refuse]`
+///
+/// Checks the `usbkexec.stage1=` fingerprint on the kernel command line. The kernel and initrd
+/// digests must match the digests the manifest recorded at the last trusted update has for
+/// their paths, and the runner digest must match this program. Any unknown or mismatching digest is reported as a warning,
+/// or as an error if `--refuse` is given.
+pub fn check_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        flags: &["--refuse"],
+        options: &["--record"],
+        ..Default::default()
+    })?;
+    let record = Path::new(args.value("--record").unwrap_or(crate::audit::DEFAULT_TRUSTED_MANIFEST));
+
+    let command_line = KernelCommandLine::read()?;
+    let fingerprint = match command_line.get(STAGE1_KEY) {
+        Some(x) => Fingerprint::parse(x)?,
+        None => {
+            println!("no {} parameter, the system was not booted through the usb", STAGE1_KEY);
+            return Ok(());
+        },
+    };
+
+    let trusted = match fs::read_to_string(record) {
+        Ok(x) => Manifest::parse(&x)?,
+        Err(e) => bail!("failed to read the trusted record \"{}\": {}", record.display(), e),
+    };
+    let (_, runner) = manifest::hash_file(Path::new("/proc/self/exe"), false)?;
+
+    let problems = check(&fingerprint, &trusted, &runner);
+
+    if problems.is_empty() {
+        println!("the first stage matches the last trusted update");
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("WARNING: {}", problem);
+    }
+    if args.flag("--refuse") {
+        bail!("refusing to continue with an unknown first stage");
+    }
+    Ok(())
+}
+
+/// Checks every digest of the fingerprint against the digest recorded for its path in the
+/// `trusted` manifest of the usb, or against `runner`. Returns the problems found.
+fn check(fingerprint: &Fingerprint, trusted: &Manifest, runner: &[u8; 32]) -> Vec<String> {
+    let trusted: HashMap<&Path, &[u8; 32]> = trusted.entries.iter().map(|x| (x.path.as_path(), &x.sha256)).collect();
+    let mut problems = Vec::new();
+    for entry in &fingerprint.digests {
+        let name = entry.component.name();
+        let digest = match &entry.digest {
+            Some(x) => x,
+            None => {
+                problems.push(format!("the {} of the first stage could not be fingerprinted", name));
+                continue;
+            },
+        };
+        let expected = match (entry.component, &entry.path) {
+            (Component::Runner, _) => Some(runner),
+            (_, Some(path)) => trusted.get(Path::new(path.trim_start_matches('/'))).copied(),
+            (_, None) => {
+                problems.push(format!("the {} of the first stage has no path in the fingerprint", name));
+                continue;
+            },
+        };
+        match (expected, &entry.path) {
+            (Some(expected), _) if expected == digest => {},
+            (Some(_), Some(path)) => problems.push(format!("the {} of the first stage, {}, does not match the last trusted update", name, path)),
+            (Some(_), None) => problems.push(format!("the {} of the first stage is unknown", name)),
+            (None, path) => problems.push(format!("the {} of the first stage, {}, is not in the last trusted update", name, path.as_deref().unwrap_or(""))),
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_parse_and_display() {
+        let digest = [0xab; 32];
+        let fingerprint = Fingerprint {
+            digests: vec![
+                ComponentDigest { component: Component::Kernel, digest: Some(digest), path: Some("/EFI/usb/vmlinuz".to_string()) },
+                ComponentDigest { component: Component::Initrd, digest: None, path: Some("/initramfs:1.img".to_string()) },
+                ComponentDigest { component: Component::Runner, digest: Some(digest), path: None },
+            ],
+        };
+        let text = format!("kernel:{0}:/EFI/usb/vmlinuz,initrd:unknown:/initramfs:1.img,runner:{0}", utils::to_hex(&digest));
+        assert_eq!(fingerprint.to_string(), text);
+        assert_eq!(Fingerprint::parse(&text), Ok(fingerprint));
+
+        assert_eq!(Fingerprint::parse("kernel"), Err(ParseFingerprintError::MalformedEntry { entry: "kernel".to_string() }));
+        assert_eq!(Fingerprint::parse("kernel:abcd"), Err(ParseFingerprintError::MalformedEntry { entry: "kernel:abcd".to_string() }));
+        assert_eq!(Fingerprint::parse("bios:unknown"), Err(ParseFingerprintError::UnknownComponent { component: "bios".to_string() }));
+    }
+
+    #[test]
+    fn test_check() {
+        let (kernel, initrd, old_kernel, runner) = ([1; 32], [2; 32], [3; 32], [4; 32]);
+        let trusted = Manifest::parse(&format!(
+            "{}  1  vmlinuz\n{}  1  initramfs.img\n{}  1  vmlinuz.old\n",
+            utils::to_hex(&kernel), utils::to_hex(&initrd), utils::to_hex(&old_kernel),
+        )).unwrap();
+        let fingerprint = |kernel_digest: [u8; 32], initrd_digest: [u8; 32]| Fingerprint {
+            digests: vec![
+                ComponentDigest { component: Component::Kernel, digest: Some(kernel_digest), path: Some("/vmlinuz".to_string()) },
+                ComponentDigest { component: Component::Initrd, digest: Some(initrd_digest), path: Some("/initramfs.img".to_string()) },
+                ComponentDigest { component: Component::Runner, digest: Some(runner), path: None },
+            ],
+        };
+        assert_eq!(check(&fingerprint(kernel, initrd), &trusted, &runner), Vec::<String>::new());
+        // Files of the usb that are recorded under other paths do not pass.
+        assert_eq!(check(&fingerprint(old_kernel, kernel), &trusted, &runner), [
+            "the kernel of the first stage, /vmlinuz, does not match the last trusted update",
+            "the initrd of the first stage, /initramfs.img, does not match the last trusted update",
+        ]);
+        assert_eq!(check(&fingerprint(kernel, initrd), &trusted, &[0; 32]), ["the runner of the first stage is unknown"]);
+
+        let mut unlisted = fingerprint(kernel, initrd);
+        unlisted.digests[0].path = Some("/EFI/evil".to_string());
+        unlisted.digests[1].path = None;
+        assert_eq!(check(&unlisted, &trusted, &runner), [
+            "the kernel of the first stage, /EFI/evil, is not in the last trusted update",
+            "the initrd of the first stage has no path in the fingerprint",
+        ]);
+    }
+
+    #[test]
+    fn test_boot_loader_path() {
+        assert_eq!(boot_loader_path("/vmlinuz-linux"), "/vmlinuz-linux");
+        assert_eq!(boot_loader_path("(hd0,gpt1)/boot/vmlinuz"), "/boot/vmlinuz");
+        assert_eq!(boot_loader_path("\\EFI\\usb\\initramfs.img"), "/EFI/usb/initramfs.img");
+    }
+
+    #[test]
+    fn test_decode_efi_string() {
+        let mut variable = vec![0x06, 0x00, 0x00, 0x00];
+        for unit in "AB-12".encode_utf16().chain([0]) {
+            variable.extend_from_slice(&unit.to_le_bytes());
+        }
+        assert_eq!(decode_efi_string(&variable), Some("AB-12".to_string()));
+        assert_eq!(decode_efi_string(&[0x06]), None);
+    }
+}
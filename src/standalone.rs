@@ -0,0 +1,273 @@
+//! Kexec-ing into the real kernel from initramfs images without systemd.
+//!
+//! In systemd based images, systemd unlocks and mounts the real root before the runner runs. In
+//! busybox based mkinitcpio images, the `usb-boot` runtime hook runs the `kexec-standalone`
+//! subcommand early instead, which does that itself:
+//!
+//! 1. Reads `root=`, `rootfstype=`, `rootflags=` and the LUKS device of the root from
+//!    `cryptdevice=<device>:<name>`, `rd.luks.uuid=` or `rd.luks.name=<uuid>=<name>`.
+//! 2. Asks for the passphrase on the console and opens the LUKS device with cryptsetup.
+//! 3. Mounts the root read-only on [`NEW_ROOT`], where the paths of the kernel and initrd are.
+//! 4. Loads the real kernel like the `kexec` subcommand does, and executes it with `kexec -e`.
+//!
+//! The LUKS device is opened even if kexec is not requested, so that images with this hook do not
+//! need the `encrypt` hook. If kexec is not requested, or fails, the root is not left mounted, so
+//! that the image boots normally, with the LUKS device already open.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Write},
+    mem::MaybeUninit,
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use zeroize::Zeroizing;
+
+use crate::{
+    cmdline::KernelCommandLine,
+    generator,
+    initramfs_kexec_runner,
+    luks::{self, LuksVolume},
+};
+
+/// Where busybox based mkinitcpio images mount the real root.
+pub const NEW_ROOT: &str = "/new_root";
+const PASSPHRASE_ATTEMPTS: usize = 3;
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How to unlock and mount the real root, from the kernel command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RootConfig {
+    pub device: PathBuf,
+    pub fstype: Option<String>,
+    /// Mount options, in addition to `ro`.
+    pub flags: Option<String>,
+    pub luks: Option<LuksVolume>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum RootConfigError {
+    #[error("no root= given on the kernel command line")]
+    MissingRoot,
+    #[error("invalid cryptdevice=\"{value}\", expected \"<device>:<name>\"")]
+    MalformedCryptdevice {
+        value: String,
+    },
+    #[error("invalid rd.luks.name=\"{value}\", expected \"<uuid>=<name>\"")]
+    MalformedLuksName {
+        value: String,
+    },
+}
+
+impl RootConfig {
+    pub fn from_command_line(command_line: &KernelCommandLine) -> Result<RootConfig, RootConfigError> {
+        let device = command_line.get("root").ok_or(RootConfigError::MissingRoot)?;
+
+        let luks = if let Some(value) = command_line.get("cryptdevice") {
+            // The encrypt hook allows options after the name, which are not needed here.
+            match value.split(':').collect::<Vec<_>>().as_slice() {
+                [device, name, ..] if !device.is_empty() && !name.is_empty() => {
+                    Some(LuksVolume { device: luks::device_path(device), name: name.to_string() })
+                },
+                _ => return Err(RootConfigError::MalformedCryptdevice { value: value.to_string() }),
+            }
+        }
+        else if let Some(value) = command_line.get("rd.luks.name") {
+            match value.split_once('=') {
+                Some((uuid, name)) if !uuid.is_empty() && !name.is_empty() => Some(LuksVolume {
+                    device: luks::device_path(&format!("UUID={}", uuid)),
+                    name: name.to_string(),
+                }),
+                _ => return Err(RootConfigError::MalformedLuksName { value: value.to_string() }),
+            }
+        }
+        else {
+            command_line.get("rd.luks.uuid").map(|uuid| LuksVolume {
+                device: luks::device_path(&format!("UUID={}", uuid)),
+                name: format!("luks-{}", uuid),
+            })
+        };
+
+        Ok(RootConfig {
+            device: luks::device_path(device),
+            fstype: command_line.get("rootfstype").map(|x| x.to_string()),
+            flags: command_line.get("rootflags").map(|x| x.to_string()),
+            luks,
+        })
+    }
+
+    /// The options to mount the root with.
+    fn mount_options(&self) -> String {
+        match &self.flags {
+            Some(flags) => format!("ro,{}", flags),
+            None => "ro".to_string(),
+        }
+    }
+}
+
+/// Reads a passphrase from the console without echoing it.
+fn read_passphrase(prompt: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let mut console = OpenOptions::new().read(true).write(true).open("/dev/console")?;
+    write!(console, "{}", prompt)?;
+
+    let fd = console.as_raw_fd();
+    let mut original = MaybeUninit::<libc::termios>::uninit();
+    let original = match unsafe { libc::tcgetattr(fd, original.as_mut_ptr()) } {
+        0 => Some(unsafe { original.assume_init() }),
+        _ => None,
+    };
+    if let Some(original) = original {
+        let mut no_echo = original;
+        no_echo.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &no_echo) };
+    }
+
+    // Read byte by byte into a buffer that is never reallocated, so no copies are left behind.
+    let mut passphrase = Zeroizing::new(Vec::with_capacity(1024));
+    let mut byte = Zeroizing::new([0u8]);
+    let result = loop {
+        match console.read(&mut *byte) {
+            Ok(0) => break Ok(()),
+            Ok(_) if byte[0] == b'\n' => break Ok(()),
+            Ok(_) if passphrase.len() == passphrase.capacity() => break Err(anyhow::anyhow!("passphrase too long")),
+            Ok(_) => passphrase.push(byte[0]),
+            Err(e) => break Err(e.into()),
+        }
+    };
+
+    if let Some(original) = original {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    }
+    writeln!(console)?;
+    result.map(|()| passphrase)
+}
+
+/// Waits for a device to appear, since udev may still be probing devices.
+fn wait_for_device(device: &Path) -> Result<()> {
+    let start = Instant::now();
+    while !device.exists() {
+        if start.elapsed() > DEVICE_TIMEOUT {
+            bail!("the device \"{}\" did not appear", device.display());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// Asks for the passphrase of the volume until it opens the volume, and returns it.
+pub fn unlock_from_console(volume: &LuksVolume) -> Result<Zeroizing<Vec<u8>>> {
+    wait_for_device(&volume.device)?;
+    for _ in 0..PASSPHRASE_ATTEMPTS {
+        let passphrase = read_passphrase(&format!("Enter passphrase for {}: ", volume.device.display()))?;
+        match luks::unlock(volume, &passphrase) {
+            Ok(()) => return Ok(passphrase),
+            Err(e) => eprintln!("{:#}", e),
+        }
+    }
+    bail!("failed to unlock \"{}\" after {} attempts", volume.device.display(), PASSPHRASE_ATTEMPTS)
+}
+
+fn is_mount_point(path: &Path) -> Result<bool> {
+    let parent = path.parent().unwrap_or(Path::new("/"));
+    Ok(fs::metadata(path)?.dev() != fs::metadata(parent)?.dev())
+}
+
+/// Mounts the root read-only on [`NEW_ROOT`], unless something is already mounted there.
+pub fn mount_root(root: &RootConfig) -> Result<()> {
+    let new_root = Path::new(NEW_ROOT);
+    fs::create_dir_all(new_root)?;
+    if is_mount_point(new_root)? {
+        return Ok(());
+    }
+    wait_for_device(&root.device)?;
+
+    let mut command = Command::new("mount");
+    command.args(["-o", &root.mount_options()]);
+    if let Some(fstype) = &root.fstype {
+        command.args(["-t", fstype]);
+    }
+    if !command.arg(&root.device).arg(new_root).status()?.success() {
+        bail!("failed to mount \"{}\" on {}", root.device.display(), NEW_ROOT);
+    }
+    Ok(())
+}
+
+fn execute() -> Result<()> {
+    unsafe { libc::sync() };
+    // kexec -e only returns if it fails.
+    Command::new("kexec").arg("-e").status()?;
+    bail!("failed to kexec -e");
+}
+
+/// `usb-boot kexec-standalone <options of kexec>`
+///
+/// Unlocks the real root, then mounts it and kexecs into the real kernel if that is requested.
+/// See the [module documentation](self).
+pub fn standalone_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let config = initramfs_kexec_runner::parse_args(args, initramfs_kexec_runner::option_names().try_into().unwrap())?;
+    let command_line = KernelCommandLine::read()?;
+    let root = RootConfig::from_command_line(&command_line)?;
+    let passphrase = match &root.luks {
+        Some(volume) => Some(unlock_from_console(volume)?),
+        None => None,
+    };
+    if !generator::is_enabled(&command_line, config.transform_parameters.keys()) {
+        return Ok(());
+    }
+
+    let mut extra_overlay_files = Vec::new();
+    // The passphrase was just entered, so forward it instead of asking again.
+    if let (Some(passphrase), Some(target)) = (passphrase, LuksVolume::from_command_line(&command_line)?) {
+        extra_overlay_files.push(luks::key_file_overlay(&target, passphrase));
+    }
+    mount_root(&root)?;
+
+    let result = initramfs_kexec_runner::load(config, extra_overlay_files).and_then(|()| execute());
+    if let Err(e) = result {
+        // Let the normal boot mount the root again.
+        let _ = Command::new("umount").arg(NEW_ROOT).status();
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_config() {
+        let volume = |device: &str, name: &str| Some(LuksVolume { device: device.into(), name: name.to_string() });
+        let test_cases = [
+            ("root=/dev/sda2 rootfstype=ext4", Ok(RootConfig {
+                device: "/dev/sda2".into(), fstype: Some("ext4".to_string()), flags: None, luks: None,
+            })),
+            ("cryptdevice=UUID=1234:cryptroot:allow-discards root=/dev/mapper/cryptroot rootflags=subvol=@", Ok(RootConfig {
+                device: "/dev/mapper/cryptroot".into(), fstype: None, flags: Some("subvol=@".to_string()),
+                luks: volume("/dev/disk/by-uuid/1234", "cryptroot"),
+            })),
+            ("rd.luks.uuid=1234 root=UUID=5678", Ok(RootConfig {
+                device: "/dev/disk/by-uuid/5678".into(), fstype: None, flags: None,
+                luks: volume("/dev/disk/by-uuid/1234", "luks-1234"),
+            })),
+            ("rd.luks.name=1234=root rd.luks.uuid=1234 root=/dev/mapper/root", Ok(RootConfig {
+                device: "/dev/mapper/root".into(), fstype: None, flags: None,
+                luks: volume("/dev/disk/by-uuid/1234", "root"),
+            })),
+            ("rd.luks.uuid=1234", Err(RootConfigError::MissingRoot)),
+            ("cryptdevice=/dev/sda2 root=/dev/mapper/root", Err(RootConfigError::MalformedCryptdevice { value: "/dev/sda2".to_string() })),
+            ("rd.luks.name=root root=/dev/mapper/root", Err(RootConfigError::MalformedLuksName { value: "root".to_string() })),
+        ];
+        for (command_line, expected) in test_cases {
+            assert_eq!(RootConfig::from_command_line(&KernelCommandLine::parse(command_line)), expected, "{}", command_line);
+        }
+
+        let root = RootConfig::from_command_line(&KernelCommandLine::parse("root=/dev/sda2 rootflags=noatime")).unwrap();
+        assert_eq!(root.mount_options(), "ro,noatime");
+    }
+}
@@ -0,0 +1,295 @@
+//! Locating the root of the real system, where the real kernel and initrd are.
+//!
+//! The root is given with `usbkexec.root=`, or found in `/proc/self/mountinfo` at one of the
+//! places initramfs images mount it: `/sysroot` for systemd based images and `/new_root` for
+//! busybox based ones. If the real system has a separate `/boot` that is not mounted yet, it is
+//! found in the `/etc/fstab` of the root and mounted read-only, after unlocking it with the
+//! `/etc/crypttab` of the root if it is encrypted.
+//!
+//! The paths of the kernel and initrd are then resolved relative to the root, so
+//! `usbkexec.kernel=/boot/vmlinuz-linux` means the kernel in `/boot` of the real system. Paths
+//! that already start with the path of the root are taken relative to it. Files are opened with
+//! [`resolve::open_in_root`], so symlinks in the real system resolve as they would there.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    ffi::OsString,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, bail};
+
+use crate::{cmdline::KernelCommandLine, luks::{self, LuksVolume}, overlay, resolve, utils::TemporaryMount};
+
+/// The kernel parameter that gives the root of the real system explicitly.
+pub const ROOT_KEY: &str = "usbkexec.root";
+/// Where initramfs images mount the root, in order of preference.
+pub const SYSROOT_CANDIDATES: &[&str] = &["/sysroot", "/new_root"];
+
+/// A line of `/proc/self/mountinfo`, with the fields used here.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MountInfo {
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+}
+
+/// Undoes the octal escapes of spaces and other special characters in mountinfo and fstab.
+fn unescape(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|x| u8::from_str_radix(std::str::from_utf8(x).ok()?, 8).ok());
+        match escape {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 4;
+            },
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    PathBuf::from(OsString::from_vec(unescaped))
+}
+
+/// Parses `/proc/self/mountinfo`. Malformed lines are skipped.
+pub fn parse_mountinfo(mountinfo: &str) -> Vec<MountInfo> {
+    mountinfo.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            // The optional fields end with a "-" separator.
+            let separator = fields.iter().skip(6).position(|x| *x == "-")? + 6;
+            Some(MountInfo {
+                mount_point: unescape(fields.get(4)?),
+                fs_type: fields.get(separator + 1)?.to_string(),
+                source: fields.get(separator + 2)?.to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn read_mountinfo() -> io::Result<Vec<MountInfo>> {
+    Ok(parse_mountinfo(&fs::read_to_string("/proc/self/mountinfo")?))
+}
+
+/// Returns the root given on the kernel command line, or the first mounted sysroot candidate.
+pub fn find_root(command_line: &KernelCommandLine, mounts: &[MountInfo]) -> Option<PathBuf> {
+    if let Some(root) = command_line.get(ROOT_KEY) {
+        return Some(root.into());
+    }
+    SYSROOT_CANDIDATES.iter()
+        .map(Path::new)
+        .find(|x| mounts.iter().any(|mount| mount.mount_point == *x))
+        .map(|x| x.to_path_buf())
+}
+
+/// An entry of `/etc/fstab`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FstabEntry {
+    pub spec: String,
+    pub file: PathBuf,
+    pub fs_type: String,
+    pub options: String,
+}
+
+pub fn parse_fstab(fstab: &str) -> Vec<FstabEntry> {
+    fstab.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [spec, file, fs_type, rest @ ..] => Some(FstabEntry {
+                spec: spec.to_string(),
+                file: unescape(file),
+                fs_type: fs_type.to_string(),
+                options: rest.first().unwrap_or(&"defaults").to_string(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the volume with the given name from `/etc/crypttab`.
+pub fn find_in_crypttab(crypttab: &str, name: &str) -> Option<LuksVolume> {
+    crypttab.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .find_map(|line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [volume, device, ..] if *volume == name => Some(LuksVolume {
+                device: luks::device_path(device),
+                name: name.to_string(),
+            }),
+            _ => None,
+        })
+}
+
+/// Splits a path of the real system into the directory in the initramfs to resolve it in, which
+/// is the root or the separately mounted `/boot`, and the path in that directory.
+pub fn split_path<'a>(root: &'a Path, boot: Option<&'a Path>, path: &'a str) -> (&'a Path, &'a Path) {
+    let path = Path::new(path);
+    let relative = match path.strip_prefix(root) {
+        Ok(relative) if root != Path::new("/") => relative,
+        _ => path.strip_prefix("/").unwrap_or(path),
+    };
+    match (boot, relative.strip_prefix("boot")) {
+        (Some(boot), Ok(in_boot)) => (boot, in_boot),
+        _ => (root, relative),
+    }
+}
+
+/// The root of the real system, with its `/boot` if it had to be mounted.
+#[derive(Debug)]
+pub struct TargetRoot {
+    root: PathBuf,
+    boot: Option<TemporaryMount>,
+}
+impl TargetRoot {
+    /// Finds the root, and mounts its `/boot` if it is separate and not mounted.
+    /// If no root is found, paths are resolved in the initramfs itself.
+    pub fn locate(command_line: &KernelCommandLine) -> Result<TargetRoot> {
+        let mounts = read_mountinfo()?;
+        let root = match find_root(command_line, &mounts) {
+            Some(root) => root,
+            None => {
+                eprintln!("no root of the real system found, using paths in the initramfs");
+                return Ok(TargetRoot { root: "/".into(), boot: None });
+            },
+        };
+        if !root.is_dir() {
+            bail!("the root of the real system \"{}\" is not a directory", root.display());
+        }
+
+        let boot_mount_point = root.join("boot");
+        let boot_entry = match read_to_string_in_root(&root, "etc/fstab") {
+            Ok(fstab) => parse_fstab(&fstab).into_iter().find(|x| x.file == Path::new("/boot")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let boot = match boot_entry {
+            Some(entry) if !mounts.iter().any(|x| x.mount_point == boot_mount_point) => {
+                Some(mount_boot(&root, &entry)?)
+            },
+            _ => None,
+        };
+        Ok(TargetRoot { root, boot })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Opens a file of the real system, like `/boot/vmlinuz-linux`, for reading.
+    pub fn open(&self, path: &str) -> io::Result<File> {
+        let (directory, path) = split_path(&self.root, self.boot.as_ref().map(|x| x.path()), path);
+        resolve::open_in_root(directory, path)
+    }
+
+    /// Returns the device of `/boot` of the real system, or of its root if `/boot` is not a
+    /// separate file system, as it appears in the mountinfo.
+    pub fn boot_device(&self) -> io::Result<Option<String>> {
+        let mounts = read_mountinfo()?;
+        let candidates = [self.boot.as_ref().map(|x| x.path().to_path_buf()), Some(self.root.join("boot")), Some(self.root.clone())];
+        // Later mounts hide earlier ones on the same mount point.
+        Ok(candidates.into_iter().flatten()
+            .find_map(|x| mounts.iter().rev().find(|mount| mount.mount_point == x))
+            .map(|x| x.source.clone()))
+    }
+
+    /// Lists the names in a directory of the real system, resolved like [`TargetRoot::open`].
+    pub fn read_dir(&self, path: &str) -> io::Result<Vec<OsString>> {
+        let directory = self.open(path)?;
+        fs::read_dir(overlay::fd_path(&directory))?
+            .map(|x| x.map(|x| x.file_name()))
+            .collect()
+    }
+}
+
+fn read_to_string_in_root(root: &Path, path: &str) -> io::Result<String> {
+    let mut contents = String::new();
+    resolve::open_in_root(root, Path::new(path))?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Mounts a separate `/boot` read-only, unlocking it first if it is encrypted.
+fn mount_boot(root: &Path, entry: &FstabEntry) -> Result<TemporaryMount> {
+    let device = luks::device_path(&entry.spec);
+    if let Ok(name) = device.strip_prefix("/dev/mapper") {
+        let name = name.to_string_lossy();
+        if !device.exists() {
+            let crypttab = read_to_string_in_root(root, "etc/crypttab").unwrap_or_default();
+            let volume = match find_in_crypttab(&crypttab, &name) {
+                Some(x) => x,
+                None => bail!("/boot is on \"{}\", which is not in /etc/crypttab", device.display()),
+            };
+            let passphrase = luks::ask_passphrase(&volume)?;
+            luks::unlock(&volume, &passphrase)?;
+        }
+    }
+    TemporaryMount::read_only(&device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = "\
+22 1 0:21 / / rw,relatime - rootfs rootfs rw
+36 22 254:0 / /sysroot ro,relatime shared:1 - ext4 /dev/mapper/root ro
+37 36 8:1 / /sysroot/boot\\040efi rw master:2 - vfat /dev/sda1 rw
+malformed
+";
+        let mounts = parse_mountinfo(mountinfo);
+        assert_eq!(mounts, [
+            MountInfo { mount_point: "/".into(), fs_type: "rootfs".to_string(), source: "rootfs".to_string() },
+            MountInfo { mount_point: "/sysroot".into(), fs_type: "ext4".to_string(), source: "/dev/mapper/root".to_string() },
+            MountInfo { mount_point: "/sysroot/boot efi".into(), fs_type: "vfat".to_string(), source: "/dev/sda1".to_string() },
+        ]);
+
+        let test_cases = [
+            ("quiet", Some("/sysroot")),
+            ("usbkexec.root=/mnt/root", Some("/mnt/root")),
+        ];
+        for (command_line, expected) in test_cases {
+            assert_eq!(find_root(&KernelCommandLine::parse(command_line), &mounts), expected.map(PathBuf::from));
+        }
+        assert_eq!(find_root(&KernelCommandLine::parse("quiet"), &mounts[..1]), None);
+    }
+
+    #[test]
+    fn test_fstab_and_crypttab() {
+        let fstab = "# <file system> <dir> <type> <options> <dump> <pass>\n/dev/mapper/root / ext4 rw 0 1\nUUID=ab /boot vfat\n";
+        assert_eq!(parse_fstab(fstab)[1], FstabEntry {
+            spec: "UUID=ab".to_string(), file: "/boot".into(), fs_type: "vfat".to_string(), options: "defaults".to_string(),
+        });
+
+        let crypttab = "# comment\nroot UUID=12 none luks\nboot PARTUUID=34 none\n";
+        assert_eq!(find_in_crypttab(crypttab, "boot"), Some(LuksVolume { device: "/dev/disk/by-partuuid/34".into(), name: "boot".to_string() }));
+        assert_eq!(find_in_crypttab(crypttab, "home"), None);
+    }
+
+    #[test]
+    fn test_split_path() {
+        let root = Path::new("/sysroot");
+        let boot = Path::new("/run/usb-boot/mount.1.0");
+        let test_cases = [
+            (None, "/boot/vmlinuz-linux", (root, "boot/vmlinuz-linux")),
+            (None, "/sysroot/boot/vmlinuz-linux", (root, "boot/vmlinuz-linux")),
+            (Some(boot), "/boot/vmlinuz-linux", (boot, "vmlinuz-linux")),
+            (Some(boot), "/sysroot/boot/vmlinuz-linux", (boot, "vmlinuz-linux")),
+            (Some(boot), "/bootstrap/vmlinuz", (root, "bootstrap/vmlinuz")),
+            (Some(boot), "/usr/lib/modules/6.1/vmlinuz", (root, "usr/lib/modules/6.1/vmlinuz")),
+        ];
+        for (boot, path, (directory, relative)) in test_cases {
+            assert_eq!(split_path(root, boot, path), (directory, Path::new(relative)), "{}", path);
+        }
+        assert_eq!(split_path(Path::new("/"), None, "/boot/vmlinuz"), (Path::new("/"), Path::new("boot/vmlinuz")));
+    }
+}
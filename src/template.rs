@@ -0,0 +1,253 @@
+//! Placeholders in the command line of the second stage.
+//!
+//! The command line the runner builds from the usb command line, and the `CMDLINE` of the config
+//! of `kexec_into_real_kernel`, can contain placeholders that are filled in at boot, so one usb
+//! config keeps working when the real system is reinstalled or its disks are swapped:
+//!
+//! - `${first:KEY}`: the value of `KEY=` on the command line of the first stage, the last one if
+//!   it is given more than once, like the kernel takes it. It is an error if it is missing.
+//! - `${stage1:KEY}`: every `KEY=...` parameter and `KEY` flag of the first stage, as they are,
+//!   like `console=tty0 console=ttyS0,115200`. Nothing if there are none.
+//! - `${uuid:DEVICE}`: the file system UUID of a device of the initramfs, like
+//!   `/dev/mapper/root`, probed with blkid.
+//! - `${partuuid-of-boot}`: the partition UUID of `/boot` of the real system, or of its root if
+//!   `/boot` is not separate.
+//! - `${kver}`: the version of the kernel that is booted.
+//!
+//! Anything else that looks like a placeholder, like `${foo}` or `$kernelopts`, is left as it is,
+//! since boot loader entries may contain them for other tools. The `.cmdline` of a UKI is not
+//! expanded.
+
+use std::{fs::File, io::Read, process::Command};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{cli, cmdline::KernelCommandLine, kernel_image, luks, pe, target_root::TargetRoot};
+
+/// Where the boot protocol header of x86 kernels stores its magic and the kernel version.
+pub(crate) const SETUP_HEADER_MAGIC_OFFSET: usize = 0x202;
+pub(crate) const SETUP_HEADER_MAGIC: &[u8; 4] = b"HdrS";
+const KERNEL_VERSION_OFFSET: usize = 0x20e;
+/// The kernel version string is relative to the end of the boot sector.
+const KERNEL_VERSION_BASE: usize = 0x200;
+/// How uncompressed kernels start their version string, like `/proc/version`.
+const LINUX_BANNER: &[u8] = b"Linux version ";
+
+/// A placeholder.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Variable<'a> {
+    First(&'a str),
+    Stage1(&'a str),
+    Uuid(&'a str),
+    PartuuidOfBoot,
+    Kver,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum TemplateError {
+    #[error("the placeholder \"{placeholder}\" is invalid, expected {expected}")]
+    InvalidPlaceholder {
+        placeholder: String,
+        expected: &'static str,
+    },
+    #[error("{key}= is not on the command line of the first stage")]
+    MissingParameter {
+        key: String,
+    },
+}
+
+impl<'a> Variable<'a> {
+    /// Parses the contents of a placeholder, between `${` and `}`. Returns None if it is not a
+    /// placeholder of this module.
+    pub fn parse(contents: &'a str) -> Option<Result<Variable<'a>, TemplateError>> {
+        let (name, argument) = match contents.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (contents, None),
+        };
+        let invalid = |expected| Err(TemplateError::InvalidPlaceholder { placeholder: format!("${{{}}}", contents), expected });
+        let variable = match (name, argument) {
+            ("first", Some(key)) if !key.is_empty() => Ok(Variable::First(key)),
+            ("stage1", Some(key)) if !key.is_empty() => Ok(Variable::Stage1(key)),
+            ("uuid", Some(device)) if !device.is_empty() => Ok(Variable::Uuid(device)),
+            ("partuuid-of-boot", None) => Ok(Variable::PartuuidOfBoot),
+            ("kver", None) => Ok(Variable::Kver),
+            ("first" | "stage1", _) => invalid("${first:KEY} or ${stage1:KEY}"),
+            ("uuid", _) => invalid("${uuid:DEVICE}"),
+            ("partuuid-of-boot" | "kver", _) => invalid("no argument"),
+            _ => return None,
+        };
+        Some(variable)
+    }
+}
+
+/// Replaces the placeholders in `template` with their values from `resolve`.
+pub fn expand<E: From<TemplateError>>(template: &str, mut resolve: impl FnMut(&Variable) -> Result<String, E>) -> Result<String, E> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(x) => start + x,
+            None => break,
+        };
+        expanded.push_str(&rest[..start]);
+        match Variable::parse(&rest[start + 2..end]) {
+            Some(variable) => expanded.push_str(&resolve(&variable?)?),
+            None => expanded.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Resolves the placeholders that come from the command line of the first stage. Returns None for
+/// the other ones.
+pub fn resolve_first_stage(variable: &Variable, first_stage: &KernelCommandLine) -> Option<Result<String, TemplateError>> {
+    match variable {
+        Variable::First(key) => Some(first_stage.get(key)
+            .map(str::to_string)
+            .ok_or_else(|| TemplateError::MissingParameter { key: key.to_string() })),
+        Variable::Stage1(key) => Some(Ok(first_stage.parameters()
+            .filter(|x| x == key || x.split_once('=').is_some_and(|(k, _)| k == *key))
+            .collect::<Vec<_>>()
+            .join(" "))),
+        _ => None,
+    }
+}
+
+/// Reads the version of a kernel image: from the boot protocol header of x86 kernels, or from the
+/// banner in uncompressed kernels.
+pub fn kernel_version(data: &[u8]) -> Option<String> {
+    let first_word = |x: &[u8]| {
+        let end = x.iter().position(|x| *x == 0 || x.is_ascii_whitespace()).unwrap_or(x.len());
+        Some(String::from_utf8_lossy(&x[..end]).into_owned()).filter(|x| !x.is_empty())
+    };
+    if data.get(SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4) == Some(SETUP_HEADER_MAGIC) {
+        let offset = pe::read_u16(data, KERNEL_VERSION_OFFSET)? as usize;
+        if offset != 0 {
+            return first_word(data.get(offset + KERNEL_VERSION_BASE..)?);
+        }
+    }
+    let banner = data.windows(LINUX_BANNER.len()).position(|x| x == LINUX_BANNER)?;
+    first_word(&data[banner + LINUX_BANNER.len()..])
+}
+
+/// Probes a tag, like `UUID` or `PARTUUID`, of a device with blkid.
+fn blkid(tag: &str, device: &str) -> Result<String> {
+    let device = luks::device_path(device);
+    let output = Command::new("blkid").args(["-s", tag, "-o", "value"]).arg(&device).output()?;
+    let value = String::from_utf8(output.stdout)?.trim().to_string();
+    if !output.status.success() || value.is_empty() {
+        bail!("failed to probe the {} of \"{}\"", tag, device.display());
+    }
+    Ok(value)
+}
+
+/// Expands the placeholders of a command line for booting `kernel`, whose version is
+/// `kernel_version` if it is known from a boot loader entry or UKI.
+pub fn expand_command_line(
+    command_line: &str,
+    first_stage: &KernelCommandLine,
+    target_root: &TargetRoot,
+    kernel: Option<&File>,
+    kernel_version: Option<&str>,
+) -> Result<String> {
+    expand(command_line, |variable| {
+        if let Some(value) = resolve_first_stage(variable, first_stage) {
+            return Ok(value?);
+        }
+        match variable {
+            Variable::Uuid(device) => blkid("UUID", device),
+            Variable::PartuuidOfBoot => match target_root.boot_device()? {
+                Some(device) => blkid("PARTUUID", &device),
+                None => bail!("failed to find the device of /boot in {}", target_root.root().display()),
+            },
+            Variable::Kver => {
+                let mut kernel = match (kernel_version, kernel) {
+                    (Some(version), _) => return Ok(version.to_string()),
+                    (None, Some(kernel)) => kernel,
+                    (None, None) => bail!("the kernel is not known for ${{kver}}"),
+                };
+                let mut data = Vec::new();
+                kernel.read_to_end(&mut data)?;
+                // Zboot images are loaded compressed with kexec_file_load.
+                let data = kernel_image::decompress(&data).ok().flatten().unwrap_or(data);
+                match self::kernel_version(&data) {
+                    Some(version) => Ok(version),
+                    None => bail!("failed to read the version of the kernel for ${{kver}}"),
+                }
+            },
+            Variable::First(_) | Variable::Stage1(_) => unreachable!(),
+        }
+    })
+}
+
+/// Entry point of the `expand-cmdline` subcommand.
+///
+/// Usage: `expand-cmdline [--kernel <file>] [--kernel-version <version>] <command line>`
+///
+/// Prints the command line with its placeholders expanded, for the current command line. Used by
+/// `kexec_into_real_kernel` for the `CMDLINE` of its config. `--kernel` is the kernel to read the
+/// version from, unless it is given with `--kernel-version`.
+pub fn expand_cmdline_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        options: &["--kernel", "--kernel-version"],
+        positionals: (1, 1),
+        ..Default::default()
+    })?;
+    let first_stage = KernelCommandLine::read()?;
+    let target_root = TargetRoot::locate(&first_stage)?;
+    let kernel = match args.value("--kernel") {
+        Some(path) => Some(File::open(path).with_context(|| format!("failed to open the kernel \"{}\"", path))?),
+        None => None,
+    };
+    let expanded = expand_command_line(&args.positionals()[0], &first_stage, &target_root, kernel.as_ref(), args.value("--kernel-version"))?;
+    println!("{}", expanded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let first_stage = KernelCommandLine::parse("root=/dev/sdb1 console=tty0 quiet console=ttyS0,115200 root=/dev/sda2 console");
+        let resolve = |variable: &Variable| -> Result<String, TemplateError> {
+            match resolve_first_stage(variable, &first_stage) {
+                Some(value) => value,
+                None => Ok(format!("<{:?}>", variable)),
+            }
+        };
+        let test_cases = [
+            ("root=${first:root} rw", Ok("root=/dev/sda2 rw")),
+            ("${stage1:console} ${stage1:quiet}${stage1:splash}", Ok("console=tty0 console=ttyS0,115200 console quiet")),
+            ("root=UUID=${uuid:/dev/mapper/root} boot=${partuuid-of-boot}", Ok("root=UUID=<Uuid(\"/dev/mapper/root\")> boot=<PartuuidOfBoot>")),
+            ("modules=/lib/modules/${kver}", Ok("modules=/lib/modules/<Kver>")),
+            ("$kernelopts ${foo} ${kver x=$ ${", Ok("$kernelopts ${foo} ${kver x=$ ${")),
+            ("resume=${first:resume}", Err(TemplateError::MissingParameter { key: "resume".to_string() })),
+            ("${first}", Err(TemplateError::InvalidPlaceholder { placeholder: "${first}".to_string(), expected: "${first:KEY} or ${stage1:KEY}" })),
+            ("${kver:6.1}", Err(TemplateError::InvalidPlaceholder { placeholder: "${kver:6.1}".to_string(), expected: "no argument" })),
+        ];
+        for (template, expected) in test_cases {
+            assert_eq!(expand(template, resolve), expected.map(str::to_string), "{}", template);
+        }
+    }
+
+    #[test]
+    fn test_kernel_version() {
+        let mut bzimage = vec![0; 0x400];
+        bzimage[SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4].copy_from_slice(SETUP_HEADER_MAGIC);
+        bzimage[KERNEL_VERSION_OFFSET..KERNEL_VERSION_OFFSET + 2].copy_from_slice(&0x100u16.to_le_bytes());
+        bzimage[0x300..0x323].copy_from_slice(b"6.10.3-arch1-1 (linux@archlinux) #1");
+
+        let test_cases: [(&[u8], Option<&str>); 3] = [
+            (&bzimage, Some("6.10.3-arch1-1")),
+            (b"\0\0Linux version 6.1.0-18-arm64 (debian-kernel@lists.debian.org)\0", Some("6.1.0-18-arm64")),
+            (b"compressed", None),
+        ];
+        for (data, expected) in test_cases {
+            assert_eq!(kernel_version(data).as_deref(), expected);
+        }
+    }
+}
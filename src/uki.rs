@@ -0,0 +1,398 @@
+//! Kexec-ing into Unified Kernel Images of the real system.
+//!
+//! A UKI is a PE image that bundles the kernel, the initrd, microcode and the kernel command line
+//! in sections. With `usbkexec.uki=<path>` on the kernel command line, the runner reads the UKI
+//! from the real system instead of a separate kernel and initrd, and writes the `.linux`,
+//! `.ucode` and `.initrd` sections into memfds for kexec. The microcode is loaded before the
+//! initrd, like systemd-stub does.
+//!
+//! The `.cmdline` of the UKI is merged with the command line transformed from the usb one
+//! according to `usbkexec.uki.cmdline=`, see [`CmdlinePolicy`].
+//!
+//! The `build-uki` subcommand builds the first stage on the usb itself as a UKI, from the systemd
+//! EFI stub, the usb kernel, microcode, the usb initramfs and a command line with the `usbkexec.*`
+//! parameters, so that the usb needs no boot loader config. See [`build_uki_command`].
+
+use std::{fs::{self, File}, io::Read, path::{Path, PathBuf}, process::Command};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    authenticode::TrustStore,
+    bls, cli,
+    cmdline::KernelCommandLine,
+    generator,
+    initramfs_kexec_runner::{BASE64_PREFIX, HEX_PREFIX, TransformParameters, encode_additional_args},
+    overlay,
+    pe::{self, PeError, PeImage},
+    target_root::TargetRoot,
+};
+
+/// The kernel parameter with the path of the UKI in the real system.
+pub const UKI_KEY: &str = "usbkexec.uki";
+/// The kernel parameter that selects the [`CmdlinePolicy`].
+pub const CMDLINE_POLICY_KEY: &str = "usbkexec.uki.cmdline";
+
+/// How the `.cmdline` of the UKI is merged with the transformed command line. The kernel takes
+/// the last value of a parameter given more than once, so the later command line wins.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum CmdlinePolicy {
+    /// `embedded`: only the `.cmdline` of the UKI.
+    Embedded,
+    /// `transformed`: only the transformed command line.
+    Transformed,
+    /// `embedded-first`: the `.cmdline` of the UKI, then the transformed command line.
+    #[default]
+    EmbeddedFirst,
+    /// `transformed-first`: the transformed command line, then the `.cmdline` of the UKI.
+    TransformedFirst,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum UkiError {
+    #[error(transparent)]
+    Pe(#[from] PeError),
+    #[error("the PE image has no .linux section, it is not a UKI")]
+    MissingLinux,
+    #[error("unknown {}=\"{value}\", expected embedded, transformed, embedded-first or transformed-first", CMDLINE_POLICY_KEY)]
+    UnknownPolicy {
+        value: String,
+    },
+}
+
+impl CmdlinePolicy {
+    pub fn from_command_line(command_line: &KernelCommandLine) -> Result<CmdlinePolicy, UkiError> {
+        match command_line.get(CMDLINE_POLICY_KEY) {
+            None | Some("embedded-first") => Ok(CmdlinePolicy::EmbeddedFirst),
+            Some("embedded") => Ok(CmdlinePolicy::Embedded),
+            Some("transformed") => Ok(CmdlinePolicy::Transformed),
+            Some("transformed-first") => Ok(CmdlinePolicy::TransformedFirst),
+            Some(value) => Err(UkiError::UnknownPolicy { value: value.to_string() }),
+        }
+    }
+
+    /// Merges the command lines. Both are expected to be empty or end with a space, like the
+    /// transformed command line.
+    pub fn merge(self, embedded: &str, transformed: &str) -> String {
+        match self {
+            CmdlinePolicy::Embedded => embedded.to_string(),
+            CmdlinePolicy::Transformed => transformed.to_string(),
+            CmdlinePolicy::EmbeddedFirst => format!("{}{}", embedded, transformed),
+            CmdlinePolicy::TransformedFirst => format!("{}{}", transformed, embedded),
+        }
+    }
+}
+
+/// The sections of a UKI that are used for kexec.
+#[derive(Debug, PartialEq)]
+pub struct Uki<'a> {
+    pub linux: &'a [u8],
+    pub initrd: Option<&'a [u8]>,
+    pub ucode: Option<&'a [u8]>,
+    /// The `.cmdline`, followed by a space if it is not empty.
+    pub cmdline: String,
+    /// The `.osrel`, the os-release of the system the UKI belongs to.
+    pub osrel: Option<String>,
+    /// The `.uname`, the version of the kernel.
+    pub uname: Option<String>,
+}
+
+impl<'a> Uki<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Uki<'a>, UkiError> {
+        let image = PeImage::parse(data)?;
+        let text = |name| -> Result<Option<String>, PeError> {
+            Ok(image.section_data(data, name)?.map(|x| String::from_utf8_lossy(x).trim_end_matches('\0').trim().to_string()))
+        };
+        let mut cmdline = text(".cmdline")?.unwrap_or_default();
+        if !cmdline.is_empty() {
+            cmdline.push(' ');
+        }
+        Ok(Uki {
+            linux: image.section_data(data, ".linux")?.ok_or(UkiError::MissingLinux)?,
+            initrd: image.section_data(data, ".initrd")?,
+            ucode: image.section_data(data, ".ucode")?,
+            cmdline,
+            osrel: text(".osrel")?,
+            uname: text(".uname")?,
+        })
+    }
+
+    /// The `PRETTY_NAME` of the `.osrel`, or else its `NAME`.
+    pub fn pretty_name(&self) -> Option<String> {
+        let osrel = self.osrel.as_ref()?;
+        let value = |key: &str| osrel.lines()
+            .find_map(|x| x.strip_prefix(key)?.strip_prefix('='))
+            .map(|x| x.trim_matches(['"', '\'']).to_string());
+        value("PRETTY_NAME").or_else(|| value("NAME"))
+    }
+}
+
+/// The kernel and initrds of a UKI, in memfds that have to stay open until kexec has loaded them.
+#[derive(Debug)]
+pub struct ExtractedUki {
+    pub kernel: File,
+    /// The microcode, then the initrd, if the UKI has them.
+    pub initrds: Vec<File>,
+    pub cmdline: String,
+    pub pretty_name: Option<String>,
+    pub uname: Option<String>,
+}
+
+/// Reads the UKI at `path` in the real system and writes its payloads into memfds. If `trust`
+/// is given, the Authenticode signature of the UKI is verified first.
+pub fn extract(target_root: &TargetRoot, path: &str, trust: Option<&TrustStore>) -> Result<ExtractedUki> {
+    let mut data = Vec::new();
+    target_root.open(path)?.read_to_end(&mut data)?;
+    if let Some(trust) = trust {
+        if let Err(e) = trust.verify(&data) {
+            bail!("failed to verify the UKI \"{}\": {}", path, e);
+        }
+    }
+    let uki = match Uki::parse(&data) {
+        Ok(x) => x,
+        Err(e) => bail!("failed to read the UKI \"{}\": {}", path, e),
+    };
+    let mut initrds = Vec::new();
+    for (name, section) in [("usb-boot-ucode", uki.ucode), ("usb-boot-uki-initrd", uki.initrd)] {
+        if let Some(section) = section {
+            initrds.push(overlay::memfd_with_contents(name, section)?);
+        }
+    }
+    Ok(ExtractedUki {
+        kernel: overlay::memfd_with_contents("usb-boot-kernel", uki.linux)?,
+        initrds,
+        pretty_name: uki.pretty_name(),
+        uname: uki.uname,
+        cmdline: uki.cmdline,
+    })
+}
+
+/// The payloads of a UKI to build.
+#[derive(Debug, Default)]
+pub struct UkiContents<'a> {
+    pub os_release: Option<&'a [u8]>,
+    pub cmdline: &'a str,
+    pub ucode: Option<&'a [u8]>,
+    pub initrd: Option<&'a [u8]>,
+    pub linux: &'a [u8],
+}
+
+/// Builds a UKI by adding the payloads as sections to the EFI stub. The `.linux` section is
+/// placed last, like ukify does, since the kernel may use more memory than the section holds.
+pub fn build(stub: &[u8], contents: &UkiContents) -> Result<Vec<u8>, PeError> {
+    let sections = [
+        (".osrel", contents.os_release),
+        (".cmdline", Some(contents.cmdline.as_bytes())),
+        (".ucode", contents.ucode),
+        (".initrd", contents.initrd),
+        (".linux", Some(contents.linux)),
+    ];
+    let sections: Vec<(&str, &[u8])> = sections.into_iter()
+        .filter_map(|(name, data)| Some((name, data?)))
+        .collect();
+    pe::add_sections(stub, &sections)
+}
+
+/// Returns the command line of the first stage: `base`, followed by the given parameters.
+/// The value of the additional arguments is quoted, since it usually has spaces, or encoded if it
+/// contains both kinds of quotes. The other values cannot be quoted, since the runner uses them as
+/// they are.
+pub fn first_stage_command_line(base: &str, parameters: &[(&str, &str)]) -> Result<String> {
+    let additional_args_key = TransformParameters::default().additional_args;
+    let mut command_line = base.trim().to_string();
+    for (key, value) in parameters {
+        let needs_quotes = value.is_empty() || value.contains(|c: char| c.is_ascii_whitespace() || c == '"' || c == '\'');
+        let is_additional_args = *key == additional_args_key;
+        let looks_encoded = [BASE64_PREFIX, HEX_PREFIX].iter().any(|x| value.starts_with(x));
+        let value = match (needs_quotes, is_additional_args) {
+            (_, true) if looks_encoded => encode_additional_args(value),
+            (false, _) => value.to_string(),
+            (true, true) if !value.contains('"') => format!("\"{}\"", value),
+            (true, true) if !value.contains('\'') => format!("'{}'", value),
+            (true, true) => encode_additional_args(value),
+            (true, false) => bail!("the value of {} cannot contain spaces or quotes: \"{}\"", key, value),
+        };
+        if !command_line.is_empty() {
+            command_line.push(' ');
+        }
+        command_line.push_str(&format!("{}={}", key, value));
+    }
+    Ok(command_line)
+}
+
+/// The EFI stub of systemd for this architecture.
+fn default_stub() -> String {
+    format!("/usr/lib/systemd/boot/efi/linux{}.efi.stub", bls::native_architecture())
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("failed to read \"{}\"", path))
+}
+
+/// Entry point of the `build-uki` subcommand.
+///
+/// Usage: `build-uki --output <file> [--stub <file>] [--kernel <file>] [--initrd <file>]
+/// [--microcode <file>]... [--os-release <file>] [--cmdline <parameters>]
+/// [--target-kernel <path>] [--target-initrd <path>] [--target-args <parameters>]
+/// [--param <key>=<value>]... [--sign-key <file> --sign-cert <file>]`
+///
+/// Builds the first stage as a UKI. The command line is `--cmdline`, followed by the
+/// `usbkexec.kernel`, `usbkexec.initrd` and `usbkexec.args` parameters for the `--target-*`
+/// options and the `--param` parameters, like `--param usbkexec.entry=default`. The kernel and
+/// initramfs default to the ones of the `usb-boot` preset. The output only depends on the inputs.
+/// With `--sign-key` and `--sign-cert`, the UKI is signed with sbsign for Secure Boot.
+pub fn build_uki_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        options: &[
+            "--output", "--stub", "--kernel", "--initrd", "--os-release", "--cmdline",
+            "--target-kernel", "--target-initrd", "--target-args", "--sign-key", "--sign-cert",
+        ],
+        repeated_options: &["--microcode", "--param"],
+        required: &["--output"],
+        ..Default::default()
+    })?;
+    let keys = TransformParameters::default();
+
+    let mut parameters = Vec::new();
+    for (option, key) in [("--target-kernel", &keys.kernel), ("--target-initrd", &keys.initrd), ("--target-args", &keys.additional_args)] {
+        if let Some(value) = args.value(option) {
+            parameters.push((key.as_str(), value));
+        }
+    }
+    for parameter in args.values("--param") {
+        match parameter.split_once('=') {
+            Some(x) => parameters.push(x),
+            None => bail!("expected --param <key>=<value>, but got \"{}\"", parameter),
+        }
+    }
+    let cmdline = first_stage_command_line(args.value("--cmdline").unwrap_or(""), &parameters)?;
+    if !generator::is_enabled(&KernelCommandLine::parse(&cmdline), &keys) {
+        bail!("the command line \"{}\" does not request kexec, give --target-kernel or a --param", cmdline);
+    }
+
+    let stub = read(args.value("--stub").unwrap_or(&default_stub()))?;
+    let linux = read(args.value("--kernel").unwrap_or("/boot/usb-boot/kernel"))?;
+    let initrd = read(args.value("--initrd").unwrap_or("/boot/usb-boot/initramfs.img"))?;
+    let mut ucode = Vec::new();
+    for path in args.values("--microcode") {
+        ucode.extend(read(path)?);
+    }
+    let os_release = match args.value("--os-release") {
+        Some(path) => Some(read(path)?),
+        None => fs::read("/etc/os-release").ok(),
+    };
+    let uki = build(&stub, &UkiContents {
+        os_release: os_release.as_deref(),
+        cmdline: &cmdline,
+        ucode: Some(ucode.as_slice()).filter(|x| !x.is_empty()),
+        initrd: Some(&initrd),
+        linux: &linux,
+    })?;
+
+    let output = PathBuf::from(args.value("--output").unwrap());
+    let mut temporary = output.clone().into_os_string();
+    temporary.push(".usb-boot-new");
+    let temporary = PathBuf::from(temporary);
+    fs::write(&temporary, uki)?;
+    match (args.value("--sign-key"), args.value("--sign-cert")) {
+        (Some(key), Some(cert)) => {
+            let result = sign(&temporary, key, cert, &output);
+            fs::remove_file(&temporary)?;
+            result?;
+        },
+        (None, None) => fs::rename(&temporary, &output)?,
+        _ => {
+            fs::remove_file(&temporary)?;
+            bail!("--sign-key and --sign-cert have to be given together");
+        },
+    }
+    println!("built {} with the command line: {}", output.display(), cmdline);
+    Ok(())
+}
+
+/// Signs the UKI with sbsign.
+fn sign(unsigned: &Path, key: &str, cert: &str, output: &Path) -> Result<()> {
+    let success = Command::new("sbsign")
+        .args(["--key", key, "--cert", cert, "--output"])
+        .arg(output)
+        .arg(unsigned)
+        .status()?
+        .success();
+    if !success {
+        bail!("failed to sign \"{}\" with sbsign", output.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::tests::build_image;
+
+    #[test]
+    fn test_parse() {
+        let data = build_image(&[
+            (".osrel", b"NAME=\"Arch Linux\"\nPRETTY_NAME=\"Arch Linux (rolling)\"\n"),
+            (".cmdline", b"root=/dev/mapper/root rw \n\0"),
+            (".uname", b"6.10.3-arch1-1"),
+            (".ucode", b"microcode"),
+            (".initrd", b"initramfs"),
+            (".linux", b"kernel"),
+        ]);
+        let uki = Uki::parse(&data).unwrap();
+        assert_eq!(uki.linux, b"kernel");
+        assert_eq!(uki.initrd, Some(&b"initramfs"[..]));
+        assert_eq!(uki.ucode, Some(&b"microcode"[..]));
+        assert_eq!(uki.cmdline, "root=/dev/mapper/root rw ");
+        assert_eq!(uki.pretty_name().as_deref(), Some("Arch Linux (rolling)"));
+        assert_eq!(uki.uname.as_deref(), Some("6.10.3-arch1-1"));
+
+        let minimal = build_image(&[(".linux", b"kernel")]);
+        let uki = Uki::parse(&minimal).unwrap();
+        assert_eq!((uki.initrd, uki.ucode, uki.cmdline.as_str(), uki.pretty_name()), (None, None, "", None));
+
+        assert_eq!(Uki::parse(&build_image(&[(".text", b"stub")])), Err(UkiError::MissingLinux));
+    }
+
+    #[test]
+    fn test_cmdline_policy() {
+        let test_cases = [
+            ("quiet", Ok("root=/dev/sda2 quiet debug ")),
+            ("usbkexec.uki.cmdline=embedded", Ok("root=/dev/sda2 quiet ")),
+            ("usbkexec.uki.cmdline=transformed", Ok("debug ")),
+            ("usbkexec.uki.cmdline=transformed-first", Ok("debug root=/dev/sda2 quiet ")),
+            ("usbkexec.uki.cmdline=both", Err(UkiError::UnknownPolicy { value: "both".to_string() })),
+        ];
+        for (command_line, expected) in test_cases {
+            let policy = CmdlinePolicy::from_command_line(&KernelCommandLine::parse(command_line));
+            assert_eq!(policy.map(|x| x.merge("root=/dev/sda2 quiet ", "debug ")), expected.map(|x| x.to_string()), "{}", command_line);
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let stub = build_image(&[(".text", b"stub")]);
+        let cmdline = first_stage_command_line("quiet", &[
+            ("usbkexec.kernel", "/boot/vmlinuz-linux"),
+            ("usbkexec.args", "root=/dev/mapper/root rw"),
+        ]).unwrap();
+        assert_eq!(cmdline, r#"quiet usbkexec.kernel=/boot/vmlinuz-linux usbkexec.args="root=/dev/mapper/root rw""#);
+
+        let contents = UkiContents { cmdline: &cmdline, initrd: Some(b"initramfs"), linux: b"kernel", ..Default::default() };
+        let data = build(&stub, &contents).unwrap();
+        assert_eq!(build(&stub, &contents).unwrap(), data);
+        let uki = Uki::parse(&data).unwrap();
+        assert_eq!((uki.linux, uki.initrd, uki.ucode), (&b"kernel"[..], Some(&b"initramfs"[..]), None));
+        assert_eq!(uki.cmdline, format!("{} ", cmdline));
+
+        let test_cases = [
+            ("usbkexec.args", r#"acpi_osi="!Windows 2020""#, Ok(r#"usbkexec.args='acpi_osi="!Windows 2020"'"#)),
+            ("usbkexec.args", r#"a="b c" d='e f'"#, Ok("usbkexec.args=base64:YT0iYiBjIiBkPSdlIGYn")),
+            ("usbkexec.args", "hex:00", Ok("usbkexec.args=base64:aGV4OjAw")),
+            ("usbkexec.kernel", "/boot/my kernel", Err(())),
+        ];
+        for (key, value, expected) in test_cases {
+            assert_eq!(first_stage_command_line("", &[(key, value)]).map_err(|_| ()), expected.map(|x| x.to_string()), "{}", value);
+        }
+    }
+}
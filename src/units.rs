@@ -0,0 +1,603 @@
+//! Generation and checking of the systemd units used in the initramfs.
+//!
+//! The `usb-boot` mkinitcpio hook adds a service that runs `usb-boot kexec`, and a drop-in that
+//! replaces the `ExecStart=` of `initrd-switch-root.service`, so that the initramfs kexecs into
+//! the real kernel instead of switching root. Both are generated from the templates below.
+//! If kexec fails, the runner switches root with the usb kernel, or with `--on-failure=emergency`
+//! fails the service, so that systemd starts emergency mode.
+//! Generated units are checked with a subset of the rules `systemd-analyze verify` applies
+//! before they are written.
+//! The kernel command line keys the runner is given are written next to the units, in
+//! [`KEYS_FILE_NAME`], so that the generator (see [`crate::generator`]) looks for the same keys.
+
+use std::{fs, path::{Path, PathBuf}};
+
+use anyhow::{Result, bail};
+use common::AggregateError;
+
+use crate::{cli, initramfs_kexec_runner::{self, OnFailure, TransformParameters}};
+
+pub const KEXEC_UNIT_NAME: &str = "initrd-kexec-real-kernel.service";
+pub const SWITCH_ROOT_DROP_IN: &str = "initrd-switch-root.service.d/usb_initramfs.conf";
+/// The file recording the kernel command line keys of the runner, see [`keys_file`].
+pub const KEYS_FILE_NAME: &str = "keys.conf";
+/// Where the `usb-boot` mkinitcpio hook takes the generated units from.
+pub const DEFAULT_OUTPUT_DIRECTORY: &str = "/etc/usb-boot";
+
+const KEXEC_UNIT_TEMPLATE: &str = "\
+# Generated by usb-boot gen-units.
+[Unit]
+Description=Kexec into the real kernel on the encrypted root
+DefaultDependencies=no
+After={after}
+OnFailure=emergency.target
+ConditionPathExists=/etc/initrd-release
+
+[Service]
+Type=oneshot
+ExecStart={exec_start}
+TimeoutStartSec={timeout}
+StandardOutput=journal+console
+StandardError=journal+console
+";
+
+const SWITCH_ROOT_DROP_IN_TEMPLATE: &str = "\
+# Generated by usb-boot gen-units.
+[Unit]
+Wants={kexec_unit}
+
+[Service]
+ExecStart=
+ExecStart=true
+";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnitOptions {
+    /// Units the kexec service is ordered after.
+    pub after: Vec<String>,
+    /// Systemd time span for `TimeoutStartSec=`.
+    pub timeout: String,
+    /// What the runner does when kexec fails.
+    pub on_failure: OnFailure,
+    /// Path of the runner inside the initramfs.
+    pub runner: String,
+    /// Arguments of the runner after the `kexec` subcommand.
+    pub runner_args: Vec<String>,
+}
+impl Default for UnitOptions {
+    fn default() -> Self {
+        let option_names = initramfs_kexec_runner::option_names();
+        let keys = TransformParameters::default();
+        UnitOptions {
+            after: vec!["initrd-fs.target".to_string()],
+            timeout: "90s".to_string(),
+            on_failure: OnFailure::default(),
+            runner: "/usr/bin/usb-boot".to_string(),
+            runner_args: vec![
+                option_names.additional_args, keys.additional_args,
+                option_names.kernel, keys.kernel,
+                option_names.initrd, keys.initrd,
+            ],
+        }
+    }
+}
+
+/// Quotes an argument for a systemd `ExecStart=` line, if needed.
+fn quote_exec_argument(argument: &str) -> String {
+    if !argument.is_empty() && !argument.contains(|c: char| c.is_whitespace() || "\"'\\;$%".contains(c)) {
+        return argument.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in argument.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            },
+            // Specifiers and environment variables are expanded even inside quotes.
+            '%' => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Generates the kexec service.
+pub fn kexec_unit(options: &UnitOptions) -> String {
+    // The runner switches root by default, so the option is only given for emergency mode.
+    let on_failure = match options.on_failure {
+        OnFailure::Emergency => Some(format!("{}={}", initramfs_kexec_runner::ON_FAILURE_OPTION, OnFailure::Emergency.name())),
+        OnFailure::SwitchRoot => None,
+    };
+    let exec_start: Vec<String> = [options.runner.as_str(), "kexec"].into_iter()
+        .chain(on_failure.as_deref())
+        .chain(options.runner_args.iter().map(|x| x.as_str()))
+        .map(quote_exec_argument)
+        .collect();
+    KEXEC_UNIT_TEMPLATE
+        .replace("{after}", &options.after.join(" "))
+        .replace("{exec_start}", &exec_start.join(" "))
+        .replace("{timeout}", &options.timeout)
+}
+
+/// Generates the drop-in for `initrd-switch-root.service`.
+pub fn switch_root_drop_in() -> String {
+    SWITCH_ROOT_DROP_IN_TEMPLATE.replace("{kexec_unit}", KEXEC_UNIT_NAME)
+}
+
+/// Generates every file for `options`, as pairs of paths relative to the output directory and
+/// contents.
+pub fn generate(options: &UnitOptions) -> Vec<(&'static str, String)> {
+    vec![
+        (KEXEC_UNIT_NAME, kexec_unit(options)),
+        (SWITCH_ROOT_DROP_IN, switch_root_drop_in()),
+    ]
+}
+
+/// Generates the file recording the kernel command line `keys` of the runner, with one
+/// `name=key` line per key.
+pub fn keys_file(keys: &TransformParameters) -> String {
+    format!(
+        "# Generated by usb-boot gen-units.\nadditional_args={}\nkernel={}\ninitrd={}\ndtb={}\n",
+        keys.additional_args, keys.kernel, keys.initrd, keys.dtb,
+    )
+}
+
+/// Parses a file generated by [`keys_file`]. Keys that are not given keep their default.
+pub fn parse_keys_file(contents: &str) -> Result<TransformParameters> {
+    let mut keys = TransformParameters::default();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, key)) = line.split_once('=') else {
+            bail!("line {}: expected \"name=key\"", i + 1);
+        };
+        let field = match name.trim() {
+            "additional_args" => &mut keys.additional_args,
+            "kernel" => &mut keys.kernel,
+            "initrd" => &mut keys.initrd,
+            "dtb" => &mut keys.dtb,
+            name => bail!("line {}: unknown key name {}", i + 1, name),
+        };
+        *field = key.trim().to_string();
+    }
+    Ok(keys)
+}
+
+/// Returns the kernel command line keys `runner_args` make the runner use.
+fn runner_keys(runner_args: &[String]) -> Result<TransformParameters> {
+    let option_names = initramfs_kexec_runner::option_names().try_into().unwrap();
+    match initramfs_kexec_runner::parse_args(runner_args.iter().cloned(), option_names) {
+        Ok(config) => Ok(config.transform_parameters.keys().clone()),
+        Err(errors) => bail!("invalid runner arguments: {}", errors),
+    }
+}
+
+/// Represents a problem found in a unit file by [`check_unit`].
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum UnitCheckError {
+    #[error("line {line}: unknown section [{section}]")]
+    UnknownSection {
+        line: usize,
+        section: String,
+    },
+    #[error("line {line}: assignment outside of a section")]
+    AssignmentOutsideSection {
+        line: usize,
+    },
+    #[error("line {line}: expected \"key=value\"")]
+    MalformedLine {
+        line: usize,
+    },
+    #[error("line {line}: unknown key {key} in section [{section}]")]
+    UnknownKey {
+        line: usize,
+        section: String,
+        key: String,
+    },
+    #[error("line {line}: invalid value for {key}: {value}")]
+    InvalidValue {
+        line: usize,
+        key: String,
+        value: String,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum ValueKind {
+    Text,
+    Boolean,
+    TimeSpan,
+    UnitNames,
+    ServiceType,
+    Command,
+    Output,
+}
+
+/// The keys accepted by [`check_unit`] in each section, and the kind of value they take.
+const KNOWN_KEYS: &[(&str, &[(&str, ValueKind)])] = &[
+    ("Unit", &[
+        ("Description", ValueKind::Text),
+        ("Documentation", ValueKind::Text),
+        ("DefaultDependencies", ValueKind::Boolean),
+        ("After", ValueKind::UnitNames),
+        ("Before", ValueKind::UnitNames),
+        ("Wants", ValueKind::UnitNames),
+        ("Requires", ValueKind::UnitNames),
+        ("Requisite", ValueKind::UnitNames),
+        ("BindsTo", ValueKind::UnitNames),
+        ("PartOf", ValueKind::UnitNames),
+        ("Conflicts", ValueKind::UnitNames),
+        ("OnFailure", ValueKind::UnitNames),
+        ("OnSuccess", ValueKind::UnitNames),
+        ("ConditionPathExists", ValueKind::Text),
+        ("ConditionKernelCommandLine", ValueKind::Text),
+        ("AssertPathExists", ValueKind::Text),
+    ]),
+    ("Service", &[
+        ("Type", ValueKind::ServiceType),
+        ("ExecStart", ValueKind::Command),
+        ("ExecStartPre", ValueKind::Command),
+        ("ExecStartPost", ValueKind::Command),
+        ("ExecStop", ValueKind::Command),
+        ("RemainAfterExit", ValueKind::Boolean),
+        ("TimeoutSec", ValueKind::TimeSpan),
+        ("TimeoutStartSec", ValueKind::TimeSpan),
+        ("TimeoutStopSec", ValueKind::TimeSpan),
+        ("StandardInput", ValueKind::Text),
+        ("StandardOutput", ValueKind::Output),
+        ("StandardError", ValueKind::Output),
+        ("Environment", ValueKind::Text),
+        ("EnvironmentFile", ValueKind::Text),
+    ]),
+    ("Install", &[
+        ("WantedBy", ValueKind::UnitNames),
+        ("RequiredBy", ValueKind::UnitNames),
+        ("Also", ValueKind::UnitNames),
+        ("Alias", ValueKind::UnitNames),
+    ]),
+];
+
+fn is_valid_boolean(value: &str) -> bool {
+    ["1", "yes", "y", "true", "t", "on", "0", "no", "n", "false", "f", "off"].contains(&value.to_lowercase().as_str())
+}
+
+/// Checks a systemd time span, such as `90s`, `5min 30s`, `1h30min` or `infinity`.
+/// A number without a unit is in seconds.
+pub fn is_valid_time_span(value: &str) -> bool {
+    const UNITS: &[&str] = &[
+        "usec", "us", "µs", "msec", "ms", "seconds", "second", "sec", "s", "minutes", "minute", "min", "m",
+        "hours", "hour", "hr", "h", "days", "day", "d", "weeks", "week", "w", "months", "month", "M",
+        "years", "year", "y",
+    ];
+    let value = value.trim();
+    if value == "infinity" {
+        return true;
+    }
+    let mut rest = value;
+    let mut any = false;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        if digits == 0 || rest[..digits].parse::<f64>().is_err() {
+            return false;
+        }
+        rest = rest[digits..].trim_start();
+        let unit_length = rest.find(|c: char| c.is_ascii_digit() || c.is_whitespace()).unwrap_or(rest.len());
+        let unit = &rest[..unit_length];
+        if !unit.is_empty() && !UNITS.contains(&unit) {
+            return false;
+        }
+        rest = rest[unit_length..].trim_start();
+        any = true;
+    }
+    any
+}
+
+fn is_valid_unit_name(name: &str) -> bool {
+    const SUFFIXES: &[&str] = &[
+        ".service", ".socket", ".device", ".mount", ".automount", ".swap", ".target", ".path", ".timer",
+        ".slice", ".scope",
+    ];
+    let prefix = match SUFFIXES.iter().find_map(|x| name.strip_suffix(x)) {
+        Some(x) => x,
+        None => return false,
+    };
+    name.len() <= 255 &&
+        !prefix.is_empty() &&
+        prefix.chars().all(|c| c.is_ascii_alphanumeric() || ":-_.\\@".contains(c))
+}
+
+fn is_valid_command(value: &str) -> bool {
+    // An empty assignment resets the list of commands.
+    if value.is_empty() {
+        return true;
+    }
+    let executable = value.trim_start_matches(['@', '-', ':', '+', '!']);
+    if executable.is_empty() || executable.starts_with(char::is_whitespace) {
+        return false;
+    }
+    // Quotes must be balanced.
+    let mut quote = None;
+    let mut escaped = false;
+    for c in executable.chars() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (_, '\\') => escaped = true,
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => (),
+        }
+    }
+    quote.is_none() && !escaped
+}
+
+fn is_valid_value(kind: ValueKind, value: &str) -> bool {
+    match kind {
+        ValueKind::Text => true,
+        ValueKind::Boolean => is_valid_boolean(value),
+        ValueKind::TimeSpan => is_valid_time_span(value),
+        ValueKind::UnitNames => value.split_whitespace().all(is_valid_unit_name),
+        ValueKind::ServiceType => ["simple", "exec", "forking", "oneshot", "dbus", "notify", "notify-reload", "idle"].contains(&value),
+        ValueKind::Command => is_valid_command(value),
+        ValueKind::Output => {
+            ["inherit", "null", "tty", "journal", "kmsg", "journal+console", "kmsg+console", "socket"].contains(&value) ||
+                ["file:", "append:", "truncate:", "fd:"].iter().any(|x| value.starts_with(x))
+        },
+    }
+}
+
+/// Checks the syntax of a unit file or drop-in, the names of the sections and keys in it, and
+/// the values of the keys it knows the types of.
+pub fn check_unit(unit: &str) -> Result<(), AggregateError<UnitCheckError>> {
+    let mut errors = Vec::new();
+    let mut section: Option<(&str, &[(&str, ValueKind)])> = None;
+    let mut in_unknown_section = false;
+
+    // Join continuation lines, remembering the line each logical line started on.
+    let mut logical_lines: Vec<(usize, String)> = Vec::new();
+    let mut continued = false;
+    for (i, line) in unit.lines().enumerate() {
+        let (content, continues) = match line.strip_suffix('\\') {
+            Some(x) => (x, true),
+            None => (line, false),
+        };
+        match logical_lines.last_mut() {
+            Some((_, last)) if continued => last.push_str(content),
+            _ => logical_lines.push((i + 1, content.to_string())),
+        }
+        continued = continues;
+    }
+
+    for (line_number, line) in &logical_lines {
+        let line_number = *line_number;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            section = KNOWN_KEYS.iter().find(|(x, _)| *x == name).copied();
+            in_unknown_section = section.is_none();
+            if in_unknown_section {
+                errors.push(UnitCheckError::UnknownSection { line: line_number, section: name.to_string() });
+            }
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                errors.push(UnitCheckError::MalformedLine { line: line_number });
+                continue;
+            },
+        };
+        let (section_name, keys) = match section {
+            Some(x) => x,
+            None => {
+                if !in_unknown_section {
+                    errors.push(UnitCheckError::AssignmentOutsideSection { line: line_number });
+                }
+                continue;
+            },
+        };
+        match keys.iter().find(|(x, _)| *x == key) {
+            None => errors.push(UnitCheckError::UnknownKey {
+                line: line_number,
+                section: section_name.to_string(),
+                key: key.to_string(),
+            }),
+            Some((_, kind)) => if !is_valid_value(*kind, value) {
+                errors.push(UnitCheckError::InvalidValue {
+                    line: line_number,
+                    key: key.to_string(),
+                    value: value.to_string(),
+                });
+            },
+        }
+    }
+
+    if let Ok(aggregate) = AggregateError::try_from(errors) {
+        return Err(aggregate);
+    }
+    Ok(())
+}
+
+/// Entry point of the `gen-units` subcommand.
+///
+/// Usage: `gen-units [--output-dir <dir>] [--after <unit>]... [--timeout <time span>]
+/// [--on-failure emergency|switch-root] [--runner <path>] [--runner-arg <arg>]... [--check]`
+///
+/// Generates the units into the output directory. `--after` and `--runner-arg` replace the
+/// default ordering and runner arguments when given.
+/// The kernel command line keys of the runner arguments are written to [`KEYS_FILE_NAME`].
+/// With `--check`, the units already in the output directory are checked instead.
+pub fn gen_units_command(args: impl IntoIterator<Item=String>) -> Result<()> {
+    let args = cli::parse(args, cli::ArgSpec {
+        flags: &["--check"],
+        options: &["--output-dir", "--timeout", "--on-failure", "--runner"],
+        repeated_options: &["--after", "--runner-arg"],
+        ..Default::default()
+    })?;
+    let output_directory = PathBuf::from(args.value("--output-dir").unwrap_or(DEFAULT_OUTPUT_DIRECTORY));
+
+    let mut options = UnitOptions::default();
+    if args.values("--after").next().is_some() {
+        options.after = args.values("--after").map(|x| x.to_string()).collect();
+    }
+    if let Some(timeout) = args.value("--timeout") {
+        options.timeout = timeout.to_string();
+    }
+    if let Some(value) = args.value("--on-failure") {
+        options.on_failure = match OnFailure::parse(value) {
+            Some(x) => x,
+            None => bail!("unknown --on-failure action: {}", value),
+        };
+    }
+    if let Some(runner) = args.value("--runner") {
+        options.runner = runner.to_string();
+    }
+    if args.values("--runner-arg").next().is_some() {
+        options.runner_args = args.values("--runner-arg").map(|x| x.to_string()).collect();
+    }
+
+    let files = generate(&options);
+    if args.flag("--check") {
+        let mut failed = false;
+        for (name, _) in &files {
+            let path = output_directory.join(name);
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("failed to read \"{}\": {}", path.display(), e))?;
+            if let Err(errors) = check_unit(&contents) {
+                eprintln!("{}: {}", path.display(), errors);
+                failed = true;
+            }
+        }
+        if failed {
+            bail!("some units failed the check");
+        }
+        return Ok(());
+    }
+
+    for (name, contents) in &files {
+        if let Err(errors) = check_unit(contents) {
+            bail!("generated {} is invalid: {}", name, errors);
+        }
+    }
+    let keys = keys_file(&runner_keys(&options.runner_args)?);
+    for (name, contents) in files.iter().chain([&(KEYS_FILE_NAME, keys)]) {
+        let path = output_directory.join(name);
+        fs::create_dir_all(path.parent().unwrap_or(Path::new("/")))?;
+        fs::write(&path, contents)?;
+        println!("wrote {}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::size_based_container::SizeBasedContainer;
+
+    #[test]
+    fn test_generate() {
+        for (name, contents) in generate(&Default::default()) {
+            assert_eq!(check_unit(&contents), Ok(()), "{}", name);
+        }
+
+        let options = UnitOptions {
+            after: vec!["initrd-fs.target".to_string(), "cryptsetup.target".to_string()],
+            timeout: "2min".to_string(),
+            runner_args: vec!["--kernel".to_string(), "my kernel=\"%x\"".to_string()],
+            ..Default::default()
+        };
+        let unit = kexec_unit(&options);
+        assert!(unit.contains("\nAfter=initrd-fs.target cryptsetup.target\n"));
+        assert!(unit.contains("\nTimeoutStartSec=2min\n"));
+        assert!(unit.contains("\nOnFailure=emergency.target\n"));
+        assert!(unit.contains("\nExecStart=/usr/bin/usb-boot kexec --kernel \"my kernel=\\\"%%x\\\"\"\n"));
+
+        let options = UnitOptions { on_failure: OnFailure::Emergency, ..Default::default() };
+        let unit = kexec_unit(&options);
+        assert_eq!(check_unit(&unit), Ok(()));
+        assert!(unit.contains("\nExecStart=/usr/bin/usb-boot kexec --on-failure=emergency --additional_args usbkexec.args "));
+        let runner_args = options.runner_args.iter().cloned();
+        let config = initramfs_kexec_runner::parse_args(["--on-failure=emergency".to_string()].into_iter().chain(runner_args), initramfs_kexec_runner::option_names().try_into().unwrap());
+        assert_eq!(config.unwrap().on_failure, OnFailure::Emergency);
+    }
+
+    #[test]
+    fn test_keys_file() {
+        let keys = TransformParameters {
+            additional_args: "a.args".to_string(),
+            kernel: "a.kernel".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(parse_keys_file(&keys_file(&keys)).unwrap(), keys);
+        assert_eq!(parse_keys_file("# comment\n\nkernel = k\n").unwrap().kernel, "k");
+        assert!(parse_keys_file("kernel").is_err());
+        assert!(parse_keys_file("root=k").is_err());
+
+        let runner_args = ["--kernel=a.kernel", "--initrd", "a.initrd", "--additional_args=a.args"].map(String::from);
+        let keys = runner_keys(&runner_args).unwrap();
+        assert_eq!((keys.kernel.as_str(), keys.initrd.as_str(), keys.dtb), ("a.kernel", "a.initrd", TransformParameters::default().dtb));
+        assert_eq!(runner_keys(&UnitOptions::default().runner_args).unwrap(), TransformParameters::default());
+        assert!(runner_keys(&["--kernel=a.kernel".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_is_valid_time_span() {
+        for valid in ["90", "90s", "5min 30s", "1h30min", "1.5s", "infinity", " 2 min "] {
+            assert!(is_valid_time_span(valid), "{}", valid);
+        }
+        for invalid in ["", "s", "5 lightyears", "-5s", "1..5s", "5min x"] {
+            assert!(!is_valid_time_span(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_check_unit() {
+        let single = |error| Err(SizeBasedContainer::from_single(error).try_into().unwrap());
+        let test_cases = [
+            ("[Unit]\nAfter=a.target \\\n  b.service\n", Ok(())),
+            ("[Service]\nExecStart=\nExecStart=-/bin/true \"a b\"\n", Ok(())),
+            ("[Sevrice]\nType=oneshot\n", single(UnitCheckError::UnknownSection { line: 1, section: "Sevrice".to_string() })),
+            ("Type=oneshot\n", single(UnitCheckError::AssignmentOutsideSection { line: 1 })),
+            ("[Unit]\nAfter\n", single(UnitCheckError::MalformedLine { line: 2 })),
+            ("[Unit]\nAftr=a.target\n", single(UnitCheckError::UnknownKey {
+                line: 2,
+                section: "Unit".to_string(),
+                key: "Aftr".to_string(),
+            })),
+            ("[Unit]\nOnFailure=emergency\n", single(UnitCheckError::InvalidValue {
+                line: 2,
+                key: "OnFailure".to_string(),
+                value: "emergency".to_string(),
+            })),
+            ("[Service]\nTimeoutStartSec=soon\n", single(UnitCheckError::InvalidValue {
+                line: 2,
+                key: "TimeoutStartSec".to_string(),
+                value: "soon".to_string(),
+            })),
+            ("[Service]\nExecStart=/bin/echo \"unbalanced\n", single(UnitCheckError::InvalidValue {
+                line: 2,
+                key: "ExecStart".to_string(),
+                value: "/bin/echo \"unbalanced".to_string(),
+            })),
+        ];
+        for (unit, expected) in test_cases {
+            assert_eq!(check_unit(unit), expected, "{}", unit);
+        }
+    }
+
+    #[test]
+    fn test_installed_units_pass_check() {
+        for unit in [
+            include_str!("actual_stuff/usb-boot-audit.service"),
+            include_str!("actual_stuff/usb-boot-stage1-check.service"),
+        ] {
+            assert_eq!(check_unit(unit), Ok(()));
+        }
+    }
+}
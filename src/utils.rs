@@ -1,4 +1,4 @@
-use std::str::CharIndices;
+use std::{fs, path::{Path, PathBuf}, process::Command, str::CharIndices, sync::atomic::{AtomicUsize, Ordering}};
 
 #[derive(Clone, Debug)]
 pub struct SplitStrings<'a> {
@@ -69,7 +69,7 @@ impl<'a> Iterator for SplitStrings<'a> {
 /// starting from the front and going to the end.
 /// The substrings returned by the iterator will not contain any
 /// unquoted spaces.
-pub fn split_at_unquoted_spaces(string: &str) -> SplitStrings {
+pub fn split_at_unquoted_spaces(string: &str) -> SplitStrings<'_> {
     SplitStrings {
         internal_iter: string.char_indices(),
         is_exhausted: false,
@@ -108,6 +108,33 @@ mod tests {
             assert_eq!(split_at_unquoted_spaces(input).collect::<Vec<_>>().as_slice(), *expected);
         }
     }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0x9f, 0xa0, 0xff]), "009fa0ff");
+        assert_eq!(from_hex("009fA0ff"), Some(vec![0x00, 0x9f, 0xa0, 0xff]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_base64() {
+        let test_cases: [(&[u8], &str); 5] = [
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"acpi_osi=\"!Windows 2020\" \xff", "YWNwaV9vc2k9IiFXaW5kb3dzIDIwMjAiIP8="),
+        ];
+        for (bytes, base64) in test_cases {
+            assert_eq!(to_base64(bytes), base64);
+            assert_eq!(from_base64(base64).as_deref(), Some(bytes));
+        }
+        assert_eq!(from_base64("Zm8"), Some(b"fo".to_vec()));
+        for invalid in ["Z", "Zm8==", "Zm=8", "Zg===", "Zm9v!"] {
+            assert_eq!(from_base64(invalid), None, "{}", invalid);
+        }
+    }
 }
 
 /// Tests whether there are any two elements in the slice that are equal
@@ -126,3 +153,141 @@ pub fn elements_are_unique<T: Eq>(elements: &[T]) -> bool {
     }
     true
 }
+
+/// Formats bytes as a lowercase hexadecimal string.
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex.push_str(&format!("{:02x}", byte));
+    }
+    hex
+}
+
+/// Parses a hexadecimal string (upper or lower case) into bytes.
+/// Returns None if the string has an odd length or contains a non-hexadecimal character.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i+2], 16).ok())
+        .collect()
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Formats bytes as standard base64, with padding.
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut base64 = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let value = chunk.iter().enumerate().fold(0u32, |value, (i, x)| value | (*x as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => base64.push(BASE64_ALPHABET[(value >> (18 - 6 * i)) as usize & 63] as char),
+                false => base64.push('='),
+            }
+        }
+    }
+    base64
+}
+
+/// Parses standard base64, with or without padding, into bytes.
+/// Returns None if the string contains a character outside of the alphabet or has an invalid length.
+pub fn from_base64(base64: &str) -> Option<Vec<u8>> {
+    let digits = base64.trim_end_matches('=').as_bytes();
+    let padded = digits.len() != base64.len();
+    if digits.len() % 4 == 1 || (padded && (!base64.len().is_multiple_of(4) || base64.len() - digits.len() > 2)) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(digits.len() * 3 / 4);
+    for chunk in digits.chunks(4) {
+        let mut value = 0u32;
+        for (i, digit) in chunk.iter().enumerate() {
+            value |= (BASE64_ALPHABET.iter().position(|x| x == digit)? as u32) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((value >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// A block device mounted read-only on a temporary mount point.
+/// The device is unmounted and the mount point removed when this is dropped.
+#[derive(Debug)]
+pub struct TemporaryMount {
+    mount_point: PathBuf,
+}
+impl TemporaryMount {
+    /// Mounts `device` read-only on a new directory under `/run/usb-boot`.
+    pub fn read_only(device: &Path) -> anyhow::Result<TemporaryMount> {
+        static MOUNT_COUNT: AtomicUsize = AtomicUsize::new(0);
+        let mount_point = PathBuf::from(format!(
+            "/run/usb-boot/mount.{}.{}",
+            std::process::id(),
+            MOUNT_COUNT.fetch_add(1, Ordering::Relaxed),
+        ));
+        fs::create_dir_all(&mount_point)?;
+
+        let success = Command::new("mount")
+            .args(["-o", "ro"])
+            .arg(device)
+            .arg(&mount_point)
+            .spawn()?
+            .wait()?
+            .success();
+        if !success {
+            let _ = fs::remove_dir(&mount_point);
+            anyhow::bail!("failed to mount \"{}\"", device.display());
+        }
+        Ok(TemporaryMount { mount_point })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.mount_point
+    }
+}
+impl Drop for TemporaryMount {
+    fn drop(&mut self) {
+        let unmounted = Command::new("umount")
+            .arg(&self.mount_point)
+            .status()
+            .map(|x| x.success())
+            .unwrap_or(false);
+        if unmounted {
+            let _ = fs::remove_dir(&self.mount_point);
+        }
+        else {
+            eprintln!("failed to unmount \"{}\"", self.mount_point.display());
+        }
+    }
+}
+
+/// An empty directory for a test, in the temporary directory.
+/// The directory is removed with its contents when this is dropped, even if the test fails.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct TestDirectory {
+    path: PathBuf,
+}
+#[cfg(test)]
+impl TestDirectory {
+    /// Creates the directory `usb-boot-test-<name>-<pid>`, removing what is left of it from an
+    /// earlier run.
+    pub fn new(name: &str) -> TestDirectory {
+        let path = std::env::temp_dir().join(format!("usb-boot-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDirectory { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+#[cfg(test)]
+impl Drop for TestDirectory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}